@@ -0,0 +1,213 @@
+use crate::cursor::Explanation;
+use crate::flatten::Flattening;
+use crate::mergebox::Inconsistency;
+use crate::subscriptions::Subscriptions;
+use anyhow::{Context, Error};
+use futures_util::FutureExt;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+
+/// Requests are expected to be small and quick, so a client cannot hold the
+/// connection (or the memory) for long.
+const REQUEST_LIMIT: u64 = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A minimal HTTP server with two endpoints:
+///   * `GET /explain` lists (as JSON) why cursors could not use change streams.
+///   * `GET /metrics` exposes the same (without the reasons, as they would make
+///     too many time series) in the Prometheus text format, together
+///     with the number of repaired mergebox inconsistencies and the (estimated)
+///     bytes saved by flattening.
+pub async fn start_admin(
+    listener: TcpListener,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+) -> Result<(), Error> {
+    loop {
+        let stream = listener.accept().await?.0;
+        let subscriptions = subscriptions.clone();
+        spawn(handle(stream, subscriptions).then(|result| async move {
+            // TODO: Better handling of subtasks.
+            if let Err(error) = &result {
+                println!("\x1b[0;31m[[ERROR]] {error:?}\x1b[0m");
+            }
+            result
+        }));
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+) -> Result<(), Error> {
+    let mut request_line = String::new();
+    let mut reader = BufReader::new((&mut stream).take(REQUEST_LIMIT));
+    timeout(REQUEST_TIMEOUT, reader.read_line(&mut request_line))
+        .await
+        .context("Admin request timed out")?
+        .context("Failed to read admin request")?;
+
+    let (status, content_type, body) = match request_line.split_whitespace().nth(1) {
+        Some("/explain") => {
+            let subscriptions = subscriptions.lock().await;
+            let body = render_explain(subscriptions.explanations().iter()).to_string();
+            ("200 OK", "application/json", body)
+        }
+        Some("/metrics") => {
+            let subscriptions = subscriptions.lock().await;
+            let repaired = Inconsistency::ALL.map(|kind| (kind, kind.repaired()));
+            let body = render_metrics(
+                subscriptions.explanations().iter(),
+                repaired.into_iter(),
+                Flattening::saved(),
+            );
+            ("200 OK", "text/plain; version=0.0.4", body)
+        }
+        _ => ("404 Not Found", "text/plain", String::from("Not Found")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("Failed to write admin response")
+}
+
+fn render_explain<'a>(
+    explanations: impl Iterator<Item = (&'a str, &'a str, &'a Explanation, usize)>,
+) -> Value {
+    explanations
+        .map(|(publication, collection, explanation, count)| {
+            json!({
+                "publication": publication,
+                "collection": collection,
+                "part": explanation.part.as_str(),
+                "reason": explanation.reason,
+                "cursors": count,
+            })
+        })
+        .collect()
+}
+
+fn render_metrics<'a>(
+    explanations: impl Iterator<Item = (&'a str, &'a str, &'a Explanation, usize)>,
+    repaired: impl Iterator<Item = (Inconsistency, usize)>,
+    saved: usize,
+) -> String {
+    let mut metrics = String::from(
+        "# HELP ddp_router_unsupported_cursors_total Cursors that could not use change streams.\n\
+         # TYPE ddp_router_unsupported_cursors_total counter\n",
+    );
+
+    for (publication, collection, explanation, count) in explanations {
+        let _ = writeln!(
+            metrics,
+            "ddp_router_unsupported_cursors_total{{publication=\"{}\",collection=\"{}\",part=\"{}\"}} {count}",
+            escape_label(publication),
+            escape_label(collection),
+            explanation.part,
+        );
+    }
+
+    metrics.push_str(
+        "# HELP ddp_router_mergebox_inconsistencies_total Mergebox inconsistencies repaired in tolerant mode (or drifted documents found by resyncs in both modes).\n\
+         # TYPE ddp_router_mergebox_inconsistencies_total counter\n",
+    );
+
+    for (inconsistency, count) in repaired {
+        let _ = writeln!(
+            metrics,
+            "ddp_router_mergebox_inconsistencies_total{{kind=\"{}\"}} {count}",
+            inconsistency.as_str(),
+        );
+    }
+
+    let _ = write!(
+        metrics,
+        "# HELP ddp_router_flattened_saved_bytes_total Bytes not sent thanks to flattened fields (estimated).\n\
+         # TYPE ddp_router_flattened_saved_bytes_total counter\n\
+         ddp_router_flattened_saved_bytes_total {saved}\n",
+    );
+
+    metrics
+}
+
+/// <https://prometheus.io/docs/instrumenting/exposition_formats/#text-format-details>
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_explain, render_metrics};
+    use crate::cursor::{Explanation, Part};
+    use crate::mergebox::Inconsistency;
+    use serde_json::json;
+
+    fn explanations() -> Vec<(&'static str, &'static str, Explanation, usize)> {
+        vec![
+            (
+                "posts",
+                "posts",
+                Explanation {
+                    part: Part::Selector,
+                    reason: String::from("$where is not supported"),
+                },
+                2,
+            ),
+            (
+                "search",
+                "posts",
+                Explanation {
+                    part: Part::Sort,
+                    reason: String::from("Sort order \"asc\" for a is not supported"),
+                },
+                1,
+            ),
+        ]
+    }
+
+    #[test]
+    fn explain() {
+        let explanations = explanations();
+        let explanations = explanations.iter().map(|(a, b, c, d)| (*a, *b, c, *d));
+        assert_eq!(
+            render_explain(explanations),
+            json!([
+                {"publication": "posts", "collection": "posts", "part": "selector", "reason": "$where is not supported", "cursors": 2},
+                {"publication": "search", "collection": "posts", "part": "sort", "reason": "Sort order \"asc\" for a is not supported", "cursors": 1}
+            ])
+        );
+    }
+
+    #[test]
+    fn metrics() {
+        let explanations = explanations();
+        let explanations = explanations.iter().map(|(a, b, c, d)| (*a, *b, c, *d));
+        let repaired = [(Inconsistency::ChangedUnknown, 3)];
+        assert_eq!(
+            render_metrics(explanations, repaired.into_iter(), 512),
+            "# HELP ddp_router_unsupported_cursors_total Cursors that could not use change streams.\n\
+             # TYPE ddp_router_unsupported_cursors_total counter\n\
+             ddp_router_unsupported_cursors_total{publication=\"posts\",collection=\"posts\",part=\"selector\"} 2\n\
+             ddp_router_unsupported_cursors_total{publication=\"search\",collection=\"posts\",part=\"sort\"} 1\n\
+             # HELP ddp_router_mergebox_inconsistencies_total Mergebox inconsistencies repaired in tolerant mode (or drifted documents found by resyncs in both modes).\n\
+             # TYPE ddp_router_mergebox_inconsistencies_total counter\n\
+             ddp_router_mergebox_inconsistencies_total{kind=\"changed_unknown\"} 3\n\
+             # HELP ddp_router_flattened_saved_bytes_total Bytes not sent thanks to flattened fields (estimated).\n\
+             # TYPE ddp_router_flattened_saved_bytes_total counter\n\
+             ddp_router_flattened_saved_bytes_total 512\n"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::ddp::DDPMessage;
+use std::collections::HashMap;
+
+/// Messages waiting to be written to the client. Consecutive changes of the
+/// same document are coalesced (e.g., `added` and `changed` into one `added`),
+/// as long as no other message (e.g., `ready`) is in between.
+#[derive(Default)]
+pub struct Batch {
+    documents: HashMap<(String, String), usize>,
+    messages: Vec<Option<DDPMessage>>,
+}
+
+enum Merged {
+    Both(DDPMessage, DDPMessage),
+    None,
+    One(DDPMessage),
+}
+
+impl Batch {
+    pub fn drain(&mut self) -> impl Iterator<Item = DDPMessage> + '_ {
+        self.documents.clear();
+        self.messages.drain(..).flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn push(&mut self, message: DDPMessage) {
+        let key = match &message {
+            DDPMessage::Added { collection, id, .. }
+            | DDPMessage::Changed { collection, id, .. }
+            | DDPMessage::Removed { collection, id } => (collection.clone(), id.to_string()),
+            // Other messages may depend on all previous ones.
+            _ => {
+                self.documents.clear();
+                self.messages.push(Some(message));
+                return;
+            }
+        };
+
+        let Some(previous) = self
+            .documents
+            .get(&key)
+            .and_then(|index| self.messages[*index].take())
+        else {
+            self.documents.insert(key, self.messages.len());
+            self.messages.push(Some(message));
+            return;
+        };
+
+        let index = self.documents[&key];
+        match merge(previous, message) {
+            Merged::Both(previous, message) => {
+                self.messages[index] = Some(previous);
+                self.documents.insert(key, self.messages.len());
+                self.messages.push(Some(message));
+            }
+            Merged::None => {
+                self.documents.remove(&key);
+            }
+            Merged::One(message) => {
+                self.messages[index] = Some(message);
+            }
+        }
+    }
+}
+
+fn merge(previous: DDPMessage, message: DDPMessage) -> Merged {
+    match (previous, message) {
+        (
+            DDPMessage::Added {
+                collection,
+                id,
+                fields,
+                cleared,
+            },
+            DDPMessage::Changed {
+                fields: fields_changed,
+                cleared: fields_cleared,
+                ..
+            },
+        ) => {
+            let mut fields = fields.unwrap_or_default();
+            for field in fields_cleared.into_iter().flatten() {
+                fields.remove(&field);
+            }
+            fields.extend(fields_changed.into_iter().flatten());
+            Merged::One(DDPMessage::Added {
+                collection,
+                id,
+                fields: (!fields.is_empty()).then_some(fields),
+                cleared,
+            })
+        }
+        (
+            DDPMessage::Changed {
+                collection,
+                id,
+                fields,
+                cleared,
+            },
+            DDPMessage::Changed {
+                fields: fields_changed,
+                cleared: fields_cleared,
+                ..
+            },
+        ) => {
+            let mut fields = fields.unwrap_or_default();
+            let mut cleared = cleared.unwrap_or_default();
+            for field in fields_cleared.into_iter().flatten() {
+                fields.remove(&field);
+                if !cleared.contains(&field) {
+                    cleared.push(field);
+                }
+            }
+            for (field, value) in fields_changed.into_iter().flatten() {
+                cleared.retain(|cleared| *cleared != field);
+                fields.insert(field, value);
+            }
+            Merged::One(DDPMessage::Changed {
+                collection,
+                id,
+                fields: (!fields.is_empty()).then_some(fields),
+                cleared: (!cleared.is_empty()).then_some(cleared),
+            })
+        }
+        // The client never saw it.
+        (DDPMessage::Added { .. }, DDPMessage::Removed { .. }) => Merged::None,
+        (DDPMessage::Changed { .. }, message @ DDPMessage::Removed { .. }) => Merged::One(message),
+        // E.g., `removed` and `added` cannot be merged without the previous
+        // fields of the document.
+        (previous, message) => Merged::Both(previous, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Batch;
+    use crate::ddp::DDPMessage;
+    use serde_json::{from_value, json, Value};
+
+    macro_rules! batch {
+        ($name:ident, [$($message:tt),* $(,)?], [$($expected:tt),* $(,)?]) => {
+            #[test]
+            fn $name() {
+                let mut batch = Batch::default();
+                $(batch.push(from_value(json!($message)).unwrap());)*
+                let messages: Vec<DDPMessage> = batch.drain().collect();
+                let expected: Vec<Value> = vec![$(json!($expected)),*];
+                let expected: Vec<DDPMessage> = expected
+                    .into_iter()
+                    .map(|message| from_value(message).unwrap())
+                    .collect();
+                assert_eq!(messages, expected);
+                assert_eq!(batch.len(), 0);
+            }
+        };
+    }
+
+    batch!(batch_1, [], []);
+    batch!(
+        batch_2,
+        [
+            {"msg": "added", "collection": "x", "id": 1, "fields": {"a": 1}},
+            {"msg": "added", "collection": "x", "id": 2},
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"b": 2}, "cleared": ["a"]},
+        ],
+        [
+            {"msg": "added", "collection": "x", "id": 1, "fields": {"b": 2}},
+            {"msg": "added", "collection": "x", "id": 2},
+        ]
+    );
+    batch!(
+        batch_3,
+        [
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"a": 1}, "cleared": ["b"]},
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"b": 2}, "cleared": ["a", "c"]},
+        ],
+        [
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"b": 2}, "cleared": ["a", "c"]},
+        ]
+    );
+    batch!(
+        batch_4,
+        [
+            {"msg": "added", "collection": "x", "id": 1},
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"a": 1}},
+            {"msg": "removed", "collection": "x", "id": 1},
+            {"msg": "added", "collection": "x", "id": 1, "fields": {"a": 2}},
+        ],
+        [
+            {"msg": "added", "collection": "x", "id": 1, "fields": {"a": 2}},
+        ]
+    );
+    batch!(
+        batch_5,
+        [
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"a": 1}},
+            {"msg": "removed", "collection": "x", "id": 1},
+            {"msg": "added", "collection": "x", "id": 1, "fields": {"a": 2}},
+        ],
+        [
+            {"msg": "removed", "collection": "x", "id": 1},
+            {"msg": "added", "collection": "x", "id": 1, "fields": {"a": 2}},
+        ]
+    );
+    batch!(
+        batch_6,
+        [
+            {"msg": "added", "collection": "x", "id": 1},
+            {"msg": "ready", "subs": ["s"]},
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"a": 1}},
+        ],
+        [
+            {"msg": "added", "collection": "x", "id": 1},
+            {"msg": "ready", "subs": ["s"]},
+            {"msg": "changed", "collection": "x", "id": 1, "fields": {"a": 1}},
+        ]
+    );
+    batch!(
+        batch_7,
+        [
+            {"msg": "added", "collection": "x", "id": 1},
+            {"msg": "added", "collection": "y", "id": 1},
+            {"msg": "removed", "collection": "x", "id": 1},
+        ],
+        [
+            {"msg": "added", "collection": "y", "id": 1},
+        ]
+    );
+}
@@ -0,0 +1,313 @@
+use anyhow::{anyhow, Error};
+use bson::{Bson, Document};
+use serde_json::Value;
+use std::cmp::Ordering;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Locales which do not tailor the root collation for the characters they use
+/// (e.g., Spanish sorts `ñ` after `n` and Swedish sorts `å` after `z`, so they
+/// are not here).
+/// <https://www.mongodb.com/docs/manual/reference/collation-locales-defaults/>
+const LOCALES: &[&str] = &[
+    "af",
+    "ca",
+    "de",
+    "en",
+    "en_US",
+    "en_US_POSIX",
+    "fil",
+    "fr",
+    "fr_CA",
+    "ga",
+    "id",
+    "it",
+    "ms",
+    "nl",
+    "pt",
+    "sw",
+    "zu",
+];
+
+/// ASCII punctuation and symbols in the root collation order (currency last, as
+/// it's a separate group).
+const SYMBOLS: &str = "_-,;:!?.'\"()[]{}@*/\\&#%`^+<=>|~$";
+
+/// Diacritics in the root collation order (of their secondary weights).
+const DIACRITICS: &[char] = &[
+    '\u{301}', // Acute.
+    '\u{300}', // Grave.
+    '\u{306}', // Breve.
+    '\u{302}', // Circumflex.
+    '\u{30C}', // Caron.
+    '\u{30A}', // Ring above.
+    '\u{308}', // Diaeresis.
+    '\u{30B}', // Double acute.
+    '\u{303}', // Tilde.
+    '\u{307}', // Dot above.
+    '\u{327}', // Cedilla.
+    '\u{328}', // Ogonek.
+    '\u{304}', // Macron.
+];
+
+/// An approximation of an ICU collation, limited to the primary (base letters)
+/// and secondary (diacritics) strengths. Everything else (case levels,
+/// shifted punctuation, locale tailoring) falls back to polling. So do strings
+/// with characters other than ASCII (optionally with the above diacritics) and
+/// `ß`, e.g., `ø` and `æ` (see `Collation::check`).
+#[derive(Clone, Copy, Debug)]
+pub struct Collation {
+    numeric_ordering: bool,
+    strength: u8,
+}
+
+impl Collation {
+    /// Fails if the value contains strings this approximation may compare
+    /// differently than the database.
+    pub fn check(&self, value: &Value) -> Result<(), Error> {
+        match value {
+            Value::Array(values) => values.iter().try_for_each(|value| self.check(value)),
+            Value::Object(object) => object.values().try_for_each(|value| self.check(value)),
+            Value::String(string) => match string.nfd().find(|char| !is_supported(*char)) {
+                Some(char) => Err(anyhow!("Collation of {char:?} is not supported")),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn cmp(&self, lhs: &str, rhs: &str) -> Ordering {
+        let lhs = self.elements(lhs);
+        let rhs = self.elements(rhs);
+
+        let ordering = lhs
+            .iter()
+            .map(|(primary, _)| primary)
+            .cmp(rhs.iter().map(|(primary, _)| primary));
+        if ordering.is_ne() || self.strength == 1 {
+            return ordering;
+        }
+
+        lhs.iter()
+            .map(|(_, secondary)| secondary)
+            .cmp(rhs.iter().map(|(_, secondary)| secondary))
+    }
+
+    pub fn compile(collation: Option<&Document>) -> Result<Option<Self>, Error> {
+        let Some(collation) = collation else {
+            return Ok(None);
+        };
+
+        let mut locale = None;
+        let mut numeric_ordering = false;
+        let mut strength = 3;
+        for (key, value) in collation {
+            match (key.as_str(), value) {
+                ("locale", Bson::String(value)) => locale = Some(value.as_str()),
+                ("strength", Bson::Int32(value @ 1..=5)) => strength = *value as u8,
+                ("strength", Bson::Int64(value @ 1..=5)) => strength = *value as u8,
+                ("numericOrdering", Bson::Boolean(value)) => numeric_ordering = *value,
+                // These are irrelevant for the strengths we support.
+                ("caseFirst", _) | ("normalization", _) => {}
+                ("alternate", Bson::String(value)) if value == "non-ignorable" => {}
+                ("backwards" | "caseLevel", Bson::Boolean(false)) => {}
+                (key, value) => return Err(anyhow!("Collation {key}: {value} is not supported")),
+            }
+        }
+
+        match locale {
+            Some("simple") => Ok(None),
+            Some(locale) if !LOCALES.contains(&locale) => {
+                Err(anyhow!("Collation locale {locale} is not supported"))
+            }
+            Some(_) if strength > 2 => {
+                Err(anyhow!("Collation strength {strength} is not supported"))
+            }
+            Some(_) => Ok(Some(Self {
+                numeric_ordering,
+                strength,
+            })),
+            None => Err(anyhow!("Collation requires locale")),
+        }
+    }
+
+    /// Splits the string into primary and secondary collation elements. It
+    /// relies on the fact that in the supported locales characters decompose
+    /// (NFD) into a base letter followed by diacritics.
+    fn elements(&self, string: &str) -> Vec<(Primary, Vec<usize>)> {
+        let mut elements: Vec<(Primary, Vec<usize>)> = vec![];
+        let mut chars = string.nfd().peekable();
+        while let Some(char) = chars.next() {
+            if is_combining_mark(char) {
+                if let Some((_, secondary)) = elements.last_mut() {
+                    let rank = DIACRITICS.iter().position(|diacritic| *diacritic == char);
+                    secondary.push(rank.unwrap_or(DIACRITICS.len()));
+                }
+                continue;
+            }
+
+            if self.numeric_ordering && char.is_ascii_digit() {
+                let mut digits = String::from(char);
+                while let Some(char) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(char);
+                }
+
+                let digits = digits.trim_start_matches('0').to_owned();
+                elements.push((
+                    Primary(Class::Digit, Key::Number(digits.len(), digits)),
+                    vec![],
+                ));
+                continue;
+            }
+
+            // The only common letter that expands on the primary level.
+            if char == 'ß' {
+                elements.push((Primary(Class::Letter, Key::Char('s')), vec![]));
+                elements.push((Primary(Class::Letter, Key::Char('s')), vec![]));
+                continue;
+            }
+
+            let primary = if char.is_whitespace() {
+                Primary(Class::Whitespace, Key::Char(char))
+            } else if char.is_numeric() {
+                Primary(Class::Digit, Key::Char(char))
+            } else if char.is_alphabetic() {
+                Primary(Class::Letter, Key::Char(char.to_ascii_lowercase()))
+            } else {
+                let rank = SYMBOLS.find(char).unwrap_or(SYMBOLS.len());
+                Primary(Class::Symbol, Key::Rank(rank))
+            };
+
+            elements.push((primary, vec![]));
+        }
+
+        elements
+    }
+}
+
+/// The order of groups in the root collation.
+/// <https://www.unicode.org/reports/tr35/tr35-collation.html#Root_Collation>
+#[derive(Eq, Ord, PartialEq, PartialOrd)]
+enum Class {
+    Whitespace,
+    Symbol,
+    Digit,
+    Letter,
+}
+
+#[derive(Eq, Ord, PartialEq, PartialOrd)]
+enum Key {
+    Char(char),
+    Number(usize, String),
+    Rank(usize),
+}
+
+/// See `Collation::check`.
+fn is_supported(char: char) -> bool {
+    match char {
+        '\t' | '\n' | '\u{b}' | '\u{c}' | '\r' | 'ß' => true,
+        char if char.is_ascii() => !char.is_ascii_control(),
+        char => DIACRITICS.contains(&char),
+    }
+}
+
+#[derive(Eq, Ord, PartialEq, PartialOrd)]
+struct Primary(Class, Key);
+
+#[cfg(test)]
+mod tests {
+    use super::Collation;
+    use bson::doc;
+    use serde_json::json;
+    use std::cmp::Ordering;
+
+    macro_rules! test {
+        ($name:ident, { $($collation:tt)* }, $lhs:expr, $rhs:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let collation = doc! { $($collation)* };
+                let collation = match Collation::compile(Some(&collation)) {
+                    Ok(Some(collation)) => collation,
+                    Ok(None) => panic!("{collation:?} should not be simple"),
+                    Err(error) => panic!("{collation:?} is not supported: {error:?}"),
+                };
+
+                assert_eq!(collation.cmp($lhs, $rhs), $expected);
+                assert_eq!(collation.cmp($rhs, $lhs), $expected.reverse());
+            }
+        };
+    }
+
+    macro_rules! check {
+        ($name:ident, $value:tt, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let collation = doc! {"locale": "en", "strength": 1};
+                let collation = Collation::compile(Some(&collation)).unwrap().unwrap();
+                assert_eq!(collation.check(&json!($value)).is_ok(), $expected);
+            }
+        };
+    }
+
+    macro_rules! fail {
+        ($name:ident, { $($collation:tt)* }) => {
+            #[test]
+            fn $name() {
+                let collation = doc! { $($collation)* };
+                if Collation::compile(Some(&collation)).is_ok() {
+                    panic!("{collation:?} should not be supported");
+                }
+            }
+        };
+    }
+
+    macro_rules! eq {($name:ident, { $($collation:tt)* }, $lhs:expr, $rhs:expr) => {test!($name, { $($collation)* }, $lhs, $rhs, Ordering::Equal);}}
+    macro_rules! lt {($name:ident, { $($collation:tt)* }, $lhs:expr, $rhs:expr) => {test!($name, { $($collation)* }, $lhs, $rhs, Ordering::Less);}}
+
+    eq!(primary_01, {"locale": "en", "strength": 1}, "abc", "ABC");
+    eq!(primary_02, {"locale": "en", "strength": 1}, "résumé", "RESUME");
+    eq!(primary_03, {"locale": "de", "strength": 1}, "Straße", "STRASSE");
+    lt!(primary_04, {"locale": "en", "strength": 1}, "a", "B");
+    lt!(primary_05, {"locale": "en", "strength": 1}, "Zebra", "zebras");
+    lt!(primary_06, {"locale": "en", "strength": 1}, "a b", "ab");
+    lt!(primary_07, {"locale": "en", "strength": 1}, "_", "1");
+    lt!(primary_08, {"locale": "en", "strength": 1}, "9", "a");
+    lt!(primary_09, {"locale": "en", "strength": 1}, "10", "9");
+    lt!(primary_10, {"locale": "en", "strength": 1}, "-", ",");
+    lt!(primary_11, {"locale": "en", "strength": 1}, "@", "#");
+    lt!(primary_12, {"locale": "en", "strength": 1}, "~", "$");
+    lt!(primary_13, {"locale": "en", "strength": 1}, "$", "0");
+
+    eq!(secondary_1, {"locale": "fr", "strength": 2}, "élève", "ÉLÈVE");
+    lt!(secondary_2, {"locale": "fr", "strength": 2}, "eleve", "élève");
+    lt!(secondary_3, {"locale": "fr", "strength": 2}, "élève", "elevf");
+    lt!(secondary_4, {"locale": "fr", "strength": 2}, "cote", "coté");
+    lt!(secondary_5, {"locale": "fr", "strength": 2}, "coté", "cotè");
+    lt!(secondary_6, {"locale": "fr", "strength": 2}, "cotè", "cotê");
+
+    lt!(numeric_1, {"locale": "en", "strength": 1, "numericOrdering": true}, "9", "10");
+    eq!(numeric_2, {"locale": "en", "strength": 1, "numericOrdering": true}, "007", "7");
+    lt!(numeric_3, {"locale": "en", "strength": 1, "numericOrdering": true}, "a2b", "a10a");
+    lt!(numeric_4, {"locale": "en", "strength": 1, "numericOrdering": true}, "99", "a");
+
+    check!(check_1, "Straße am Fluß", true);
+    check!(check_2, ["Résumé", {"a": "Ça va?"}], true);
+    check!(check_3, "Øyvind", false);
+    check!(check_4, {"a": ["æble"]}, false);
+    check!(check_5, "Ελλάδα", false);
+    check!(check_6, "a\u{0}b", false);
+    check!(check_7, 1, true);
+
+    fail!(unsupported_1, {"strength": 1});
+    fail!(unsupported_2, {"locale": "en"});
+    fail!(unsupported_3, {"locale": "sv", "strength": 1});
+    fail!(unsupported_4, {"locale": "en", "strength": 1, "caseLevel": true});
+    fail!(unsupported_5, {"locale": "en", "strength": 1, "alternate": "shifted"});
+    fail!(unsupported_6, {"locale": "en", "strength": 6});
+
+    #[test]
+    fn simple() {
+        let collation = doc! {"locale": "simple"};
+        assert!(Collation::compile(Some(&collation)).unwrap().is_none());
+    }
+}
@@ -1,30 +1,79 @@
-use bson::Document;
-use mongodb::options::FindOptions;
+use bson::{doc, from_document, Bson, Document};
+use mongodb::options::{Collation, FindOptions, Hint, ReadPreference, SelectionCriteria};
+use serde::de::{Error, IgnoredAny};
 use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::time::Duration;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct CursorDescription {
+    pub batch_size: Option<u32>,
+    pub collation: Option<Document>,
     pub collection: String,
+    pub comment: Option<Bson>,
     pub disable_oplog: bool,
+    pub hint: Option<Hint>,
     pub limit: Option<i64>,
+    pub max_time_ms: Option<u64>,
     pub polling_interval_ms: Option<u64>,
+    pub polling_throttle_ms: Option<u64>,
     pub projection: Option<Document>,
+    pub read_preference: Option<ReadPreference>,
     pub selector: Document,
     pub skip: Option<u64>,
     pub sort: Option<Document>,
-    pub transform: Option<()>,
+    /// Names of options that are not recognized (and therefore ignored).
+    pub unknown_options: Vec<String>,
 }
 
 impl CursorDescription {
     pub fn as_find_options(&self) -> FindOptions {
         FindOptions::builder()
+            .batch_size(self.batch_size)
+            .collation(
+                self.collation
+                    .clone()
+                    .and_then(|collation| from_document::<Collation>(collation).ok()),
+            )
+            .comment_bson(self.comment.clone())
+            .hint(self.hint.clone())
             .limit(self.limit)
+            .max_time(self.max_time_ms.map(Duration::from_millis))
             .projection(self.projection.clone())
+            .selection_criteria(
+                self.read_preference
+                    .clone()
+                    .map(SelectionCriteria::ReadPreference),
+            )
             .skip(self.skip)
             .sort(self.sort.clone())
             .build()
     }
 
+    /// Whether the selector uses `$text`, which only the database can evaluate.
+    /// It is allowed only at the top level or in a top-level `$and` or `$or`.
+    pub fn is_text_search(&self) -> bool {
+        fn has_text(selector: &Document) -> bool {
+            selector
+                .iter()
+                .any(|(key, value)| match (key.as_str(), value) {
+                    ("$text", _) => true,
+                    ("$and" | "$or", Bson::Array(selectors)) => selectors
+                        .iter()
+                        .any(|selector| selector.as_document().is_some_and(has_text)),
+                    _ => false,
+                })
+        }
+
+        has_text(&self.selector)
+    }
+
+    /// Whether the cursor is rerun on every change of its collection when it
+    /// cannot be observed locally, instead of polling.
+    pub fn is_triggered(&self) -> bool {
+        self.is_text_search() && !self.disable_oplog
+    }
+
     pub fn limit(&self) -> Option<usize> {
         self.limit.map(|limit| limit.unsigned_abs() as usize)
     }
@@ -42,17 +91,31 @@ impl<'de> Deserialize<'de> for CursorDescription {
         }
 
         #[derive(Deserialize)]
-        #[serde(deny_unknown_fields)]
         struct Options {
+            #[serde(rename = "batchSize")]
+            batch_size: Option<u32>,
+            collation: Option<Document>,
+            comment: Option<Bson>,
             #[serde(default, rename = "disableOplog")]
             disable_oplog: bool,
+            hint: Option<Hint>,
             limit: Option<i64>,
+            #[serde(alias = "maxTimeMS", rename = "maxTimeMs")]
+            max_time_ms: Option<u64>,
             #[serde(rename = "pollingIntervalMs")]
             polling_interval_ms: Option<u64>,
+            #[serde(rename = "pollingThrottleMs")]
+            polling_throttle_ms: Option<u64>,
             projection: Option<Document>,
+            #[serde(rename = "readPreference")]
+            read_preference: Option<Bson>,
             skip: Option<u64>,
             sort: Option<Document>,
-            transform: Option<()>,
+            // Meteor does not apply transforms to published documents.
+            #[serde(rename = "transform")]
+            _transform: Option<IgnoredAny>,
+            #[serde(flatten)]
+            unknown: BTreeMap<String, IgnoredAny>,
         }
 
         let Description {
@@ -60,26 +123,165 @@ impl<'de> Deserialize<'de> for CursorDescription {
             selector,
             options:
                 Options {
+                    batch_size,
+                    collation,
+                    comment,
                     disable_oplog,
+                    hint,
                     limit,
+                    max_time_ms,
                     polling_interval_ms,
+                    polling_throttle_ms,
                     projection,
+                    read_preference,
                     skip,
                     sort,
-                    transform,
+                    _transform: _,
+                    unknown,
                 },
         } = Description::deserialize(deserializer)?;
 
+        // Validate it early, as `as_find_options` cannot fail.
+        if let Some(collation) = &collation {
+            from_document::<Collation>(collation.clone()).map_err(D::Error::custom)?;
+        }
+
+        let read_preference = read_preference
+            .map(parse_read_preference)
+            .transpose()
+            .map_err(D::Error::custom)?;
+
         Ok(Self {
+            batch_size,
+            collation,
             collection,
+            comment,
             disable_oplog,
+            hint,
             limit,
+            max_time_ms,
             polling_interval_ms,
+            polling_throttle_ms,
             projection,
+            read_preference,
             selector,
             skip,
             sort,
-            transform,
+            unknown_options: unknown.into_keys().collect(),
         })
     }
 }
+
+/// Meteor passes either a mode (e.g., `"secondaryPreferred"`) or a serialized
+/// `ReadPreference` of the Node.js driver, which calls tag sets `tags`.
+fn parse_read_preference(read_preference: Bson) -> Result<ReadPreference, bson::de::Error> {
+    let document = match read_preference {
+        Bson::String(mode) => doc! { "mode": mode },
+        Bson::Document(mut document) => {
+            if let Some(tags) = document.remove("tags") {
+                document.insert("tagSets", tags);
+            }
+            document
+        }
+        read_preference => {
+            return Err(bson::de::Error::custom(format!(
+                "Read preference {read_preference} is not supported"
+            )))
+        }
+    };
+
+    from_document(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CursorDescription;
+    use mongodb::options::{Hint, ReadPreference, ReadPreferenceOptions};
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn description(options: serde_json::Value) -> CursorDescription {
+        let description = json!({"collectionName": "x", "selector": {}, "options": options});
+        CursorDescription::deserialize(description).unwrap()
+    }
+
+    #[test]
+    fn options() {
+        let description = description(json!({
+            "batchSize": 10,
+            "comment": "x",
+            "hint": {"a": 1},
+            "maxTimeMs": 1000,
+            "transform": null,
+        }));
+        assert!(description.unknown_options.is_empty());
+
+        let options = description.as_find_options();
+        assert_eq!(options.batch_size, Some(10));
+        assert_eq!(options.comment_bson, Some("x".into()));
+        assert_eq!(options.hint, Some(Hint::Keys(bson::doc! {"a": 1})));
+        assert_eq!(options.max_time, Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn options_max_time_ms() {
+        let description = description(json!({"maxTimeMS": 5}));
+        assert_eq!(description.max_time_ms, Some(5));
+    }
+
+    #[test]
+    fn options_read_preference_mode() {
+        let description = description(json!({"readPreference": "secondaryPreferred"}));
+        assert_eq!(
+            description.read_preference,
+            Some(ReadPreference::SecondaryPreferred {
+                options: ReadPreferenceOptions::default()
+            })
+        );
+    }
+
+    #[test]
+    fn options_read_preference_object() {
+        let description =
+            description(json!({"readPreference": {"mode": "nearest", "tags": [{"dc": "a"}]}}));
+        let Some(ReadPreference::Nearest { options }) = description.read_preference else {
+            panic!("Expected nearest, got {:?}", description.read_preference);
+        };
+        assert_eq!(options.tag_sets.map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn options_read_preference_invalid() {
+        let description =
+            json!({"collectionName": "x", "selector": {}, "options": {"readPreference": "x"}});
+        assert!(CursorDescription::deserialize(description).is_err());
+    }
+
+    macro_rules! triggered {
+        ($name:ident, $selector:tt, { $($options:tt)* }, $text_search:expr, $triggered:expr) => {
+            #[test]
+            fn $name() {
+                let description = json!({"collectionName": "x", "selector": $selector, "options": { $($options)* }});
+                let description = CursorDescription::deserialize(description).unwrap();
+                assert_eq!(description.is_text_search(), $text_search);
+                assert_eq!(description.is_triggered(), $triggered);
+            }
+        };
+    }
+
+    triggered!(triggered_1, {}, {}, false, false);
+    triggered!(triggered_2, {"$text": {"$search": "x"}}, {}, true, true);
+    triggered!(triggered_3, {"$text": {"$search": "x"}}, {"disableOplog": true}, true, false);
+    triggered!(triggered_4, {"$and": [{"a": 1}, {"$text": {"$search": "x"}}]}, {}, true, true);
+    triggered!(triggered_5, {"$or": [{"$and": [{"$text": {"$search": "x"}}]}]}, {}, true, true);
+    triggered!(triggered_6, {"a": {"$text": 1}}, {}, false, false);
+    triggered!(triggered_7, {"$nor": [{"$text": {"$search": "x"}}]}, {}, false, false);
+    triggered!(triggered_8, {"$where": "true"}, {}, false, false);
+
+    #[test]
+    fn options_unknown() {
+        let description = description(json!({"limit": 1, "tailable": true, "foo": 1}));
+        assert_eq!(description.unknown_options, vec!["foo", "tailable"]);
+    }
+}
@@ -1,11 +1,16 @@
 use super::description::CursorDescription;
-use super::viewer::CursorViewer;
-use crate::ejson::into_ejson_document;
-use crate::mergebox::{Mergebox, Mergeboxes};
+use super::viewer::{CursorViewer, Explanation, Part};
+use crate::ejson::{into_ddp, into_ddp_document, into_ejson_document};
+use crate::flatten::Flattening;
+use crate::mergebox::{Mergebox, Mergeboxes, Owner, SharedDocument};
+use crate::projector::Projector;
+use crate::settings::ReadOptions;
+use crate::sorter::SortKey;
 use crate::watcher::{Event, Watcher};
 use anyhow::{anyhow, Context, Error};
-use bson::Document;
+use bson::{doc, Bson, Document};
 use futures_util::{StreamExt, TryStreamExt};
+use mongodb::options::{ReadPreference, SelectionCriteria, SessionOptions};
 use mongodb::Database;
 use serde_json::{Map, Value};
 use std::mem::{replace, take};
@@ -14,64 +19,209 @@ use tokio::sync::broadcast::Receiver;
 use tokio::sync::Mutex;
 use tokio::time::{interval_at, Duration, Instant, Interval};
 
+/// How the fetched documents are kept up to date.
+pub enum Updates {
+    /// Every change event is processed locally by the `CursorViewer`.
+    Events(Receiver<Event>),
+    /// Change events only trigger a refetch, throttled by the given duration.
+    /// Used for cursors the database has to evaluate, e.g., `$text` searches.
+    Trigger(Receiver<Event>, Duration),
+    /// The query is periodically refetched.
+    Polling(Interval),
+}
+
+/// Called when a started cursor falls back to polling.
+pub type FellBack = Box<dyn Fn(&Explanation) + Send + Sync>;
+
 pub struct CursorFetcher {
     database: Database,
     description: CursorDescription,
-    documents: Vec<Map<String, Value>>,
+    documents: Vec<CachedDocument>,
+    explanation: Option<Explanation>,
+    fell_back: FellBack,
+    flattening: Flattening,
+    reads: ReadOptions,
     viewer: Option<CursorViewer>,
     watcher: Arc<Mutex<Watcher>>,
 }
 
+/// Fetched document with its projected fields, which are computed once and
+/// then shared by all mergeboxes. The whole document is not needed, as change
+/// events come with it.
+struct CachedDocument {
+    id: Value,
+    fields: SharedDocument,
+    key: SortKey,
+}
+
+impl CachedDocument {
+    fn new(id: Value, fields: SharedDocument, key: SortKey) -> Self {
+        Self { id, fields, key }
+    }
+}
+
 impl CursorFetcher {
+    /// Stops processing events locally, e.g., when a document cannot be
+    /// sorted. The background task then switches to polling.
+    fn fall_back(&mut self, explanation: Explanation) {
+        println!(
+            "\x1b[0;32mmongo\x1b[0m \x1b[0;31m{}: {explanation}\x1b[0m",
+            self.description.collection
+        );
+        (self.fell_back)(&explanation);
+        self.explanation = Some(explanation);
+        self.viewer = None;
+    }
+
     pub async fn fetch(&mut self, mergeboxes: &Arc<Mutex<Mergeboxes>>) -> Result<(), Error> {
         println!("\x1b[0;32mmongo\x1b[0m fetch({:?})", self.description);
 
-        let mut documents: Vec<_> = self
-            .database
-            .collection::<Document>(&self.description.collection)
-            .find(
-                Some(self.description.selector.clone()),
-                Some(self.description.as_find_options()),
-            )
-            .await?
-            .map(|maybe_document| maybe_document.map(into_ejson_document))
-            .try_collect()
-            .await?;
+        let (projection, is_projected_locally) =
+            projection(&self.description, self.viewer.is_some());
+        let documents = self.query(projection).await?;
+        let documents = match self.cache(documents, is_projected_locally) {
+            Ok(documents) => documents,
+            Err(error) => {
+                // Documents have to be refetched, as they were not projected.
+                self.fall_back(error.downcast::<Explanation>()?);
+                let documents = self.query(self.description.projection.clone()).await?;
+                self.cache(documents, false)?
+            }
+        };
 
         let mut mergeboxes = mergeboxes.lock().await;
-
-        for document in &mut documents {
-            let id = extract_id(document)?;
+        for document in &documents {
             mergeboxes
                 .insert(
                     self.description.collection.clone(),
-                    id.clone(),
-                    document.clone(),
+                    document.id.clone(),
+                    &document.fields,
                 )
                 .await?;
-            document.insert(String::from("_id"), id);
         }
 
-        for mut document in replace(&mut self.documents, documents) {
-            let id = extract_id(&mut document)?;
+        for document in replace(&mut self.documents, documents) {
             mergeboxes
-                .remove(self.description.collection.clone(), id.clone(), &document)
+                .remove(
+                    self.description.collection.clone(),
+                    document.id,
+                    &document.fields,
+                )
                 .await?;
         }
 
         Ok(())
     }
 
+    async fn query(&self, projection: Option<Document>) -> Result<Vec<Map<String, Value>>, Error> {
+        let mut options = self.description.as_find_options();
+        options.projection = projection;
+
+        // Options of the cursor take precedence over the configured ones.
+        if options.read_concern.is_none() {
+            options.read_concern = self.reads.concern.clone();
+        }
+
+        if options.selection_criteria.is_none() {
+            options.selection_criteria = self
+                .reads
+                .preference
+                .clone()
+                .map(SelectionCriteria::ReadPreference);
+        }
+
+        let filter = Some(self.description.selector.clone());
+        let collection = self
+            .database
+            .collection::<Document>(&self.description.collection);
+        let documents: Vec<_> = match &options.selection_criteria {
+            None | Some(SelectionCriteria::ReadPreference(ReadPreference::Primary)) => {
+                collection
+                    .find(filter, Some(options))
+                    .await?
+                    .map(|maybe_document| maybe_document.map(into_ejson_document))
+                    .try_collect()
+                    .await?
+            }
+            Some(_) => {
+                // Secondaries may lag behind, so the read has to include
+                // everything the primary has now. Otherwise, the documents
+                // could be older than already processed change stream events.
+                let session_options = SessionOptions::builder().causal_consistency(true).build();
+                let mut session = collection.client().start_session(session_options).await?;
+                let primary = SelectionCriteria::ReadPreference(ReadPreference::Primary);
+                let response = self
+                    .database
+                    .run_command_with_session(doc! { "ping": 1 }, primary, &mut session)
+                    .await?;
+                if let Ok(operation_time) = response.get_timestamp("operationTime") {
+                    session.advance_operation_time(operation_time);
+                }
+
+                let mut cursor = collection
+                    .find_with_session(filter, Some(options), &mut session)
+                    .await?;
+                cursor
+                    .stream(&mut session)
+                    .map(|maybe_document| maybe_document.map(into_ejson_document))
+                    .try_collect()
+                    .await?
+            }
+        };
+
+        Ok(documents)
+    }
+
+    /// Computes the projected fields of fetched documents and their sort keys,
+    /// which are needed only to maintain the order of limited cursors. Fails
+    /// with an `Explanation` if the cursor has to fall back to polling.
+    fn cache(
+        &self,
+        documents: Vec<Map<String, Value>>,
+        is_projected_locally: bool,
+    ) -> Result<Vec<CachedDocument>, Error> {
+        let projector = self
+            .viewer
+            .as_ref()
+            .filter(|_| is_projected_locally)
+            .map(|viewer| &viewer.projector);
+        documents
+            .into_iter()
+            .map(|document| {
+                let key = match &self.viewer {
+                    Some(viewer) if self.description.limit().is_some() => {
+                        viewer.check(&document)?;
+                        viewer
+                            .sorter
+                            .key(&document)
+                            .map_err(|error| Part::Sort.explain(error))?
+                    }
+                    _ => SortKey::default(),
+                };
+
+                let id = extract_id(&document)?;
+                let fields = share(&document, projector, &self.flattening)?;
+                Ok(CachedDocument::new(id, fields, key))
+            })
+            .collect()
+    }
+
     pub fn new(
         database: Database,
         description: CursorDescription,
         watcher: Arc<Mutex<Watcher>>,
+        reads: ReadOptions,
+        flattening: Flattening,
+        fell_back: FellBack,
     ) -> Self {
-        let viewer = match CursorViewer::try_from(&description) {
-            Ok(viewer) => Some(viewer),
-            Err(error) => {
-                println!("\x1b[0;32mmongo\x1b[0m \x1b[0;31m{error}\x1b[0m");
-                None
+        let (viewer, explanation) = match CursorViewer::try_from(&description) {
+            Ok(viewer) => (Some(viewer), None),
+            Err(explanation) => {
+                println!(
+                    "\x1b[0;32mmongo\x1b[0m \x1b[0;31m{}: {explanation}\x1b[0m",
+                    description.collection
+                );
+                (None, Some(explanation))
             }
         };
 
@@ -79,25 +229,55 @@ impl CursorFetcher {
             database,
             description,
             documents: Vec::default(),
+            explanation,
+            fell_back,
+            flattening,
+            reads,
             viewer,
             watcher,
         }
     }
 
+    /// All documents sent to the mergeboxes (with their DDP `_id`).
+    pub fn documents(&self) -> Vec<(Value, SharedDocument)> {
+        self.documents
+            .iter()
+            .map(|document| {
+                let mut id = document.id.clone();
+                into_ddp(&mut id);
+                (id, document.fields.clone())
+            })
+            .collect()
+    }
+
+    pub fn explanation(&self) -> Option<&Explanation> {
+        self.explanation.as_ref()
+    }
+
     pub async fn process(
         &mut self,
         event: Event,
         mergeboxes: &Arc<Mutex<Mergeboxes>>,
     ) -> Result<(), Error> {
-        let refetch = process(
+        let refetch = match process(
             event,
             &self.description,
             &mut self.documents,
             mergeboxes,
             self.viewer.as_ref().unwrap(),
+            &self.flattening,
         )
         .await
-        .context("CursorFetcher::process")?;
+        {
+            Ok(refetch) => refetch,
+            Err(error) => match error.downcast::<Explanation>() {
+                Ok(explanation) => {
+                    self.fall_back(explanation);
+                    true
+                }
+                Err(error) => return Err(error.context("CursorFetcher::process")),
+            },
+        };
 
         if refetch {
             self.fetch(mergeboxes)
@@ -108,12 +288,22 @@ impl CursorFetcher {
         Ok(())
     }
 
-    pub async fn register(&self, mergebox: &Arc<Mutex<Mergebox>>) -> Result<(), Error> {
+    pub async fn register(
+        &self,
+        mergebox: &Arc<Mutex<Mergebox>>,
+        owner: &Owner,
+    ) -> Result<(), Error> {
         let mut mergebox = mergebox.lock().await;
-        for mut document in self.documents.clone() {
-            let id = extract_id(&mut document)?;
+        for document in &self.documents {
+            let mut id = document.id.clone();
+            into_ddp(&mut id);
             mergebox
-                .insert(self.description.collection.clone(), id, document)
+                .insert(
+                    self.description.collection.clone(),
+                    id,
+                    owner,
+                    &document.fields,
+                )
                 .await
                 .context("CursorFetcher::register")?;
         }
@@ -121,24 +311,40 @@ impl CursorFetcher {
         Ok(())
     }
 
-    pub async fn watch(&self) -> Result<Receiver<Event>, Interval> {
+    pub async fn watch(&self) -> Updates {
         if self.viewer.is_some() {
             let mut watcher = self.watcher.lock().await;
-            Ok(watcher.watch(self.description.collection.clone()).await)
+            Updates::Events(watcher.watch(self.description.collection.clone()).await)
+        } else if self.description.is_triggered() {
+            let mut watcher = self.watcher.lock().await;
+            let receiver = watcher.watch(self.description.collection.clone()).await;
+            // Meteor's default.
+            let throttle = self.description.polling_throttle_ms.unwrap_or(50);
+            Updates::Trigger(receiver, Duration::from_millis(throttle))
         } else {
             // Meteor's default.
             let interval = self.description.polling_interval_ms.unwrap_or(10_000);
             let duration = Duration::from_millis(interval);
-            Err(interval_at(Instant::now() + duration, duration))
+            Updates::Polling(interval_at(Instant::now() + duration, duration))
         }
     }
 
-    pub async fn unregister(&self, mergebox: &Arc<Mutex<Mergebox>>) -> Result<(), Error> {
+    pub async fn unregister(
+        &self,
+        mergebox: &Arc<Mutex<Mergebox>>,
+        owner: &Owner,
+    ) -> Result<(), Error> {
         let mut mergebox = mergebox.lock().await;
-        for mut document in self.documents.clone() {
-            let id = extract_id(&mut document)?;
+        for document in &self.documents {
+            let mut id = document.id.clone();
+            into_ddp(&mut id);
             mergebox
-                .remove(self.description.collection.clone(), id, &document)
+                .remove(
+                    self.description.collection.clone(),
+                    id,
+                    owner,
+                    &document.fields,
+                )
                 .await
                 .context("CursorFetcher::unregister")?;
         }
@@ -147,92 +353,123 @@ impl CursorFetcher {
     }
 }
 
-fn extract_id(document: &mut Map<String, Value>) -> Result<Value, Error> {
+fn extract_id(document: &Map<String, Value>) -> Result<Value, Error> {
     document
-        .remove("_id")
+        .get("_id")
+        .cloned()
         .ok_or_else(|| anyhow!("_id not found in {document:?}"))
 }
 
+/// Projected fields of the document (without `_id`), converted to DDP format
+/// and flattened (if configured).
+fn share(
+    document: &Map<String, Value>,
+    projector: Option<&Projector>,
+    flattening: &Flattening,
+) -> Result<SharedDocument, Explanation> {
+    let mut fields = document.clone();
+    fields.remove("_id");
+    if let Some(projector) = projector {
+        projector
+            .apply(&mut fields)
+            .map_err(|error| Part::Projection.explain(error))?;
+    }
+
+    into_ddp_document(&mut fields);
+    flattening.apply(&mut fields);
+    Ok(SharedDocument::from(fields))
+}
+
 async fn process(
     event: Event,
     description: &CursorDescription,
-    documents: &mut Vec<Map<String, Value>>,
+    documents: &mut Vec<CachedDocument>,
     mergeboxes: &Arc<Mutex<Mergeboxes>>,
     viewer: &CursorViewer,
+    flattening: &Flattening,
 ) -> Result<bool, Error> {
     match event {
         Event::Clear => {
             let mut mergeboxes = mergeboxes.lock().await;
-            for mut document in take(documents) {
-                let id = extract_id(&mut document)?;
-                viewer.projector.apply(&mut document);
+            for document in take(documents) {
+                let id = document.id;
                 mergeboxes
-                    .remove(description.collection.clone(), id, &document)
+                    .remove(description.collection.clone(), id, &document.fields)
                     .await
                     .context("process -> Event::Clear")?;
             }
             Ok(false)
         }
         Event::Delete(document) => {
-            let mut document = into_ejson_document(document);
-            let id = extract_id(&mut document)?;
-            let Some(index) = documents.iter().position(|x| x.get("_id") == Some(&id)) else {
+            let document = into_ejson_document(document);
+            let id = extract_id(&document)?;
+            let Some(index) = position(documents, Some(&id)) else {
                 return Ok(false);
             };
 
-            if description
-                .limit()
-                .is_some_and(|limit| limit == documents.len())
-            {
-                return Ok(true);
-            }
+            let document = match description.limit() {
+                Some(limit) => {
+                    // If we fall below the limit, we need to refetch.
+                    if limit == documents.len() {
+                        return Ok(true);
+                    }
+
+                    documents.remove(index)
+                }
+                None => documents.swap_remove(index),
+            };
 
-            let mut document = documents.swap_remove(index);
-            document.remove("_id");
-            viewer.projector.apply(&mut document);
             mergeboxes
                 .lock()
                 .await
-                .remove(description.collection.clone(), id, &document)
+                .remove(description.collection.clone(), id, &document.fields)
                 .await
                 .context("process -> Event::Delete")?;
 
             Ok(false)
         }
         Event::Insert(document) => {
-            let mut document = into_ejson_document(document);
+            let document = into_ejson_document(document);
+            viewer.check(&document)?;
             if !viewer.matcher.matches(&document) {
                 return Ok(false);
             }
 
-            if let Some(limit) = description.limit() {
+            let id = extract_id(&document)?;
+            let fields = if let Some(limit) = description.limit() {
+                let key = viewer
+                    .sorter
+                    .key(&document)
+                    .map_err(|error| Part::Sort.explain(error))?;
                 let index = documents
-                    .binary_search_by(|x| viewer.sorter.cmp(x, &document))
+                    .binary_search_by(|x| viewer.sorter.cmp(&x.key, &key))
                     .unwrap_or_else(|index| index);
                 if index == limit {
                     return Ok(false);
                 }
 
-                documents.insert(index, document.clone());
+                let fields = share(&document, Some(&viewer.projector), flattening)?;
+                documents.insert(index, CachedDocument::new(id.clone(), fields.clone(), key));
+                fields
             } else {
-                documents.push(document.clone());
-            }
+                let fields = share(&document, Some(&viewer.projector), flattening)?;
+                let key = SortKey::default();
+                documents.push(CachedDocument::new(id.clone(), fields.clone(), key));
+                fields
+            };
 
-            let id = extract_id(&mut document)?;
-            viewer.projector.apply(&mut document);
             let mut mergeboxes = mergeboxes.lock().await;
             mergeboxes
-                .insert(description.collection.clone(), id, document)
+                .insert(description.collection.clone(), id, &fields)
                 .await
                 .context("process -> Event::Insert")?;
 
             if let Some(limit) = description.limit() {
                 if documents.len() > limit {
-                    if let Some(mut document) = documents.pop() {
-                        let id = extract_id(&mut document)?;
-                        viewer.projector.apply(&mut document);
+                    if let Some(document) = documents.pop() {
+                        let id = document.id;
                         mergeboxes
-                            .remove(description.collection.clone(), id, &document)
+                            .remove(description.collection.clone(), id, &document.fields)
                             .await
                             .context("process -> Event::Insert")?;
                     }
@@ -242,58 +479,92 @@ async fn process(
             Ok(false)
         }
         Event::Update(document) => {
-            let mut document = into_ejson_document(document);
-            let is_matching = viewer.matcher.matches(&document);
-            if is_matching {
-                let index_before = {
-                    let id = document.get("_id");
-                    documents.iter().position(|x| x.get("_id") == id)
-                };
-
-                if let Some(limit) = description.limit() {
-                    let index = documents
-                        .binary_search_by(|x| viewer.sorter.cmp(x, &document))
+            let document = into_ejson_document(document);
+            viewer.check(&document)?;
+            let index_before = position(documents, document.get("_id"));
+            if viewer.matcher.matches(&document) {
+                let id = extract_id(&document)?;
+                let fields = share(&document, Some(&viewer.projector), flattening)?;
+                let cached = fields.clone();
+                let document_before = if let Some(limit) = description.limit() {
+                    let key = viewer
+                        .sorter
+                        .key(&document)
+                        .map_err(|error| Part::Sort.explain(error))?;
+                    let mut index = documents
+                        .binary_search_by(|x| viewer.sorter.cmp(&x.key, &key))
                         .unwrap_or_else(|index| index);
 
-                    // Skip newly matching documents that don't fit in `limit`.
-                    if index == limit {
-                        return Ok(false);
-                    }
+                    let document_before = match index_before {
+                        Some(index_before) => {
+                            // The previous version is still cached.
+                            if index > index_before {
+                                index -= 1;
+                            }
 
-                    documents.insert(index, document.clone());
+                            // If it moved to the very end, one of the documents
+                            // that are not cached may take its place instead.
+                            if documents.len() == limit
+                                && index == limit - 1
+                                && viewer
+                                    .sorter
+                                    .cmp(&key, &documents[index_before].key)
+                                    .is_gt()
+                            {
+                                return Ok(true);
+                            }
+
+                            Some(documents.remove(index_before))
+                        }
+                        None => {
+                            // Skip newly matching documents that don't fit in `limit`.
+                            if index == limit {
+                                return Ok(false);
+                            }
+
+                            None
+                        }
+                    };
+
+                    documents.insert(index, CachedDocument::new(id.clone(), cached, key));
+                    document_before
                 } else {
-                    documents.push(document.clone());
-                }
+                    let document_before = index_before.map(|index| documents.swap_remove(index));
+                    let key = SortKey::default();
+                    documents.push(CachedDocument::new(id.clone(), cached, key));
+                    document_before
+                };
 
-                let id = extract_id(&mut document)?;
-                viewer.projector.apply(&mut document);
                 let mut mergeboxes = mergeboxes.lock().await;
                 mergeboxes
-                    .insert(description.collection.clone(), id.clone(), document)
+                    .insert(description.collection.clone(), id.clone(), &fields)
                     .await
                     .context("process -> Event::Update")?;
 
-                if let Some(index) = index_before {
-                    let mut document = if description.limit().is_some() {
-                        documents.remove(index)
-                    } else {
-                        documents.swap_remove(index)
-                    };
-
-                    document.remove("_id");
-                    viewer.projector.apply(&mut document);
+                if let Some(document) = document_before {
                     mergeboxes
-                        .remove(description.collection.clone(), id, &document)
+                        .remove(description.collection.clone(), id, &document.fields)
                         .await
                         .context("process -> Event::Update")?;
                 }
+
+                if let Some(limit) = description.limit() {
+                    if documents.len() > limit {
+                        if let Some(document) = documents.pop() {
+                            let id = document.id;
+                            mergeboxes
+                                .remove(description.collection.clone(), id, &document.fields)
+                                .await
+                                .context("process -> Event::Update")?;
+                        }
+                    }
+                }
             } else {
-                let id = extract_id(&mut document)?;
-                let Some(index) = documents.iter().position(|x| x.get("_id") == Some(&id)) else {
+                let Some(index) = index_before else {
                     return Ok(false);
                 };
 
-                let mut document = match description.limit() {
+                let document = match description.limit() {
                     Some(limit) => {
                         // If we fall below the limit, we need to refetch.
                         if limit == documents.len() {
@@ -305,12 +576,11 @@ async fn process(
                     None => documents.swap_remove(index),
                 };
 
-                let id = extract_id(&mut document)?;
-                viewer.projector.apply(&mut document);
+                let id = document.id;
                 mergeboxes
                     .lock()
                     .await
-                    .remove(description.collection.clone(), id, &document)
+                    .remove(description.collection.clone(), id, &document.fields)
                     .await
                     .context("process -> Event::Update")?;
             }
@@ -320,18 +590,85 @@ async fn process(
     }
 }
 
+/// Projection sent to the database and whether the fetched documents have to
+/// be projected locally too. Sort keys of limited cursors need the sorted
+/// fields, so these are added to plain projections (and removed by the local
+/// one) and other projections are applied only locally.
+fn projection(description: &CursorDescription, is_viewed: bool) -> (Option<Document>, bool) {
+    let Some(mut projection) = description.projection.clone() else {
+        return (None, false);
+    };
+
+    let (true, Some(_), Some(sort)) = (is_viewed, description.limit(), &description.sort) else {
+        return (Some(projection), false);
+    };
+
+    let is_plain = projection.iter().all(|(path, value)| {
+        !path.contains('$') && matches!(value, Bson::Boolean(_) | Bson::Int32(0 | 1))
+    });
+    if !is_plain {
+        return (None, true);
+    }
+
+    let is_included = |value: &Bson| matches!(value, Bson::Boolean(true) | Bson::Int32(1));
+    let is_inclusive = projection
+        .iter()
+        .any(|(path, value)| path != "_id" && is_included(value));
+    for path in sort.keys() {
+        if is_inclusive && projection.get(path).is_some_and(is_included) {
+            continue;
+        }
+
+        if projection.keys().any(|key| is_overlapping(key, path)) {
+            return (None, true);
+        }
+
+        if is_inclusive {
+            projection.insert(path.clone(), 1);
+        }
+    }
+
+    (Some(projection), true)
+}
+
+/// Whether one of the paths is a prefix of (or equal to) the other.
+fn is_overlapping(lhs: &str, rhs: &str) -> bool {
+    let (shorter, longer) = if lhs.len() < rhs.len() {
+        (lhs, rhs)
+    } else {
+        (rhs, lhs)
+    };
+
+    longer
+        .strip_prefix(shorter)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+fn position(documents: &[CachedDocument], id: Option<&Value>) -> Option<usize> {
+    documents.iter().position(|x| Some(&x.id) == id)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{process, CursorDescription, CursorViewer};
+    use super::{
+        process, projection, CursorDescription, CursorFetcher, CursorViewer, Explanation, Part,
+    };
     use crate::ddp::DDPMessage;
-    use crate::mergebox::{Mergebox, Mergeboxes};
-    use crate::watcher::Event;
+    use crate::ejson::into_ejson_document;
+    use crate::flatten::Flattening;
+    use crate::mergebox::{Mergebox, Mergeboxes, Owner};
+    use crate::outbox::Outbox;
+    use crate::settings::Inconsistencies;
+    use crate::watcher::{Event, Watcher};
     use anyhow::Error;
     use bson::doc;
+    use mongodb::options::ClientOptions;
+    use mongodb::Client;
     use serde::Deserialize;
-    use serde_json::{json, Value};
+    use serde_json::{json, Map, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
-    use tokio::sync::mpsc::channel;
+    use std::time::Instant;
     use tokio::sync::Mutex;
     use tokio::test;
 
@@ -339,21 +676,37 @@ mod tests {
         description: Value,
         events: Vec<Event>,
         messages: Vec<DDPMessage>,
+        flattening: Flattening,
     ) -> Result<(), Error> {
         let description = CursorDescription::deserialize(description)?;
         let viewer = CursorViewer::try_from(&description)?;
-        let (sender, mut receiver) = channel(64);
+        let (sender, mut receiver) = Outbox::new(None);
+        let mergebox = Arc::new(Mutex::new(Mergebox::new(
+            sender,
+            Inconsistencies::Strict,
+            Arc::default(),
+        )));
         let mergeboxes = Arc::new(Mutex::new({
             let mut mergeboxes = Mergeboxes::default();
-            mergeboxes.insert_mergebox(1, &Arc::new(Mutex::new(Mergebox::new(sender))));
+            mergeboxes.insert_mergebox(1, Owner::Subscription("a".into()), &mergebox);
             mergeboxes
         }));
 
         let mut documents = Vec::new();
         for event in events {
-            process(event, &description, &mut documents, &mergeboxes, &viewer).await?;
+            process(
+                event,
+                &description,
+                &mut documents,
+                &mergeboxes,
+                &viewer,
+                &flattening,
+            )
+            .await?;
         }
 
+        mergebox.lock().await.flush().await?;
+
         for message in messages {
             assert_eq!(receiver.try_recv(), Ok(message));
         }
@@ -366,9 +719,13 @@ mod tests {
 
     macro_rules! simulate {
         ($name:ident, $description:expr, $events:expr, $messages:expr) => {
+            simulate!($name, $description, $events, $messages, []);
+        };
+        ($name:ident, $description:expr, $events:expr, $messages:expr, [$($field:expr),*]) => {
             #[test]
             async fn $name() -> Result<(), Error> {
-                simulate($description, $events, $messages).await
+                let flattening = Flattening::new(vec![$($field.to_owned()),*]);
+                simulate($description, $events, $messages, flattening).await
             }
         };
     }
@@ -420,4 +777,307 @@ mod tests {
             }
         ]
     );
+
+    simulate!(
+        scenario_3,
+        json! {{"collectionName": "x", "selector": {"a": {"$type": "long"}}, "options": {}}},
+        vec![
+            Event::Insert(doc! {"_id": 1_i64, "a": 9_007_199_254_740_993_i64, "b": [2_i64]}),
+            Event::Insert(doc! {"_id": 2, "a": 3}),
+            Event::Clear
+        ],
+        vec![
+            DDPMessage::Added {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: Some(json_doc! {"a": 9_007_199_254_740_993_i64, "b": [2]}),
+                cleared: None,
+            },
+            DDPMessage::Removed {
+                collection: "x".to_owned(),
+                id: json!(1)
+            }
+        ]
+    );
+
+    simulate!(
+        scenario_4,
+        json! {{"collectionName": "x", "selector": {}, "options": {"projection": {"a": {"$slice": [1, 1]}}}}},
+        vec![
+            Event::Insert(doc! {"_id": 1, "a": [1, 2, 3]}),
+            Event::Update(doc! {"_id": 1, "a": [4, 5, 6], "b": 7}),
+            Event::Delete(doc! {"_id": 1})
+        ],
+        vec![
+            DDPMessage::Added {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: Some(json_doc! {"a": [2]}),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: Some(json_doc! {"a": [5], "b": 7}),
+                cleared: None,
+            },
+            DDPMessage::Removed {
+                collection: "x".to_owned(),
+                id: json!(1)
+            }
+        ]
+    );
+
+    simulate!(
+        scenario_5,
+        json! {{"collectionName": "x", "selector": {}, "options": {"limit": 2, "sort": {"a": 1}}}},
+        vec![
+            Event::Insert(doc! {"_id": 1, "a": 1}),
+            Event::Insert(doc! {"_id": 2, "a": 3}),
+            Event::Insert(doc! {"_id": 3, "a": 5}),
+            Event::Update(doc! {"_id": 2, "a": 0}),
+            Event::Update(doc! {"_id": 1, "a": -1}),
+            Event::Clear
+        ],
+        vec![
+            DDPMessage::Added {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: Some(json_doc! {"a": 1}),
+                cleared: None,
+            },
+            DDPMessage::Added {
+                collection: "x".to_owned(),
+                id: json!(2),
+                fields: Some(json_doc! {"a": 3}),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: "x".to_owned(),
+                id: json!(2),
+                fields: Some(json_doc! {"a": 0}),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: Some(json_doc! {"a": -1}),
+                cleared: None,
+            },
+            DDPMessage::Removed {
+                collection: "x".to_owned(),
+                id: json!(1)
+            },
+            DDPMessage::Removed {
+                collection: "x".to_owned(),
+                id: json!(2)
+            }
+        ]
+    );
+
+    simulate!(
+        flattened,
+        json! {{"collectionName": "x", "selector": {}, "options": {}}},
+        vec![
+            Event::Insert(doc! {"_id": 1, "a": [1, 2], "b": {"c": [3]}}),
+            Event::Update(doc! {"_id": 1, "a": [1, 2, 4], "b": {"c": [3]}}),
+            Event::Update(doc! {"_id": 1, "a": [1], "b": {"c": [3]}}),
+            Event::Delete(doc! {"_id": 1})
+        ],
+        vec![
+            DDPMessage::Added {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: Some(json_doc! {"a.0": 1, "a.1": 2, "b": {"c": [3]}}),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: Some(json_doc! {"a.2": 4}),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: "x".to_owned(),
+                id: json!(1),
+                fields: None,
+                cleared: Some(vec!["a.1".to_owned(), "a.2".to_owned()]),
+            },
+            DDPMessage::Removed {
+                collection: "x".to_owned(),
+                id: json!(1)
+            }
+        ],
+        ["a"]
+    );
+
+    macro_rules! projection {
+        ($name:ident, $options:tt, $expected:expr, $is_projected_locally:expr) => {
+            #[test]
+            async fn $name() -> Result<(), Error> {
+                let description = CursorDescription::deserialize(
+                    json! {{"collectionName": "x", "selector": {}, "options": $options}},
+                )?;
+                let expected = ($expected, $is_projected_locally);
+                assert_eq!(projection(&description, true), expected);
+                Ok(())
+            }
+        };
+    }
+
+    projection!(projection_1, {}, None, false);
+    projection!(projection_2, {"projection": {"a": 1}}, Some(doc! {"a": 1}), false);
+    projection!(projection_3, {"projection": {"a": {"$slice": 1}}}, Some(doc! {"a": {"$slice": 1}}), false);
+    projection!(projection_4, {"projection": {"a": 1}, "limit": 1, "sort": {"a": 1}}, Some(doc! {"a": 1}), true);
+    projection!(projection_5, {"projection": {"a": 1}, "limit": 1, "sort": {"b.c": 1}}, Some(doc! {"a": 1, "b.c": 1}), true);
+    projection!(projection_6, {"projection": {"a": 0}, "limit": 1, "sort": {"b": 1}}, Some(doc! {"a": 0}), true);
+    projection!(projection_7, {"projection": {"a": 0}, "limit": 1, "sort": {"a.b": 1}}, None, true);
+    projection!(projection_8, {"projection": {"a.b": 1}, "limit": 1, "sort": {"a": 1}}, None, true);
+    projection!(projection_9, {"projection": {"a": {"$slice": 1}}, "limit": 1, "sort": {"b": 1}}, None, true);
+
+    macro_rules! fall_back {
+        ($name:ident, $description:expr, $event:expr, $part:expr) => {
+            #[test]
+            async fn $name() -> Result<(), Error> {
+                let description = CursorDescription::deserialize($description)?;
+                let viewer = CursorViewer::try_from(&description)?;
+                let mergeboxes = Arc::new(Mutex::new(Mergeboxes::default()));
+
+                let mut documents = Vec::new();
+                let flattening = Flattening::default();
+                let result = process(
+                    $event,
+                    &description,
+                    &mut documents,
+                    &mergeboxes,
+                    &viewer,
+                    &flattening,
+                )
+                .await;
+                let explanation = result.unwrap_err().downcast::<Explanation>()?;
+                assert_eq!(explanation.part, $part);
+                assert!(documents.is_empty());
+                Ok(())
+            }
+        };
+    }
+
+    fall_back!(
+        parallel_arrays,
+        json! {{"collectionName": "x", "selector": {}, "options": {"limit": 2, "sort": {"a": 1, "b": 1}}}},
+        Event::Insert(doc! {"_id": 1, "a": [1, 2], "b": [3, 4]}),
+        Part::Sort
+    );
+
+    fall_back!(
+        unsupported_collation,
+        json! {{"collectionName": "x", "selector": {}, "options": {"collation": {"locale": "en", "strength": 1}}}},
+        Event::Insert(doc! {"_id": 1, "a": "Øyvind"}),
+        Part::Collation
+    );
+
+    fall_back!(
+        failed_projection,
+        json! {{"collectionName": "x", "selector": {}, "options": {"projection": {"b": {"$size": "$a"}}}}},
+        Event::Insert(doc! {"_id": 1, "a": 1}),
+        Part::Projection
+    );
+
+    #[test]
+    async fn fall_back_reported() -> Result<(), Error> {
+        // The client connects lazily, so there's no need for a database.
+        let database = Client::with_options(ClientOptions::default())?.database("x");
+        let watcher = Arc::new(Mutex::new(Watcher::new(database.clone())));
+        let description = CursorDescription::deserialize(
+            json! {{"collectionName": "x", "selector": {}, "options": {}}},
+        )?;
+
+        let reported = Arc::new(AtomicUsize::new(0));
+        let fell_back = {
+            let reported = reported.clone();
+            Box::new(move |explanation: &Explanation| {
+                assert_eq!(explanation.part, Part::Sort);
+                reported.fetch_add(1, Ordering::Relaxed);
+            })
+        };
+
+        let reads = Default::default();
+        let flattening = Flattening::default();
+        let mut fetcher =
+            CursorFetcher::new(database, description, watcher, reads, flattening, fell_back);
+        assert!(fetcher.explanation().is_none());
+        assert_eq!(reported.load(Ordering::Relaxed), 0);
+
+        fetcher.fall_back(Part::Sort.explain("x"));
+        assert_eq!(fetcher.explanation(), Some(&Part::Sort.explain("x")));
+        assert_eq!(reported.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with `cargo test --release -- --ignored --nocapture`"]
+    async fn benchmark_limit_10k() -> Result<(), Error> {
+        let description = CursorDescription::deserialize(
+            json! {{"collectionName": "x", "selector": {}, "options": {"limit": 10_000, "sort": {"a.b": 1, "c": -1}}}},
+        )?;
+        let viewer = CursorViewer::try_from(&description)?;
+        let mergeboxes = Arc::new(Mutex::new(Mergeboxes::default()));
+        let events: Vec<_> = (0..2)
+            .flat_map(|round| (0..10_000).map(move |id| (round, id)))
+            .map(|(round, id)| {
+                let a = (id * 7_919 + round) % 10_007;
+                let document = doc! {"_id": id, "a": {"b": a, "x": [1, 2, 3]}, "c": id % 10};
+                if round == 0 {
+                    Event::Insert(document)
+                } else {
+                    Event::Update(document)
+                }
+            })
+            .collect();
+
+        // Sort keys used to be computed from both documents in every
+        // comparison of the binary search.
+        let start = Instant::now();
+        let mut documents: Vec<Map<String, Value>> = Vec::new();
+        for event in events.clone() {
+            let (Event::Insert(document) | Event::Update(document)) = event else {
+                unreachable!()
+            };
+            let document = into_ejson_document(document);
+            if let Some(index) = documents.iter().position(|x| x["_id"] == document["_id"]) {
+                documents.remove(index);
+            }
+
+            let index = documents
+                .binary_search_by(|x| {
+                    let lhs = viewer.sorter.key(x).unwrap();
+                    let rhs = viewer.sorter.key(&document).unwrap();
+                    viewer.sorter.cmp(&lhs, &rhs)
+                })
+                .unwrap_or_else(|index| index);
+            documents.insert(index, document);
+            documents.truncate(10_000);
+        }
+        let uncached = start.elapsed();
+
+        let start = Instant::now();
+        let mut documents = Vec::new();
+        for event in events {
+            process(
+                event,
+                &description,
+                &mut documents,
+                &mergeboxes,
+                &viewer,
+                &Flattening::default(),
+            )
+            .await?;
+        }
+        let cached = start.elapsed();
+
+        println!(
+            "20k events on a 10k-document limit cursor took {cached:?} ({uncached:?} without cached sort keys)"
+        );
+        Ok(())
+    }
 }
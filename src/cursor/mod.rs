@@ -3,17 +3,48 @@ mod fetcher;
 mod viewer;
 
 pub use description::CursorDescription;
+pub use viewer::{Explanation, Part};
 
 use crate::drop_handle::DropHandle;
-use crate::mergebox::{Mergebox, Mergeboxes};
-use crate::watcher::Watcher;
+use crate::flatten::Flattening;
+use crate::mergebox::{Mergebox, Mergeboxes, Owner};
+use crate::settings::ReadOptions;
+use crate::watcher::{Event, Watcher};
 use anyhow::{Context, Error};
-use fetcher::CursorFetcher;
+use fetcher::{CursorFetcher, FellBack, Updates};
 use futures_util::FutureExt;
 use mongodb::Database;
 use std::sync::Arc;
 use tokio::spawn;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::broadcast::Receiver;
 use tokio::sync::{Mutex, RwLock};
+use tokio::time::{sleep, Duration};
+use viewer::CursorViewer;
+
+/// Waits for the next change of the collection. Events that come within the
+/// `throttle` are drained, so all of them trigger only one refetch.
+async fn triggered(receiver: &mut Receiver<Event>, throttle: Duration) -> Result<(), Error> {
+    // Missed events are fine, as all of them trigger the same refetch.
+    if let Err(RecvError::Closed) = receiver.recv().await {
+        return Err(RecvError::Closed.into());
+    }
+
+    sleep(throttle).await;
+    loop {
+        match receiver.try_recv() {
+            Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+            Err(TryRecvError::Empty) => return Ok(()),
+            Err(TryRecvError::Closed) => return Err(RecvError::Closed.into()),
+        }
+    }
+}
+
+/// Why the cursor would have to be polled (if it would).
+pub fn explain_polling(description: &CursorDescription) -> Option<Explanation> {
+    let explanation = CursorViewer::try_from(description).err()?;
+    (!description.is_triggered()).then_some(explanation)
+}
 
 pub struct Cursor {
     description: CursorDescription,
@@ -27,12 +58,28 @@ impl Cursor {
         &self.description
     }
 
+    /// Why this cursor cannot use change streams (if it cannot), also if it
+    /// fell back to polling after it started.
+    pub async fn explanation(&self) -> Option<Explanation> {
+        self.fetcher.read().await.explanation().cloned()
+    }
+
     pub fn new(
         database: Database,
         description: CursorDescription,
         watcher: Arc<Mutex<Watcher>>,
+        reads: ReadOptions,
+        flattening: Flattening,
+        fell_back: FellBack,
     ) -> Self {
-        let fetcher = CursorFetcher::new(database, description.clone(), watcher);
+        let fetcher = CursorFetcher::new(
+            database,
+            description.clone(),
+            watcher,
+            reads,
+            flattening,
+            fell_back,
+        );
         Self {
             description,
             mergeboxes: Arc::new(Mutex::new(Mergeboxes::default())),
@@ -41,20 +88,49 @@ impl Cursor {
         }
     }
 
+    /// Checks the documents of the owner in the mergebox against the fetched
+    /// ones (see `Mergebox::resync`). The cursor cannot process any events in
+    /// the meantime, but other cursors can.
+    pub async fn resync(
+        &self,
+        session_id: usize,
+        owner: &Owner,
+        mergebox: &Arc<Mutex<Mergebox>>,
+    ) -> Result<usize, Error> {
+        let fetcher = self.fetcher.read().await;
+        let inserts = self.mergeboxes.lock().await.count(session_id, owner);
+        if inserts == 0 {
+            return Ok(0);
+        }
+
+        let documents = fetcher.documents();
+        mergebox
+            .lock()
+            .await
+            .resync(owner, &self.description.collection, inserts, documents)
+            .await
+            .context("Cursor::resync")
+    }
+
     pub async fn start(
         &mut self,
         session_id: usize,
+        owner: &Owner,
         mergebox: &Arc<Mutex<Mergebox>>,
     ) -> Result<(), Error> {
         // Register new mergebox. If it is the first one, start the background
-        // task. If not, add all already fetched documents to it.
-        let is_first = self
-            .mergeboxes
-            .lock()
-            .await
-            .insert_mergebox(session_id, mergebox);
+        // task. If not, add all already fetched documents to it. No event can
+        // be processed in the meantime, as it would reach the new mergebox too
+        // and its document would be added twice.
+        let fetcher = self.fetcher.read().await;
+        let is_first =
+            self.mergeboxes
+                .lock()
+                .await
+                .insert_mergebox(session_id, owner.clone(), mergebox);
 
         if is_first {
+            drop(fetcher);
             println!("\x1b[0;32mmongo\x1b[0m start({:?})", self.description);
 
             // Run initial query.
@@ -69,27 +145,41 @@ impl Cursor {
             // Start background task.
             let fetcher = self.fetcher.clone();
             let task = async move {
-                // Start an event processor or fall back to pooling.
-                let receiver_or_interval = fetcher.read().await.watch().await;
-                match receiver_or_interval {
-                    Ok(mut receiver) => loop {
-                        let event = receiver.recv().await?;
-                        fetcher
-                            .write()
-                            .await
-                            .process(event, &mergeboxes)
-                            .await
-                            .context("Cursor::start (process)")?;
-                    },
-                    Err(mut interval) => loop {
-                        interval.tick().await;
-                        fetcher
-                            .write()
-                            .await
-                            .fetch(&mergeboxes)
-                            .await
-                            .context("Cursor::start (refetch)")?;
-                    },
+                // Start an event processor or fall back to pooling. It may fall
+                // back later too, e.g., if a document cannot be sorted.
+                loop {
+                    let updates = fetcher.read().await.watch().await;
+                    match updates {
+                        Updates::Events(mut receiver) => loop {
+                            let event = receiver.recv().await?;
+                            let mut fetcher = fetcher.write().await;
+                            fetcher
+                                .process(event, &mergeboxes)
+                                .await
+                                .context("Cursor::start (process)")?;
+                            if fetcher.explanation().is_some() {
+                                break;
+                            }
+                        },
+                        Updates::Trigger(mut receiver, throttle) => loop {
+                            triggered(&mut receiver, throttle).await?;
+                            fetcher
+                                .write()
+                                .await
+                                .fetch(&mergeboxes)
+                                .await
+                                .context("Cursor::start (trigger)")?;
+                        },
+                        Updates::Polling(mut interval) => loop {
+                            interval.tick().await;
+                            fetcher
+                                .write()
+                                .await
+                                .fetch(&mergeboxes)
+                                .await
+                                .context("Cursor::start (refetch)")?;
+                        },
+                    }
                 }
             }
             .then(|result| async move {
@@ -102,7 +192,7 @@ impl Cursor {
             let _ = self.task.insert(DropHandle::new(spawn(task)));
         } else {
             println!("\x1b[0;32mmongo\x1b[0m reuse({:?})", self.description);
-            self.fetcher.read().await.register(mergebox).await?;
+            fetcher.register(mergebox, owner).await?;
         }
 
         Ok(())
@@ -111,19 +201,26 @@ impl Cursor {
     pub async fn stop(
         &mut self,
         session_id: usize,
+        owner: &Owner,
         mergebox: &Arc<Mutex<Mergebox>>,
     ) -> Result<(), Error> {
-        // Unregister all documents.
-        self.fetcher
-            .read()
-            .await
-            .unregister(mergebox)
+        // Unregister all documents. Just like in `start`, no event can be
+        // processed until the mergebox is removed, as it would be left with
+        // documents of this owner.
+        let fetcher = self.fetcher.read().await;
+        fetcher
+            .unregister(mergebox, owner)
             .await
             .context("Cursor::stop")?;
 
         // If it is the last one, stop the cursor. If it is the last one, stop
         // the background task.
-        let is_last = self.mergeboxes.lock().await.remove_mergebox(session_id);
+        let is_last = self
+            .mergeboxes
+            .lock()
+            .await
+            .remove_mergebox(session_id, owner);
+        drop(fetcher);
         if is_last {
             println!("\x1b[0;32mmongo\x1b[0m  stop({:?})", self.description);
 
@@ -136,3 +233,57 @@ impl Cursor {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::triggered;
+    use crate::watcher::Event;
+    use tokio::sync::broadcast::channel;
+    use tokio::sync::broadcast::error::TryRecvError;
+    use tokio::test;
+    use tokio::time::{sleep, timeout, Duration, Instant};
+
+    #[test(start_paused = true)]
+    async fn trigger_throttle() {
+        let (sender, mut receiver) = channel(16);
+        sender.send(Event::Clear).unwrap();
+        let later = sender.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(10)).await;
+            later.send(Event::Clear).unwrap();
+        });
+
+        // Both events trigger only one refetch, after the throttle.
+        let start = Instant::now();
+        triggered(&mut receiver, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+
+        // Nothing changed since then.
+        let next = triggered(&mut receiver, Duration::ZERO);
+        assert!(timeout(Duration::from_millis(20), next).await.is_err());
+    }
+
+    #[test]
+    async fn trigger_lagged() {
+        let (sender, mut receiver) = channel(2);
+        for _ in 0..5 {
+            sender.send(Event::Clear).unwrap();
+        }
+
+        // Missed events trigger a refetch too.
+        triggered(&mut receiver, Duration::ZERO).await.unwrap();
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    async fn trigger_closed() {
+        let (sender, mut receiver) = channel(2);
+        sender.send(Event::Clear).unwrap();
+        drop(sender);
+
+        assert!(triggered(&mut receiver, Duration::ZERO).await.is_err());
+    }
+}
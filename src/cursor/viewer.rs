@@ -1,20 +1,94 @@
 use super::description::CursorDescription;
+use crate::collation::Collation;
 use crate::matcher::DocumentMatcher;
 use crate::projector::Projector;
 use crate::sorter::Sorter;
-use anyhow::{ensure, Context, Error};
+use serde_json::{Map, Value};
+use std::fmt::{Display, Formatter};
 
 #[derive(Debug)]
 pub struct CursorViewer {
+    collation: Option<Collation>,
     pub matcher: DocumentMatcher,
     pub projector: Projector,
     pub sorter: Sorter,
 }
 
+impl CursorViewer {
+    /// Documents that cannot be matched or sorted locally (see
+    /// `Collation::check`) make the cursor fall back to polling.
+    pub fn check(&self, document: &Map<String, Value>) -> Result<(), Explanation> {
+        let Some(collation) = &self.collation else {
+            return Ok(());
+        };
+
+        document
+            .values()
+            .try_for_each(|value| collation.check(value))
+            .map_err(|error| Part::Collation.explain(error))
+    }
+}
+
+/// Why a `CursorViewer` could not be created, i.e., why the cursor cannot use
+/// change streams and has to rerun its query instead.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Explanation {
+    pub part: Part,
+    pub reason: String,
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not supported: {}", self.part, self.reason)
+    }
+}
+
+impl std::error::Error for Explanation {}
+
+/// Part of the `CursorDescription` that blocked the `CursorViewer`.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Part {
+    Collation,
+    DisableOplog,
+    Limit,
+    Projection,
+    Selector,
+    Skip,
+    Sort,
+}
+
+impl Part {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Collation => "collation",
+            Self::DisableOplog => "disableOplog",
+            Self::Limit => "limit",
+            Self::Projection => "projection",
+            Self::Selector => "selector",
+            Self::Skip => "skip",
+            Self::Sort => "sort",
+        }
+    }
+
+    pub fn explain(self, reason: impl Display) -> Explanation {
+        Explanation {
+            part: self,
+            reason: format!("{reason:#}"),
+        }
+    }
+}
+
+impl Display for Part {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl TryFrom<&CursorDescription> for CursorViewer {
-    type Error = Error;
+    type Error = Explanation;
     fn try_from(description: &CursorDescription) -> Result<Self, Self::Error> {
         let CursorDescription {
+            collation,
             disable_oplog,
             limit,
             projection,
@@ -24,21 +98,70 @@ impl TryFrom<&CursorDescription> for CursorViewer {
             ..
         } = description;
 
-        let matcher = DocumentMatcher::compile(selector)
-            .with_context(|| format!("selector {selector:?} is not supported"))?;
-        let projector = Projector::compile(projection.as_ref())
-            .with_context(|| format!("projection {projection:?} is not supported"))?;
-        let sorter = Sorter::compile(sort.as_ref())
-            .with_context(|| format!("sort {sort:?} is not supported"))?;
+        let collation = Collation::compile(collation.as_ref())
+            .map_err(|error| Part::Collation.explain(error))?;
+        // Sort is checked first, as `$meta` sorts come with `$text` selectors
+        // and its error explains the fallback better.
+        let sorter = Sorter::compile(sort.as_ref(), collation.as_ref())
+            .map_err(|error| Part::Sort.explain(error))?;
+        let matcher = DocumentMatcher::compile(selector, collation.as_ref())
+            .map_err(|error| Part::Selector.explain(error))?;
+        let projector = Projector::compile(projection.as_ref(), selector, collation.as_ref())
+            .map_err(|error| Part::Projection.explain(error))?;
+
+        if limit.is_some() && sort.is_none() {
+            return Err(Part::Limit.explain("limit requires sort"));
+        }
 
-        ensure!(limit.is_none() || sort.is_some(), "limit requires sort");
-        ensure!(matches!(skip, None | Some(0)), "skip is not supported");
-        ensure!(!*disable_oplog, "explicitly disabled");
+        // The storage order is not known (see `Sorter::compile`).
+        if limit.is_some()
+            && sort
+                .as_ref()
+                .is_some_and(|sort| sort.contains_key("$natural"))
+        {
+            return Err(Part::Limit.explain("limit requires sort other than $natural"));
+        }
+
+        if !matches!(skip, None | Some(0)) {
+            return Err(Part::Skip.explain("skip is not supported"));
+        }
+
+        if *disable_oplog {
+            return Err(Part::DisableOplog.explain("explicitly disabled"));
+        }
 
         Ok(Self {
+            collation,
             matcher,
             projector,
             sorter,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CursorDescription, CursorViewer, Part};
+    use serde::Deserialize;
+    use serde_json::json;
+
+    macro_rules! explain {
+        ($name:ident, { $($options:tt)* }, $selector:tt, $part:expr, $reason:expr) => {
+            #[test]
+            fn $name() {
+                let description = json! {{"collectionName": "x", "selector": $selector, "options": { $($options)* }}};
+                let description = CursorDescription::deserialize(description).unwrap();
+                let explanation = CursorViewer::try_from(&description).unwrap_err();
+                assert_eq!(explanation.part, $part);
+                assert_eq!(explanation.reason, $reason);
+            }
+        };
+    }
+
+    explain!(explain_1, {}, {"$where": "true"}, Part::Selector, "$where is not supported");
+    explain!(explain_2, {"sort": {"a": "asc"}}, {}, Part::Sort, "Sort order \"asc\" for a is not supported");
+    explain!(explain_3, {"limit": 1}, {}, Part::Limit, "limit requires sort");
+    explain!(explain_4, {"skip": 1}, {}, Part::Skip, "skip is not supported");
+    explain!(explain_5, {"disableOplog": true}, {}, Part::DisableOplog, "explicitly disabled");
+    explain!(explain_6, {"limit": 1, "sort": {"$natural": -1}}, {}, Part::Limit, "limit requires sort other than $natural");
+}
@@ -8,26 +8,45 @@ pub fn into_ejson(bson: Bson) -> Value {
         Bson::Binary(v) => json!({ "$binary": STANDARD.encode(v.bytes)}),
         Bson::DateTime(v) => json!({ "$date": v.timestamp_millis() }),
         Bson::Decimal128(v) => json!({ "$type": "Decimal", "$value": v.to_string() }),
-        Bson::Double(v) if v.is_infinite() => json!({ "$InfNaN": v.signum() }),
-        Bson::Double(v) if v.is_nan() => json!({ "$InfNan": 0 }),
+        Bson::Double(v) if v.is_infinite() => json!({ "$InfNaN": v.signum() as i8 }),
+        Bson::Double(v) if v.is_nan() => json!({ "$InfNaN": 0 }),
         Bson::ObjectId(v) => json!({ "$type": "oid", "$value": v.to_hex() }),
         Bson::RegularExpression(v) => json!({ "$regexp": v.pattern, "$flags": v.options }),
 
+        // Internal serialization, replaced in `into_ddp`. It is needed to
+        // differentiate between `int` and `long` and to support `$type` and
+        // sorting of types Meteor does not know.
+        Bson::DbPointer(v) => {
+            // Its fields are private, but the extended JSON has both the
+            // namespace and the id, i.e., `{"$ref": ..., "$id": {"$oid": ...}}`.
+            let value = match Bson::DbPointer(v).into_relaxed_extjson() {
+                Value::Object(mut object) => object.remove("$dbPointer"),
+                _ => None,
+            };
+            json!({ "$type": "dbPointer", "$value": value })
+        }
+        Bson::Int64(v) => json!({ "$type": "long", "$value": v }),
+        Bson::JavaScriptCode(v) => json!({ "$type": "javascript", "$value": v }),
+        Bson::JavaScriptCodeWithScope(v) => json!({
+            "$type": "javascriptWithScope",
+            "$value": { "code": v.code, "scope": into_ejson_document(v.scope) }
+        }),
+        Bson::MaxKey => json!({ "$type": "maxKey", "$value": 1 }),
+        Bson::MinKey => json!({ "$type": "minKey", "$value": 1 }),
+        Bson::Symbol(v) => json!({ "$type": "symbol", "$value": v }),
+        Bson::Timestamp(v) => {
+            json!({ "$type": "timestamp", "$value": { "t": v.time, "i": v.increment } })
+        }
+        Bson::Undefined => json!({ "$type": "undefined", "$value": null }),
+
         // Standard JSON serialization.
         Bson::Array(v) => Value::Array(v.into_iter().map(into_ejson).collect()),
         Bson::Boolean(v) => Value::Bool(v),
         Bson::Document(v) => Value::Object(into_ejson_document(v)),
         Bson::Double(v) => Value::Number(Number::from_f64(v).unwrap()),
         Bson::Int32(v) => Value::Number(Number::from(v)),
-        Bson::Int64(v) => Value::Number(Number::from(v)),
         Bson::Null => Value::Null,
         Bson::String(v) => Value::String(v),
-
-        // Replace everything else with `null`s.
-        v => {
-            println!("Unrecognized BSON value found: {v}");
-            Value::Null
-        }
     }
 }
 
@@ -37,3 +56,76 @@ pub fn into_ejson_document(document: Document) -> Map<String, Value> {
         .map(|(k, v)| (k, into_ejson(v)))
         .collect()
 }
+
+/// Replaces all internal EJSON values (see `into_ejson`) with ones Meteor
+/// understands. Types other than `long` are replaced with `null`s.
+pub fn into_ddp(value: &mut Value) {
+    match value {
+        Value::Array(values) => values.iter_mut().for_each(into_ddp),
+        Value::Object(object) => {
+            if object.len() == 2 && object.contains_key("$value") {
+                match object.get("$type").and_then(Value::as_str) {
+                    Some("long") => {
+                        *value = object.remove("$value").unwrap();
+                        return;
+                    }
+                    Some(
+                        "dbPointer"
+                        | "javascript"
+                        | "javascriptWithScope"
+                        | "maxKey"
+                        | "minKey"
+                        | "symbol"
+                        | "timestamp"
+                        | "undefined",
+                    ) => {
+                        *value = Value::Null;
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+
+            into_ddp_document(object);
+        }
+        _ => {}
+    }
+}
+
+pub fn into_ddp_document(document: &mut Map<String, Value>) {
+    document.values_mut().for_each(into_ddp);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::into_ejson;
+    use bson::Bson;
+    use serde_json::json;
+
+    fn db_pointer(namespace: &str, id: &str) -> Bson {
+        let extjson = json!({"$dbPointer": {"$ref": namespace, "$id": {"$oid": id}}});
+        Bson::try_from(extjson).unwrap()
+    }
+
+    #[test]
+    fn db_pointer_value() {
+        let id = "5f2b00000000000000000000";
+        assert_eq!(
+            into_ejson(db_pointer("db.x", id)),
+            json!({"$type": "dbPointer", "$value": {"$ref": "db.x", "$id": {"$oid": id}}})
+        );
+    }
+
+    #[test]
+    fn db_pointer_equality() {
+        let a = into_ejson(db_pointer("db.x", "5f2b00000000000000000000"));
+        let b = into_ejson(db_pointer("db.x", "5f2b00000000000000000001"));
+        let c = into_ejson(db_pointer("db.y", "5f2b00000000000000000000"));
+        assert_ne!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(
+            a,
+            into_ejson(db_pointer("db.x", "5f2b00000000000000000000"))
+        );
+    }
+}
@@ -0,0 +1,434 @@
+use crate::collation::Collation;
+use crate::ejson::into_ejson;
+use crate::numeric::Numeric;
+use crate::sorter::Sorter;
+use anyhow::{anyhow, Error};
+use bson::Bson;
+use serde_json::{Map, Value};
+
+/// An aggregation expression, limited to a subset of operators that cannot
+/// have side effects and do not depend on anything but the document itself.
+/// <https://www.mongodb.com/docs/manual/reference/operator/aggregation/>
+#[derive(Debug)]
+pub enum Expression {
+    Array(Vec<Self>),
+    Field(Vec<String>),
+    Literal(Value),
+    Operator(Operator, Vec<Self>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Operator {
+    And,
+    ArrayElemAt,
+    Compare(Comparison, Option<Collation>),
+    Concat,
+    Cond,
+    IfNull,
+    IsArray,
+    Not,
+    Or,
+    Size,
+    StrLenCP,
+    ToLower,
+    ToUpper,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Comparison {
+    Cmp,
+    Eq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Ne,
+}
+
+impl Expression {
+    pub fn compile(expression: &Bson, collation: Option<&Collation>) -> Result<Self, Error> {
+        match expression {
+            Bson::Array(expressions) => expressions
+                .iter()
+                .map(|expression| Self::compile(expression, collation))
+                .collect::<Result<_, _>>()
+                .map(Self::Array),
+            Bson::Document(document) if document.len() == 1 => {
+                let (operator, operand) = document.iter().next().unwrap();
+                if operator == "$literal" {
+                    return Ok(Self::Literal(into_ejson(operand.clone())));
+                }
+
+                if !operator.starts_with('$') {
+                    return Err(anyhow!("Expression {expression} is not supported"));
+                }
+
+                Self::compile_operator(operator, operand, collation)
+            }
+            Bson::Document(_) => Err(anyhow!("Expression {expression} is not supported")),
+            Bson::String(path) if path.starts_with("$$") => {
+                Err(anyhow!("Expression variable {path} is not supported"))
+            }
+            Bson::String(path) if path.starts_with('$') => {
+                let path: Vec<_> = path[1..].split('.').map(str::to_owned).collect();
+                if path
+                    .iter()
+                    .any(|key| key.is_empty() || key.starts_with('$'))
+                {
+                    return Err(anyhow!("Expression field path {expression} is invalid"));
+                }
+
+                // `_id` is extracted before the projection is applied.
+                if path[0] == "_id" {
+                    return Err(anyhow!(
+                        "Expression field path {expression} is not supported"
+                    ));
+                }
+
+                Ok(Self::Field(path))
+            }
+            expression => Ok(Self::Literal(into_ejson(expression.clone()))),
+        }
+    }
+
+    fn compile_operator(
+        name: &str,
+        operand: &Bson,
+        collation: Option<&Collation>,
+    ) -> Result<Self, Error> {
+        let (operator, arity) = match name {
+            "$and" => (Operator::And, 0..=usize::MAX),
+            "$arrayElemAt" => (Operator::ArrayElemAt, 2..=2),
+            "$cmp" => (
+                Operator::Compare(Comparison::Cmp, collation.copied()),
+                2..=2,
+            ),
+            "$concat" => (Operator::Concat, 0..=usize::MAX),
+            "$cond" => (Operator::Cond, 3..=3),
+            "$eq" => (Operator::Compare(Comparison::Eq, collation.copied()), 2..=2),
+            "$gt" => (Operator::Compare(Comparison::Gt, collation.copied()), 2..=2),
+            "$gte" => (
+                Operator::Compare(Comparison::Gte, collation.copied()),
+                2..=2,
+            ),
+            "$ifNull" => (Operator::IfNull, 2..=usize::MAX),
+            "$isArray" => (Operator::IsArray, 1..=1),
+            "$lt" => (Operator::Compare(Comparison::Lt, collation.copied()), 2..=2),
+            "$lte" => (
+                Operator::Compare(Comparison::Lte, collation.copied()),
+                2..=2,
+            ),
+            "$ne" => (Operator::Compare(Comparison::Ne, collation.copied()), 2..=2),
+            "$not" => (Operator::Not, 1..=1),
+            "$or" => (Operator::Or, 0..=usize::MAX),
+            "$size" => (Operator::Size, 1..=1),
+            "$strLenCP" => (Operator::StrLenCP, 1..=1),
+            "$toLower" => (Operator::ToLower, 1..=1),
+            "$toUpper" => (Operator::ToUpper, 1..=1),
+            operator => return Err(anyhow!("Expression operator {operator} is not supported")),
+        };
+
+        let operands = match (operator, operand) {
+            (Operator::Cond, Bson::Document(operand)) => {
+                let mut operands = vec![];
+                for key in ["if", "then", "else"] {
+                    let operand = operand
+                        .get(key)
+                        .ok_or_else(|| anyhow!("$cond requires {key}"))?;
+                    operands.push(Self::compile(operand, collation)?);
+                }
+
+                if operand.len() != 3 {
+                    return Err(anyhow!("$cond got unknown arguments: {operand:?}"));
+                }
+
+                operands
+            }
+            // A single argument does not have to be wrapped in an array.
+            (_, Bson::Array(operands)) => operands
+                .iter()
+                .map(|operand| Self::compile(operand, collation))
+                .collect::<Result<_, _>>()?,
+            (_, operand) => vec![Self::compile(operand, collation)?],
+        };
+
+        if !arity.contains(&operands.len()) {
+            return Err(anyhow!(
+                "Expression operator {name} got {} arguments",
+                operands.len()
+            ));
+        }
+
+        Ok(Self::Operator(operator, operands))
+    }
+
+    /// Returns `None` if the result is missing (e.g., a nonexistent field).
+    pub fn evaluate(&self, document: &Map<String, Value>) -> Result<Option<Value>, Error> {
+        match self {
+            Self::Array(expressions) => Ok(Some(Value::Array(
+                expressions
+                    .iter()
+                    .map(|expression| Ok(expression.evaluate(document)?.unwrap_or(Value::Null)))
+                    .collect::<Result<_, Error>>()?,
+            ))),
+            Self::Field(path) => Ok(document
+                .get(&path[0])
+                .and_then(|value| resolve(value, &path[1..]))),
+            Self::Literal(value) => Ok(Some(value.clone())),
+            Self::Operator(operator, operands) => {
+                let mut values = operands
+                    .iter()
+                    .map(|operand| operand.evaluate(document))
+                    .collect::<Result<Vec<_>, _>>()?;
+                evaluate_operator(*operator, &mut values)
+            }
+        }
+    }
+}
+
+fn evaluate_operator(
+    operator: Operator,
+    values: &mut [Option<Value>],
+) -> Result<Option<Value>, Error> {
+    let is_nullish = |value: &Option<Value>| matches!(value, None | Some(Value::Null));
+    Ok(Some(match operator {
+        Operator::And => Value::Bool(values.iter().all(is_truthy)),
+        Operator::ArrayElemAt => {
+            if values.iter().any(is_nullish) {
+                return Ok(Some(Value::Null));
+            }
+
+            let Some(Value::Array(array)) = &values[0] else {
+                return Err(anyhow!("$arrayElemAt expected an array, got {values:?}"));
+            };
+            let index = values[1]
+                .as_ref()
+                .and_then(Numeric::from_value)
+                .and_then(|index| index.as_i64_truncated())
+                .ok_or_else(|| anyhow!("$arrayElemAt expected an index, got {values:?}"))?;
+            let index = if index < 0 {
+                index + array.len() as i64
+            } else {
+                index
+            };
+
+            return Ok(usize::try_from(index)
+                .ok()
+                .and_then(|index| array.get(index))
+                .cloned());
+        }
+        Operator::Compare(comparison, collation) => {
+            let ordering = Sorter::cmp_value_option(
+                values[0].as_ref(),
+                values[1].as_ref(),
+                collation.as_ref(),
+            );
+            match comparison {
+                Comparison::Cmp => Value::from(ordering as i8),
+                Comparison::Eq => Value::Bool(ordering.is_eq()),
+                Comparison::Gt => Value::Bool(ordering.is_gt()),
+                Comparison::Gte => Value::Bool(ordering.is_ge()),
+                Comparison::Lt => Value::Bool(ordering.is_lt()),
+                Comparison::Lte => Value::Bool(ordering.is_le()),
+                Comparison::Ne => Value::Bool(ordering.is_ne()),
+            }
+        }
+        Operator::Concat => {
+            if values.iter().any(is_nullish) {
+                return Ok(Some(Value::Null));
+            }
+
+            let mut result = String::new();
+            for value in values.iter() {
+                match value {
+                    Some(Value::String(string)) => result.push_str(string),
+                    value => return Err(anyhow!("$concat expected a string, got {value:?}")),
+                }
+            }
+
+            Value::String(result)
+        }
+        Operator::Cond => {
+            let index = if is_truthy(&values[0]) { 1 } else { 2 };
+            return Ok(values[index].take());
+        }
+        Operator::IfNull => {
+            let (replacement, values) = values.split_last_mut().unwrap();
+            return Ok(values
+                .iter_mut()
+                .find(|value| !is_nullish(value))
+                .unwrap_or(replacement)
+                .take());
+        }
+        Operator::IsArray => Value::Bool(matches!(values[0], Some(Value::Array(_)))),
+        Operator::Not => Value::Bool(!is_truthy(&values[0])),
+        Operator::Or => Value::Bool(values.iter().any(is_truthy)),
+        Operator::Size => match &values[0] {
+            Some(Value::Array(array)) => Value::from(array.len()),
+            value => return Err(anyhow!("$size expected an array, got {value:?}")),
+        },
+        Operator::StrLenCP => match &values[0] {
+            Some(Value::String(string)) => Value::from(string.chars().count()),
+            value => return Err(anyhow!("$strLenCP expected a string, got {value:?}")),
+        },
+        Operator::ToLower | Operator::ToUpper => match &values[0] {
+            None | Some(Value::Null) => Value::String(String::new()),
+            // MongoDB maps only ASCII characters.
+            Some(Value::String(string)) if matches!(operator, Operator::ToLower) => {
+                Value::String(string.to_ascii_lowercase())
+            }
+            Some(Value::String(string)) => Value::String(string.to_ascii_uppercase()),
+            value => return Err(anyhow!("{operator:?} expected a string, got {value:?}")),
+        },
+    }))
+}
+
+/// <https://www.mongodb.com/docs/manual/reference/operator/aggregation/and/#behavior>
+fn is_truthy(value: &Option<Value>) -> bool {
+    match value {
+        None | Some(Value::Null | Value::Bool(false)) => false,
+        Some(value) => {
+            !Numeric::from_value(value).is_some_and(|number| number.cmp(&Numeric::Int(0)).is_eq())
+        }
+    }
+}
+
+/// Field paths traverse arrays, e.g., `$a.b` resolves `{a: [{b: 1}, {b: 2}]}`
+/// to `[1, 2]`.
+fn resolve(value: &Value, path: &[String]) -> Option<Value> {
+    let Some((key, rest)) = path.split_first() else {
+        return Some(value.clone());
+    };
+
+    match value {
+        Value::Array(values) => Some(Value::Array(
+            values
+                .iter()
+                .filter(|value| value.is_array() || value.is_object())
+                .filter_map(|value| resolve(value, path))
+                .collect(),
+        )),
+        Value::Object(object) => object.get(key).and_then(|value| resolve(value, rest)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expression;
+    use crate::ejson::into_ejson_document;
+    use bson::{bson, doc};
+    use serde_json::{json, Value};
+
+    macro_rules! test {
+        ($name:ident, $expression:tt, { $($document:tt)* }, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let expression = bson!($expression);
+                let document = into_ejson_document(doc! { $($document)* });
+                let expression = match Expression::compile(&expression, None) {
+                    Ok(expression) => expression,
+                    Err(error) => panic!("{expression:?} is not supported: {error:?}"),
+                };
+
+                let expected: Option<Value> = $expected;
+                assert_eq!(expression.evaluate(&document).ok(), Some(expected));
+            }
+        };
+    }
+
+    macro_rules! fail {
+        ($name:ident, $expression:tt) => {
+            #[test]
+            fn $name() {
+                let expression = bson!($expression);
+                if Expression::compile(&expression, None).is_ok() {
+                    panic!("{expression:?} should not be supported");
+                }
+            }
+        };
+    }
+
+    macro_rules! error {
+        ($name:ident, $expression:tt, { $($document:tt)* }) => {
+            #[test]
+            fn $name() {
+                let expression = bson!($expression);
+                let document = into_ejson_document(doc! { $($document)* });
+                let expression = Expression::compile(&expression, None).unwrap();
+                assert!(expression.evaluate(&document).is_err());
+            }
+        };
+    }
+
+    test!(field_1, "$a", {"a": 1}, Some(json!(1)));
+    test!(field_2, "$a", {}, None);
+    test!(field_3, "$a.b", {"a": {"b": 1}}, Some(json!(1)));
+    test!(field_4, "$a.b", {"a": [{"b": 1}, {"c": 2}, {"b": [3]}, 4]}, Some(json!([1, [3]])));
+    test!(field_5, "$a.b", {"a": 1}, None);
+    fail!(field_6, "$_id");
+    fail!(field_7, "$$ROOT");
+    fail!(field_8, "$a..b");
+
+    test!(literal_1, "a", {}, Some(json!("a")));
+    test!(literal_2, {"$literal": "$a"}, {"a": 1}, Some(json!("$a")));
+    test!(literal_3, ["$a", "b", "$c"], {"a": 1}, Some(json!([1, "b", null])));
+
+    test!(and_1, {"$and": [1, "$a"]}, {"a": true}, Some(json!(true)));
+    test!(and_2, {"$and": [1, "$a"]}, {"a": 0.0}, Some(json!(false)));
+    test!(and_3, {"$and": []}, {}, Some(json!(true)));
+    test!(or_1, {"$or": ["$a", "$b"]}, {"b": []}, Some(json!(true)));
+    test!(or_2, {"$or": ["$a", "$b"]}, {"a": null}, Some(json!(false)));
+    test!(not_1, {"$not": ["$a"]}, {}, Some(json!(true)));
+    test!(not_2, {"$not": "$a"}, {"a": "x"}, Some(json!(false)));
+
+    test!(cmp_1, {"$cmp": ["$a", 2]}, {"a": 1}, Some(json!(-1)));
+    test!(cmp_2, {"$cmp": ["$a", 2]}, {"a": 2_i64}, Some(json!(0)));
+    test!(cmp_3, {"$cmp": ["$a", null]}, {}, Some(json!(-1)));
+    test!(eq_1, {"$eq": ["$a", "$b"]}, {"a": 1, "b": 1.0}, Some(json!(true)));
+    test!(eq_2, {"$eq": ["$a", null]}, {}, Some(json!(false)));
+    test!(gt_1, {"$gt": ["$a", 1]}, {"a": "1"}, Some(json!(true)));
+    test!(gte_1, {"$gte": ["$a", 1]}, {"a": 1}, Some(json!(true)));
+    test!(lt_1, {"$lt": ["$a", 1]}, {"a": null}, Some(json!(true)));
+    test!(lte_1, {"$lte": ["$a", 1]}, {"a": 2}, Some(json!(false)));
+    test!(ne_1, {"$ne": ["$a", 1]}, {"a": 2}, Some(json!(true)));
+    fail!(eq_3, {"$eq": ["$a"]});
+
+    test!(cond_1, {"$cond": ["$a", "yes", "no"]}, {"a": 1}, Some(json!("yes")));
+    test!(cond_2, {"$cond": {"if": "$a", "then": "yes", "else": "no"}}, {}, Some(json!("no")));
+    test!(cond_3, {"$cond": ["$a", "$b", "no"]}, {"a": 1}, None);
+    fail!(cond_4, {"$cond": {"if": "$a", "then": "yes"}});
+    fail!(cond_5, {"$cond": {"if": "$a", "then": "yes", "else": "no", "x": 1}});
+    test!(if_null_1, {"$ifNull": ["$a", "b"]}, {}, Some(json!("b")));
+    test!(if_null_2, {"$ifNull": ["$a", "b"]}, {"a": null}, Some(json!("b")));
+    test!(if_null_3, {"$ifNull": ["$a", "$b", "c"]}, {"b": 0}, Some(json!(0)));
+    test!(if_null_4, {"$ifNull": ["$a", "$b"]}, {}, None);
+
+    test!(concat_1, {"$concat": ["$a", " ", "$b"]}, {"a": "x", "b": "y"}, Some(json!("x y")));
+    test!(concat_2, {"$concat": ["$a", " ", "$b"]}, {"a": "x"}, Some(json!(null)));
+    test!(concat_3, {"$concat": []}, {}, Some(json!("")));
+    error!(concat_4, {"$concat": ["$a", "x"]}, {"a": 1});
+    test!(to_lower_1, {"$toLower": "$a"}, {"a": "ÀBc"}, Some(json!("Àbc")));
+    test!(to_lower_2, {"$toLower": "$a"}, {}, Some(json!("")));
+    test!(to_upper_1, {"$toUpper": ["$a"]}, {"a": "aBc"}, Some(json!("ABC")));
+    test!(to_upper_3, {"$toUpper": "$a"}, {"a": "straße"}, Some(json!("STRAßE")));
+    error!(to_upper_2, {"$toUpper": "$a"}, {"a": []});
+    test!(str_len_cp_1, {"$strLenCP": "$a"}, {"a": "żółw"}, Some(json!(4)));
+    error!(str_len_cp_2, {"$strLenCP": "$a"}, {});
+
+    test!(size_1, {"$size": "$a"}, {"a": [1, 2, 3]}, Some(json!(3)));
+    test!(size_2, {"$size": ["$a.b"]}, {"a": [{"b": 1}, {"b": 2}]}, Some(json!(2)));
+    error!(size_3, {"$size": "$a"}, {"a": 1});
+    error!(size_4, {"$size": "$a"}, {});
+    test!(is_array_1, {"$isArray": "$a"}, {"a": []}, Some(json!(true)));
+    test!(is_array_2, {"$isArray": ["$a"]}, {"a": 1}, Some(json!(false)));
+    test!(array_elem_at_1, {"$arrayElemAt": ["$a", 1]}, {"a": [1, 2, 3]}, Some(json!(2)));
+    test!(array_elem_at_2, {"$arrayElemAt": ["$a", -1]}, {"a": [1, 2, 3]}, Some(json!(3)));
+    test!(array_elem_at_3, {"$arrayElemAt": ["$a", 3]}, {"a": [1, 2, 3]}, None);
+    test!(array_elem_at_4, {"$arrayElemAt": ["$a", 0]}, {}, Some(json!(null)));
+    error!(array_elem_at_5, {"$arrayElemAt": ["$a", "0"]}, {"a": [1]});
+
+    fail!(unknown_1, {"$function": {"body": "", "args": [], "lang": "js"}});
+    fail!(unknown_2, {"$add": [1, 2]});
+    fail!(unknown_3, {"a": 1, "b": 2});
+}
@@ -0,0 +1,188 @@
+use serde_json::{to_writer, Map, Value};
+use std::io::{Result, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Estimated number of bytes that were not sent thanks to flattening.
+static SAVED: AtomicUsize = AtomicUsize::new(0);
+
+/// Top-level fields published as multiple top-level fields, one per nested
+/// value (e.g., `items.0.name`), so a change deep inside (or an appended array
+/// element) is sent as a `changed` of only the affected ones. Clients have to
+/// reassemble them (e.g., in a `Mongo.Collection` transform).
+#[derive(Clone, Default)]
+pub struct Flattening(Arc<[String]>);
+
+impl Flattening {
+    pub fn apply(&self, fields: &mut Map<String, Value>) {
+        for field in self.0.iter() {
+            let Some(value) = fields.remove(field) else {
+                continue;
+            };
+
+            flatten(field.clone(), value, fields);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn new(fields: Vec<String>) -> Self {
+        Self(fields.into())
+    }
+
+    /// Records how many bytes a `changed` (of `fields` and `cleared`) did not
+    /// have to send, i.e., the size of the whole values of the affected
+    /// flattened fields (`size` of their flattened keys, without the field
+    /// itself, and values) compared to the sent ones. It's an upper
+    /// bound, as it ignores the JSON syntax (and an update that is sent as two
+    /// `changed`, of set and cleared fields, counts the whole values twice).
+    pub fn record(
+        &self,
+        fields: &Map<String, Value>,
+        cleared: &[String],
+        size: impl Fn(&str) -> usize,
+    ) {
+        let saved: usize = self
+            .0
+            .iter()
+            .map(|field| saving(field, fields, cleared, &size))
+            .sum();
+        SAVED.fetch_add(saved, Ordering::Relaxed);
+    }
+
+    pub fn saved() -> usize {
+        SAVED.load(Ordering::Relaxed)
+    }
+}
+
+/// Nested values are flattened too, except for EJSON ones (e.g., `$date`).
+/// Empty arrays and objects are kept as they are.
+fn flatten(path: String, value: Value, fields: &mut Map<String, Value>) {
+    match value {
+        Value::Array(values) if !values.is_empty() => {
+            for (index, value) in values.into_iter().enumerate() {
+                flatten(format!("{path}.{index}"), value, fields);
+            }
+        }
+        Value::Object(object)
+            if !object.is_empty() && !object.keys().any(|key| key.starts_with('$')) =>
+        {
+            for (key, value) in object {
+                flatten(format!("{path}.{key}"), value, fields);
+            }
+        }
+        value => {
+            fields.insert(path, value);
+        }
+    }
+}
+
+/// Whether the key is the field or one of its flattened values.
+pub fn is_flattened(field: &str, key: &str) -> bool {
+    key.strip_prefix(field)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+fn saving(
+    field: &str,
+    fields: &Map<String, Value>,
+    cleared: &[String],
+    size: impl Fn(&str) -> usize,
+) -> usize {
+    let sent = fields
+        .iter()
+        .filter(|(key, _)| is_flattened(field, key))
+        .map(|(key, value)| key.len() + size_of_json(value))
+        .chain(
+            cleared
+                .iter()
+                .filter(|key| is_flattened(field, key))
+                .map(String::len),
+        )
+        .sum::<usize>();
+
+    if sent == 0 {
+        return 0;
+    }
+
+    (field.len() + size(field)).saturating_sub(sent)
+}
+
+/// Length of the value serialized as JSON, without allocating it.
+pub fn size_of_json(value: &Value) -> usize {
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+            self.0 += buffer.len();
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    to_writer(&mut counter, value).map_or(0, |()| counter.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_flattened, saving, size_of_json, Flattening};
+    use serde_json::{json, Map, Value};
+
+    fn flattened(fields: &[&str], document: Value) -> Value {
+        let Value::Object(mut document) = document else {
+            unreachable!()
+        };
+        let fields = fields.iter().map(|field| field.to_string()).collect();
+        Flattening::new(fields).apply(&mut document);
+        Value::Object(document)
+    }
+
+    macro_rules! flatten {
+        ($name:ident, $fields:expr, $document:tt, $expected:tt) => {
+            #[test]
+            fn $name() {
+                assert_eq!(flattened(&$fields, json!($document)), json!($expected));
+            }
+        };
+    }
+
+    flatten!(flatten_1, [], {"a": [1, 2]}, {"a": [1, 2]});
+    flatten!(flatten_2, ["a"], {"a": [1, 2], "b": [3]}, {"b": [3], "a.0": 1, "a.1": 2});
+    flatten!(flatten_3, ["a"], {"a": {"b": {"c": 1}, "d": [2]}}, {"a.b.c": 1, "a.d.0": 2});
+    flatten!(flatten_4, ["a"], {"a": {"b": {"$date": 1}}}, {"a.b": {"$date": 1}});
+    flatten!(flatten_5, ["a"], {"a": {"b": [], "c": {}}}, {"a.b": [], "a.c": {}});
+    flatten!(flatten_6, ["a", "b"], {"a": 1}, {"a": 1});
+
+    #[test]
+    fn saved() {
+        let object = |value| {
+            let Value::Object(object) = value else {
+                unreachable!()
+            };
+            object
+        };
+
+        let items: Vec<_> = (0..101).collect();
+        let document = object(flattened(&["a"], json!({"a": items, "b": 2})));
+        let size = |field: &str| {
+            document
+                .iter()
+                .filter(|(key, _)| is_flattened(field, key))
+                .map(|(key, value)| key.len() - field.len() + size_of_json(value))
+                .sum()
+        };
+
+        // Only `a.100` was sent (or cleared) instead of the whole array.
+        let changed = object(json!({"a.100": 100, "b": 2}));
+        assert!(saving("a", &changed, &[], size) > 400);
+        assert!(saving("a", &Map::new(), &["a.100".to_owned()], size) > 400);
+        assert_eq!(saving("a", &object(json!({"b": 2})), &[], size), 0);
+        assert_eq!(saving("b", &changed, &[], size), 0);
+    }
+}
@@ -1,7 +1,10 @@
-use serde_json::Value;
+use serde_json::{Map, Value};
 
 #[derive(Clone, Debug)]
 pub struct Branch<'a> {
+    /// Implicitly iterated arrays as pairs of the number of path segments
+    /// leading to the array (`usize::MAX` for the leaf) and the element index.
+    pub array_indices: Vec<(usize, usize)>,
     pub dont_iterate: bool,
     pub value: Option<&'a Value>,
 }
@@ -16,8 +19,11 @@ impl Branch<'_> {
             }
 
             if this_is_array && !branch.dont_iterate {
-                for value in branch.value.unwrap().as_array().unwrap() {
+                for (index, value) in branch.value.unwrap().as_array().unwrap().iter().enumerate() {
+                    let mut array_indices = branch.array_indices.clone();
+                    array_indices.push((usize::MAX, index));
                     branches_out.push(Self {
+                        array_indices,
                         value: Some(value),
                         dont_iterate: false,
                     });
@@ -31,6 +37,7 @@ impl Branch<'_> {
 
 #[derive(Debug)]
 pub struct Lookup {
+    depth: usize,
     for_sort: bool,
     key: String,
     key_as_usize: Option<usize>,
@@ -38,12 +45,9 @@ pub struct Lookup {
 }
 
 impl Lookup {
-    pub fn lookup<'a>(&'a self, value: &'a Value) -> Vec<Branch> {
+    pub fn lookup<'a>(&'a self, value: &'a Value) -> Vec<Branch<'a>> {
         let Self {
-            for_sort,
-            key,
-            key_as_usize,
-            rest,
+            key, key_as_usize, ..
         } = &self;
 
         if let Value::Array(values) = value {
@@ -58,20 +62,39 @@ impl Lookup {
             _ => None,
         };
 
+        self.lookup_head(value_head, value.is_array())
+    }
+
+    /// Same as `lookup`, but does not require wrapping the document in a
+    /// `Value`.
+    pub fn lookup_document<'a>(&'a self, document: &'a Map<String, Value>) -> Vec<Branch<'a>> {
+        self.lookup_head(document.get(&self.key), false)
+    }
+
+    fn lookup_head<'a>(&'a self, value_head: Option<&'a Value>, is_array: bool) -> Vec<Branch<'a>> {
+        let Self {
+            depth,
+            for_sort,
+            rest,
+            ..
+        } = &self;
+
         let Some(rest) = rest else {
             return vec![Branch {
+                array_indices: vec![],
                 value: value_head,
-                dont_iterate: value.is_array() && value_head.is_some_and(Value::is_array),
+                dont_iterate: is_array && value_head.is_some_and(Value::is_array),
             }];
         };
 
         let Some(value_head) = value_head
             .filter(|value_head| matches!(value_head, Value::Array(_) | Value::Object(_)))
         else {
-            return if value.is_array() {
+            return if is_array {
                 vec![]
             } else {
                 vec![Branch {
+                    array_indices: vec![],
                     value: None,
                     dont_iterate: false,
                 }]
@@ -81,10 +104,13 @@ impl Lookup {
         let mut result = rest.lookup(value_head);
         if rest.key_as_usize.is_none() || !*for_sort {
             if let Value::Array(branches) = value_head {
-                for branch in branches {
+                for (index, branch) in branches.iter().enumerate() {
                     // TODO: Exclude BSON-specific types.
                     if branch.is_object() {
-                        result.extend(rest.lookup(branch));
+                        result.extend(rest.lookup(branch).into_iter().map(|mut branch| {
+                            branch.array_indices.insert(0, (depth + 1, index));
+                            branch
+                        }));
                     }
                 }
             }
@@ -94,16 +120,25 @@ impl Lookup {
     }
 
     pub fn new(key: String, for_sort: bool) -> Self {
+        Self::new_at_depth(key, for_sort, 0)
+    }
+
+    fn new_at_depth(key: String, for_sort: bool, depth: usize) -> Self {
         let (key, rest) = match key.split_once('.') {
             Some((key, rest)) => (
                 key.to_owned(),
-                Some(Box::new(Self::new(rest.to_owned(), for_sort))),
+                Some(Box::new(Self::new_at_depth(
+                    rest.to_owned(),
+                    for_sort,
+                    depth + 1,
+                ))),
             ),
             None => (key, None),
         };
         let key_as_usize = key.parse::<usize>().ok();
 
         Self {
+            depth,
             for_sort,
             key,
             key_as_usize,
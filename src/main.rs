@@ -1,30 +1,40 @@
+mod admin;
+mod batch;
+mod collation;
 mod cursor;
 mod ddp;
 mod drop_handle;
 mod ejson;
+mod expression;
+mod flatten;
 mod inflights;
 mod lookup;
 mod matcher;
 mod mergebox;
+mod numeric;
+mod outbox;
+mod pattern;
 mod projector;
+mod routing;
 mod session;
 mod settings;
 mod sorter;
 mod subscriptions;
 mod watcher;
 
+use admin::start_admin;
 use anyhow::{Context, Error};
 use futures_util::FutureExt;
-use mongodb::Client;
-use session::start_session;
+use routing::Databases;
+use session::{start_session, SessionOptions};
 use settings::Settings;
 use std::sync::Arc;
+use std::time::Duration;
 use subscriptions::Subscriptions;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use tokio::{main, spawn};
 use tokio_tungstenite::{accept_async, connect_async};
-use watcher::Watcher;
 
 #[main]
 async fn main() -> Result<(), Error> {
@@ -37,16 +47,42 @@ async fn main() -> Result<(), Error> {
         settings.router.url
     );
 
-    let database = Client::with_uri_str(settings.mongo.url)
-        .await
-        .context("Failed to connect to MongoDB")?
-        .default_database()
-        .expect("Mongo URL did not specify the database");
+    let databases = Databases::connect(settings.mongo).await?;
     println!("\x1b[0;33mrouter\x1b[0m Connected to MongoDB");
 
     let mut session_id_counter = 0;
-    let watcher = Watcher::new(database.clone());
-    let subscriptions = Arc::new(Mutex::new(Subscriptions::new(database, watcher)));
+    let options = SessionOptions {
+        client_buffer: settings
+            .router
+            .client_buffer
+            .map(|limit| (limit, settings.options.slow_clients)),
+        flatten: Arc::new(settings.flatten.clone()),
+        inconsistencies: settings.options.inconsistencies,
+        resync: settings.resync,
+        tick: Duration::from_millis(settings.router.tick_ms.unwrap_or(10)),
+    };
+    let subscriptions = Subscriptions::new(
+        databases,
+        settings.strict,
+        settings.options.unknown,
+        settings.reads,
+        settings.flatten,
+    );
+    let subscriptions = Arc::new(Mutex::new(subscriptions));
+
+    if let Some(admin) = &settings.admin {
+        let listener = TcpListener::bind(&admin.url).await?;
+        println!("\x1b[0;33mrouter\x1b[0m Admin listening at {}", admin.url);
+        spawn(
+            start_admin(listener, subscriptions.clone()).then(|result| async move {
+                // TODO: Better handling of subtasks.
+                if let Err(error) = &result {
+                    println!("\x1b[0;31m[[ERROR]] {error:?}\x1b[0m");
+                }
+                result
+            }),
+        );
+    }
 
     loop {
         // Get next ID.
@@ -57,6 +93,7 @@ async fn main() -> Result<(), Error> {
         let stream = listener.accept().await?.0;
         let meteor_url = settings.meteor.url.clone();
         let subscriptions = subscriptions.clone();
+        let options = options.clone();
         spawn(
             async move {
                 let client = accept_async(stream)
@@ -66,7 +103,7 @@ async fn main() -> Result<(), Error> {
                     .await
                     .context("Failed to connect to Meteor server")?
                     .0;
-                start_session(session_id, subscriptions.clone(), client, server).await
+                start_session(session_id, subscriptions.clone(), client, server, options).await
             }
             .then(|result| async move {
                 // TODO: Better handling of subtasks.
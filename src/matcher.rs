@@ -1,9 +1,11 @@
+use crate::collation::Collation;
 use crate::ejson::into_ejson;
 use crate::lookup::{Branch, Lookup};
+use crate::numeric::Numeric;
+use crate::pattern::Pattern;
 use crate::sorter::Sorter;
 use anyhow::{anyhow, Error};
 use bson::{Bson, Document, Regex as BsonRegex};
-use regex::{Regex, RegexBuilder};
 use serde_json::{Map, Value};
 use std::cmp::Ordering;
 
@@ -29,14 +31,15 @@ impl DocumentMatcher {
         one_or_wrap(matchers, Self::Any)
     }
 
-    pub fn compile(selector: &Document) -> Result<Self, Error> {
-        Self::compile_inner(selector, false, true)
+    pub fn compile(selector: &Document, collation: Option<&Collation>) -> Result<Self, Error> {
+        Self::compile_inner(selector, false, true, collation)
     }
 
     fn compile_inner(
         selector: &Document,
         is_in_elem_match: bool,
         is_root: bool,
+        collation: Option<&Collation>,
     ) -> Result<Self, Error> {
         Ok(Self::all(
             selector
@@ -44,13 +47,19 @@ impl DocumentMatcher {
                 .filter(|(key, _)| key.as_str() != "$comment") // Ignore it.
                 .map(|(key, sub_selector)| {
                     if key.starts_with('$') {
-                        Self::compile_logical_operator(key, sub_selector, is_in_elem_match)
+                        Self::compile_logical_operator(
+                            key,
+                            sub_selector,
+                            is_in_elem_match,
+                            collation,
+                        )
                     } else {
                         Ok(Self::Lookup {
                             lookup: Lookup::new(key.to_owned(), false),
                             matcher: BranchedMatcher::compile_value_selector(
                                 sub_selector,
                                 is_root,
+                                collation,
                             )?,
                         })
                     }
@@ -63,18 +72,24 @@ impl DocumentMatcher {
         operator: &str,
         selector: &Bson,
         is_in_elem_match: bool,
+        collation: Option<&Collation>,
     ) -> Result<Self, Error> {
         match operator {
-            "$and" => Self::compile_many(selector, is_in_elem_match).map(Self::all),
-            "$or" => Self::compile_many(selector, is_in_elem_match).map(Self::any),
-            "$nor" => Self::compile_many(selector, is_in_elem_match)
+            "$and" => Self::compile_many(selector, is_in_elem_match, collation).map(Self::all),
+            "$or" => Self::compile_many(selector, is_in_elem_match, collation).map(Self::any),
+            "$nor" => Self::compile_many(selector, is_in_elem_match, collation)
                 .map(Self::any)
                 .map(Self::invert),
+            "$text" => Err(anyhow!("$text can be evaluated only by the database")),
             operator => Err(anyhow!("{operator} is not supported")),
         }
     }
 
-    fn compile_many(selector: &Bson, is_in_elem_match: bool) -> Result<Vec<Self>, Error> {
+    fn compile_many(
+        selector: &Bson,
+        is_in_elem_match: bool,
+        collation: Option<&Collation>,
+    ) -> Result<Vec<Self>, Error> {
         let selectors = selector
             .as_array()
             .ok_or_else(|| anyhow!("Expected array of selectors, got {selector:?}"))?;
@@ -88,7 +103,7 @@ impl DocumentMatcher {
                 let document = selector
                     .as_document()
                     .ok_or_else(|| anyhow!("Expected document selector, got {selector:?}"))?;
-                Self::compile_inner(document, is_in_elem_match, false)
+                Self::compile_inner(document, is_in_elem_match, false, collation)
             })
             .collect()
     }
@@ -137,6 +152,7 @@ impl BranchedMatcher {
         operand: &Bson,
         selector: &Document,
         _is_root: bool,
+        collation: Option<&Collation>,
     ) -> Result<Self, Error> {
         match operator {
             "$all" => {
@@ -154,13 +170,14 @@ impl BranchedMatcher {
                             if is_operator_object(operand).is_some() {
                                 Err(anyhow!("$all expected plain document, got {operand:?}"))
                             } else {
-                                Ok(ElementMatcher::compile(operand)?.into_branched(false, false))
+                                Ok(ElementMatcher::compile(operand, collation)?
+                                    .into_branched(false, false))
                             }
                         })
                         .collect::<Result<_, _>>()?,
                 ))
             }
-            "$eq" => Ok(ElementMatcher::compile(operand)?.into_branched(false, false)),
+            "$eq" => Ok(ElementMatcher::compile(operand, collation)?.into_branched(false, false)),
             "$exists" => {
                 let matcher = ElementMatcher::Exists.into_branched(false, false);
                 Ok(match operand {
@@ -177,8 +194,14 @@ impl BranchedMatcher {
                     _ => unreachable!(),
                 };
 
+                let selector = into_ejson(operand.clone());
+                if let Some(collation) = collation {
+                    collation.check(&selector)?;
+                }
+
                 Ok(ElementMatcher::Order {
-                    selector: into_ejson(operand.clone()),
+                    collation: collation.copied(),
+                    selector,
                     ordering,
                     is_negated,
                 }
@@ -193,7 +216,8 @@ impl BranchedMatcher {
                         if is_operator_object(operand).is_some() {
                             Err(anyhow!("$in expected plain document, got {operand:?}"))
                         } else {
-                            Ok(ElementMatcher::compile(operand)?.into_branched(false, false))
+                            Ok(ElementMatcher::compile(operand, collation)?
+                                .into_branched(false, false))
                         }
                     })
                     .collect::<Result<_, _>>()?,
@@ -217,12 +241,17 @@ impl BranchedMatcher {
 
                 let div = parse(&operands[0])?;
                 let rem = parse(&operands[1])?;
+                if div == 0 {
+                    return Err(anyhow!("$mod divisor cannot be 0"));
+                }
 
                 Ok(ElementMatcher::Mod(div, rem).into_branched(false, false))
             }
-            "$ne" => Self::compile_operator("$eq", operand, selector, _is_root).map(Self::invert),
-            "$nin" => Self::compile_operator("$in", operand, selector, _is_root).map(Self::invert),
-            "$not" => Self::compile_value_selector(operand, false).map(Self::invert),
+            "$ne" => Self::compile_operator("$eq", operand, selector, _is_root, collation)
+                .map(Self::invert),
+            "$nin" => Self::compile_operator("$in", operand, selector, _is_root, collation)
+                .map(Self::invert),
+            "$not" => Self::compile_value_selector(operand, false, collation).map(Self::invert),
             "$regex" => {
                 let (pattern, options) = match operand {
                     Bson::RegularExpression(regex) => {
@@ -249,7 +278,7 @@ impl BranchedMatcher {
                     .to_owned();
 
                 let regex = BsonRegex { pattern, options };
-                Self::compile_value_selector(&Bson::RegularExpression(regex), false)
+                Self::compile_value_selector(&Bson::RegularExpression(regex), false, collation)
             }
             // TODO: This could be optimized out.
             "$options" if selector.contains_key("$regex") => Ok(Self::Never.invert()),
@@ -268,49 +297,81 @@ impl BranchedMatcher {
                 Ok(ElementMatcher::Size(size).into_branched(true, false))
             }
             "$type" => {
-                let type_ = match operand {
-                    Bson::Int32(operand) => match operand {
-                        1..=5 | 7..=11 => *operand as i8,
-                        operand => return Err(anyhow!("$type got an unknown number: {operand}")),
-                    },
-                    Bson::String(operand) => match operand.as_str() {
-                        "double" => 1,
-                        "string" => 2,
-                        "object" => 3,
-                        "array" => 4,
-                        "binData" => 5,
-                        "objectId" => 7,
-                        "bool" => 8,
-                        "date" => 9,
-                        "null" => 10,
-                        "regex" => 11,
-                        operand => return Err(anyhow!("$type got an unknown string: {operand}")),
-                    },
-                    operand => {
-                        return Err(anyhow!(
-                            "$type expected a number or string, got {operand:?}"
-                        ))
+                let parse = |value: &Bson| -> Result<Vec<i8>, Error> {
+                    let code = match value {
+                        Bson::Double(n) if n.fract() == 0.0 => *n as i64,
+                        Bson::Int32(n) => *n as i64,
+                        Bson::Int64(n) => *n,
+                        Bson::String(alias) => match alias.as_str() {
+                            "double" => 1,
+                            "string" => 2,
+                            "object" => 3,
+                            "array" => 4,
+                            "binData" => 5,
+                            "undefined" => 6,
+                            "objectId" => 7,
+                            "bool" => 8,
+                            "date" => 9,
+                            "null" => 10,
+                            "regex" => 11,
+                            "dbPointer" => 12,
+                            "javascript" => 13,
+                            "symbol" => 14,
+                            "javascriptWithScope" => 15,
+                            "int" => 16,
+                            "timestamp" => 17,
+                            "long" => 18,
+                            "decimal" => 19,
+                            "minKey" => -1,
+                            "maxKey" => 127,
+                            "number" => return Ok(vec![1, 16, 18, 19]),
+                            alias => return Err(anyhow!("$type got an unknown string: {alias}")),
+                        },
+                        value => {
+                            return Err(anyhow!("$type expected a number or string, got {value:?}"))
+                        }
+                    };
+
+                    match code {
+                        -1 | 1..=19 | 127 => Ok(vec![code as i8]),
+                        code => Err(anyhow!("$type got an unknown number: {code}")),
                     }
                 };
 
-                Ok(ElementMatcher::Type(type_).into_branched(false, true))
+                let types = match operand {
+                    Bson::Array(operands) if operands.is_empty() => {
+                        return Err(anyhow!("$type must match at least one type"))
+                    }
+                    Bson::Array(operands) => operands
+                        .iter()
+                        .map(parse)
+                        .collect::<Result<Vec<_>, _>>()?
+                        .concat(),
+                    operand => parse(operand)?,
+                };
+
+                Ok(ElementMatcher::Type(types).into_branched(false, true))
             }
             operator => Err(anyhow!("{operator} is not supported")),
         }
     }
 
-    fn compile_value_selector(selector: &Bson, is_root: bool) -> Result<Self, Error> {
+    fn compile_value_selector(
+        selector: &Bson,
+        is_root: bool,
+        collation: Option<&Collation>,
+    ) -> Result<Self, Error> {
         if let Some(selector) = is_operator_object(selector) {
             Ok(Self::all(
                 selector
                     .iter()
                     .map(|(operator, operand)| {
-                        Self::compile_operator(operator, operand, selector, is_root)
+                        Self::compile_operator(operator, operand, selector, is_root, collation)
                     })
                     .collect::<Result<_, _>>()?,
             ))
         } else {
-            Ok(ElementMatcher::compile(selector)?.into_branched(false, false))
+            Ok(ElementMatcher::compile(selector, collation)?.into_branched(false, false))
         }
     }
 
@@ -354,18 +415,19 @@ enum ElementMatcher {
     Exists,
     Mod(i64, i64),
     Order {
+        collation: Option<Collation>,
         selector: Value,
         ordering: Ordering,
         is_negated: bool,
     },
-    Regex(Regex, Value),
+    Regex(Pattern, Value),
     Size(usize),
-    Type(i8),
-    Value(Value),
+    Type(Vec<i8>),
+    Value(Value, Option<Collation>),
 }
 
 impl ElementMatcher {
-    fn compile(selector: &Bson) -> Result<Self, Error> {
+    fn compile(selector: &Bson, collation: Option<&Collation>) -> Result<Self, Error> {
         match selector {
             Bson::Array(_)
             | Bson::Binary(_)
@@ -378,7 +440,14 @@ impl ElementMatcher {
             | Bson::Int64(_)
             | Bson::Null
             | Bson::ObjectId(_)
-            | Bson::String(_) => Ok(Self::Value(into_ejson(selector.clone()))),
+            | Bson::String(_) => {
+                let selector = into_ejson(selector.clone());
+                if let Some(collation) = collation {
+                    collation.check(&selector)?;
+                }
+
+                Ok(Self::Value(selector, collation.copied()))
+            }
             Bson::DbPointer(_)
             | Bson::JavaScriptCode(_)
             | Bson::JavaScriptCodeWithScope(_)
@@ -388,18 +457,7 @@ impl ElementMatcher {
             | Bson::Timestamp(_)
             | Bson::Undefined => Err(anyhow!("Selector not supported: {selector:?}")),
             Bson::RegularExpression(regex) => {
-                let mut regex_builder = RegexBuilder::new(&regex.pattern);
-                for flag in regex.options.chars() {
-                    match flag {
-                        'i' => regex_builder.case_insensitive(true),
-                        'm' => regex_builder.multi_line(true),
-                        's' => regex_builder.dot_matches_new_line(true),
-                        'x' => regex_builder.ignore_whitespace(true),
-                        flag => return Err(anyhow!("Unknown $regex flag {flag}")),
-                    };
-                }
-
-                let regex = regex_builder.build()?;
+                let regex = Pattern::compile(&regex.pattern, &regex.options)?;
                 let ejson = into_ejson(selector.clone());
                 Ok(Self::Regex(regex, ejson))
             }
@@ -421,21 +479,23 @@ impl ElementMatcher {
     fn matches(&self, maybe_value: Option<&Value>) -> bool {
         match &self {
             Self::Exists => maybe_value.is_some(),
-            // TODO: Check how MongoDB handles $mod of floats.
+            // MongoDB truncates `double`s and `decimal`s towards zero.
             Self::Mod(div, rem) => maybe_value
-                .and_then(Value::as_i64)
-                .is_some_and(|number| number % div == *rem),
+                .and_then(Numeric::from_value)
+                .and_then(|numeric| numeric.as_i64_truncated())
+                .is_some_and(|number| number.wrapping_rem(*div) == *rem),
             Self::Order {
                 selector: Value::Array(_),
                 ..
             } => false,
             Self::Order {
+                collation,
                 selector,
                 ordering,
                 is_negated,
             } => {
                 let value = maybe_value.unwrap_or(&Value::Null);
-                let result = Sorter::cmp_value_partial(value, selector);
+                let result = Sorter::cmp_value_partial(value, selector, collation.as_ref());
                 result.is_ok_and(|result| result == *ordering) != *is_negated
             }
             Self::Regex(regex, ejson) => maybe_value.is_some_and(|value| match value {
@@ -446,13 +506,13 @@ impl ElementMatcher {
             Self::Size(size) => maybe_value
                 .and_then(Value::as_array)
                 .is_some_and(|array| array.len() == *size),
-            Self::Type(type_) => maybe_value
+            Self::Type(types) => maybe_value
                 .map(Sorter::value_type)
-                .is_some_and(|value_type| value_type == *type_),
-            Self::Value(Value::Null) => maybe_value.map_or(true, Value::is_null),
-            Self::Value(selector) => {
-                maybe_value.is_some_and(|value| Sorter::cmp_value(selector, value).is_eq())
-            }
+                .is_some_and(|value_type| types.contains(&value_type)),
+            Self::Value(Value::Null, _) => matches!(maybe_value, None | Some(Value::Null)),
+            Self::Value(selector, collation) => maybe_value.is_some_and(|value| {
+                Sorter::cmp_value(selector, value, collation.as_ref()).is_eq()
+            }),
         }
     }
 }
@@ -476,9 +536,10 @@ fn one_or_wrap<T>(mut list: Vec<T>, wrap: impl Fn(Vec<T>) -> T) -> T {
 #[cfg(test)]
 mod tests {
     use super::DocumentMatcher;
+    use crate::collation::Collation;
     use crate::ejson::into_ejson_document;
     use bson::oid::ObjectId;
-    use bson::{doc, Binary, DateTime, Regex};
+    use bson::{doc, Binary, Bson, DateTime, Decimal128, Regex, Timestamp};
 
     macro_rules! regex {
         ($pattern:expr) => {
@@ -492,14 +553,20 @@ mod tests {
         };
     }
 
+    fn decimal(string: &str) -> Decimal128 {
+        string.parse().unwrap()
+    }
+
     macro_rules! test {
-        ($name:ident, { $($selector:tt)* }, { $($document:tt)* }, $expected:expr) => {
+        ($name:ident, { $($collation:tt)* }, { $($selector:tt)* }, { $($document:tt)* }, $expected:expr) => {
             #[test]
             fn $name() {
+                let collation = doc! { $($collation)* };
+                let collation = Collation::compile(Some(&collation).filter(|x| !x.is_empty())).unwrap();
                 let selector = &doc! { $($selector)* };
                 let document = &into_ejson_document(doc! { $($document)* });
 
-                let matcher = match DocumentMatcher::compile(selector) {
+                let matcher = match DocumentMatcher::compile(selector, collation.as_ref()) {
                     Ok(matcher) => matcher,
                     Err(error) => panic!("{selector:?} is not supported: {error:?}"),
                 };
@@ -512,21 +579,35 @@ mod tests {
                 );
             }
         };
+        ($name:ident, { $($selector:tt)* }, { $($document:tt)* }, $expected:expr) => {
+            test!($name, {}, { $($selector)* }, { $($document)* }, $expected);
+        };
     }
 
-    macro_rules! y {($name:ident, { $($selector:tt)* }, { $($document:tt)* }) => {test!($name, { $($selector)* }, { $($document)* }, true);}}
-    macro_rules! n {($name:ident, { $($selector:tt)* }, { $($document:tt)* }) => {test!($name, { $($selector)* }, { $($document)* }, false);}}
+    macro_rules! y {
+        ($name:ident, { $($selector:tt)* }, { $($document:tt)* }) => {test!($name, { $($selector)* }, { $($document)* }, true);};
+        ($name:ident, { $($collation:tt)* }, { $($selector:tt)* }, { $($document:tt)* }) => {test!($name, { $($collation)* }, { $($selector)* }, { $($document)* }, true);};
+    }
+    macro_rules! n {
+        ($name:ident, { $($selector:tt)* }, { $($document:tt)* }) => {test!($name, { $($selector)* }, { $($document)* }, false);};
+        ($name:ident, { $($collation:tt)* }, { $($selector:tt)* }, { $($document:tt)* }) => {test!($name, { $($collation)* }, { $($selector)* }, { $($document)* }, false);};
+    }
 
     macro_rules! f {
-        ($name:ident, { $($selector:tt)* }) => {
+        ($name:ident, { $($collation:tt)* }, { $($selector:tt)* }) => {
             #[test]
             fn $name() {
+                let collation = doc! { $($collation)* };
+                let collation = Collation::compile(Some(&collation).filter(|x| !x.is_empty())).unwrap();
                 let selector = &doc! { $($selector)* };
-                if let Ok(matcher) = DocumentMatcher::compile(selector) {
+                if let Ok(matcher) = DocumentMatcher::compile(selector, collation.as_ref()) {
                     panic!("{selector:?} should not be supported, got {matcher:?}");
                 }
             }
         };
+        ($name:ident, { $($selector:tt)* }) => {
+            f!($name, {}, { $($selector)* });
+        };
     }
 
     // Empty selector.
@@ -562,6 +643,14 @@ mod tests {
     y!(number_12, {"a": 1}, {"a": [1, "bar"]});
     y!(number_13, {"a": 1}, {"a": ["bar", 1]});
     y!(number_14, {"a": 1}, {"a": [1, 1]});
+    y!(number_15, {"a": 1}, {"a": 1_i64});
+    y!(number_16, {"a": 1}, {"a": 1.0});
+    y!(number_17, {"a": 1}, {"a": decimal("1.00")});
+    y!(number_18, {"a": 1_i64}, {"a": 1});
+    n!(number_19, {"a": 9_007_199_254_740_993_i64}, {"a": 9_007_199_254_740_992_i64});
+    n!(number_20, {"a": 9_007_199_254_740_993_i64}, {"a": 9_007_199_254_740_992.0});
+    y!(number_21, {"a": f64::NAN}, {"a": decimal("NaN")});
+    y!(number_22, {"a": {"b": 1}}, {"a": {"b": 1_i64}});
 
     // String.
     n!(string_01, {"a": "foo"}, {});
@@ -652,6 +741,44 @@ mod tests {
     n!(regex_34, {"a": regex!("5")}, {"a": 5});
     n!(regex_35, {"a": regex!("t")}, {"a": true});
     y!(regex_36, {"a": regex!("m", "i")}, {"a": ["x", "xM"]});
+    f!(regex_37, {"a": regex!("a", "g")});
+
+    // Regex dialect (PCRE).
+    y!(regex_dialect_01, {"a": regex!(r"^\d+$")}, {"a": "123"});
+    n!(regex_dialect_02, {"a": regex!(r"^\d+$")}, {"a": "١٢٣"});
+    y!(regex_dialect_03, {"a": regex!(r"^\D+$")}, {"a": "١٢٣"});
+    n!(regex_dialect_04, {"a": regex!(r"^\w+$")}, {"a": "żółw"});
+    y!(regex_dialect_05, {"a": regex!(r"^[\w.]+$")}, {"a": "a.b_c"});
+    n!(regex_dialect_06, {"a": regex!(r"^[\w.]+$")}, {"a": "a.b-c"});
+    y!(regex_dialect_07, {"a": regex!(r"^\s$")}, {"a": "\u{b}"});
+    n!(regex_dialect_08, {"a": regex!(r"^\s$")}, {"a": "\u{a0}"});
+    y!(regex_dialect_09, {"a": regex!(r"\bcat\b")}, {"a": "a cat"});
+    n!(regex_dialect_10, {"a": regex!(r"\bcat\b")}, {"a": "a cats"});
+    y!(regex_dialect_11, {"a": regex!(r"^[]a]+$")}, {"a": "]a]"});
+    y!(regex_dialect_12, {"a": regex!(r"^[[:alpha:]\d]+$")}, {"a": "a1"});
+    y!(regex_dialect_13, {"a": regex!(r"^\\d$")}, {"a": "\\d"});
+    #[cfg(feature = "pcre")]
+    y!(regex_dialect_14, {"a": regex!(r"^(?!admin).*$")}, {"a": "user"});
+    #[cfg(feature = "pcre")]
+    n!(regex_dialect_15, {"a": regex!(r"^(?!admin).*$")}, {"a": "administrator"});
+    #[cfg(feature = "pcre")]
+    y!(regex_dialect_16, {"a": regex!(r"(\w)\1")}, {"a": "hello"});
+    #[cfg(feature = "pcre")]
+    n!(regex_dialect_17, {"a": regex!(r"(\w)\1")}, {"a": "helo"});
+    #[cfg(feature = "pcre")]
+    y!(regex_dialect_18, {"a": regex!(r"(?<=\$)\d+", "i")}, {"a": "$12"});
+    #[cfg(feature = "pcre")]
+    n!(regex_dialect_19, {"a": regex!(r"(?<=\$)\d+", "i")}, {"a": "12"});
+    #[cfg(feature = "pcre")]
+    y!(regex_dialect_20, {"a": regex!(r"^(A)(?=.*\1)", "i")}, {"a": "abca"});
+    #[cfg(not(feature = "pcre"))]
+    f!(regex_dialect_21, {"a": regex!(r"^(?!admin).*$")});
+    #[cfg(not(feature = "pcre"))]
+    f!(regex_dialect_22, {"a": regex!(r"(\w)\1")});
+    n!(regex_dialect_23, {"a": regex!(r"^\d+$", "u")}, {"a": "١٢٣"});
+    n!(regex_dialect_24, {"a": regex!(r"^\w+$", "iu")}, {"a": "ŻÓŁW"});
+    n!(regex_dialect_25, {"a": regex!(r"^\s$", "u")}, {"a": "x"});
+    y!(regex_dialect_26, {"a": regex!(r"^\w+$", "iu")}, {"a": "ZOLW"});
 
     // Nested paths.
     y!(nested_01, {"a.b": 1}, {"a": {"b": 1}});
@@ -707,6 +834,22 @@ mod tests {
     y!(nested_51, {"a.b": regex!("a")}, {"a": {"b": "cat"}});
     n!(nested_52, {"a.b": regex!("a")}, {"a": {"b": "dog"}});
 
+    // Collation.
+    y!(collation_01, {"locale": "en", "strength": 1}, {"a": "foo"}, {"a": "FOO"});
+    y!(collation_02, {"locale": "en", "strength": 1}, {"a": "resume"}, {"a": "Résumé"});
+    n!(collation_03, {"locale": "en", "strength": 2}, {"a": "resume"}, {"a": "Résumé"});
+    y!(collation_04, {"locale": "en", "strength": 2}, {"a": "résumé"}, {"a": "RÉSUMÉ"});
+    y!(collation_05, {"locale": "en", "strength": 1}, {"a": {"$in": ["x", "foo"]}}, {"a": ["FOO"]});
+    y!(collation_06, {"locale": "en", "strength": 1}, {"a": {"$gt": "b"}}, {"a": "C"});
+    n!(collation_07, {"locale": "en", "strength": 1}, {"a": {"$gt": "b"}}, {"a": "B"});
+    y!(collation_08, {"locale": "en", "strength": 1}, {"a": {"b": "x"}}, {"a": {"b": "X"}});
+    n!(collation_09, {"locale": "en", "strength": 1}, {"a": {"$ne": "x"}}, {"a": "X"});
+    n!(collation_10, {"locale": "en", "strength": 1}, {"a": regex!("^x$")}, {"a": "X"});
+    n!(collation_11, {"locale": "simple"}, {"a": "foo"}, {"a": "FOO"});
+    f!(collation_13, {"locale": "en", "strength": 1}, {"a": "Øyvind"});
+    f!(collation_14, {"locale": "en", "strength": 1}, {"a": {"$lt": ["æ"]}});
+    y!(collation_12, {"locale": "en", "strength": 1, "numericOrdering": true}, {"a": {"$lt": "10"}}, {"a": "9"});
+
     // $all.
     y!(operator_all_01, {"a": {"$all": [1, 2]}}, {"a": [1, 2]});
     n!(operator_all_02, {"a": {"$all": [1, 2, 3]}}, {"a": [1, 2]});
@@ -834,6 +977,9 @@ mod tests {
     n!(operator_lt_6, {"a": {"$lt": "null"}}, {"a": null});
     y!(operator_lt_7, {"a": {"$lt": {"x": [2, 3, 4]}}}, {"a": {"x": [1, 3, 4]}});
     n!(operator_lt_8, {"a": {"$lt": {"x": [2, 3, 4]}}}, {"a": {"x": [2, 3, 4]}});
+    y!(operator_lt_9, {"a": {"$lt": 9_007_199_254_740_993_i64}}, {"a": 9_007_199_254_740_992_i64});
+    y!(operator_lt_10, {"a": {"$lt": decimal("0.1")}}, {"a": decimal("0.09")});
+    n!(operator_lt_11, {"a": {"$lt": decimal("0.1")}}, {"a": 0.1});
 
     // $lte.
     y!(operator_lte_1, {"a": {"$lte": 10}}, {"a": 9});
@@ -852,6 +998,12 @@ mod tests {
     f!(operator_mod_08, {"a": {"$mod": "foo"}});
     f!(operator_mod_09, {"a": {"$mod": {"bar": 1}}});
     f!(operator_mod_10, {"a": {"$mod": []}});
+    f!(operator_mod_11, {"a": {"$mod": [0, 1]}});
+    y!(operator_mod_12, {"a": {"$mod": [10, 1]}}, {"a": 11_i64});
+    y!(operator_mod_13, {"a": {"$mod": [10, 1]}}, {"a": 11.5});
+    y!(operator_mod_14, {"a": {"$mod": [10, -1]}}, {"a": -11});
+    y!(operator_mod_15, {"a": {"$mod": [10, 1]}}, {"a": decimal("11.9")});
+    n!(operator_mod_16, {"a": {"$mod": [10, 1]}}, {"a": f64::NAN});
 
     // $ne.
     y!(operator_ne_01, {"a": {"$ne": 1}}, {"a": 2});
@@ -901,6 +1053,9 @@ mod tests {
     y!(operator_nor_4, {"$nor": [{"a": regex!("i")}, {"a": regex!("o")}]}, {"a": "cat"});
     n!(operator_nor_5, {"$nor": [{"a": regex!("i")}, {"b": regex!("o")}]}, {"a": "cat", "b": "dog"});
 
+    f!(operator_text_1, {"$text": {"$search": "cat"}});
+    f!(operator_text_2, {"$and": [{"$text": {"$search": "cat"}}, {"a": 1}]});
+
     // $not.
     y!(operator_not_01, {"x": {"$not": {"$gt": 7}}}, {"x": 6});
     n!(operator_not_02, {"x": {"$not": {"$gt": 7}}}, {"x": 8});
@@ -957,7 +1112,7 @@ mod tests {
     // $type.
     y!(operator_type_1, {"a": {"$type": 1}}, {"a": 1.1});
     y!(operator_type_2, {"a": {"$type": "double"}}, {"a": 1.1});
-    y!(operator_type_3, {"a": {"$type": 1}}, {"a": 1});
+    n!(operator_type_3, {"a": {"$type": 1}}, {"a": 1});
     n!(operator_type_4, {"a": {"$type": 1}}, {"a": "1"});
     y!(operator_type_5, {"a": {"$type": 2}}, {"a": "1"});
     y!(operator_type_6, {"a": {"$type": "string"}}, {"a": "1"});
@@ -999,9 +1154,9 @@ mod tests {
     n!(operator_type_42, {"a": {"$type": 11}}, {});
     n!(operator_type_43, {"a": {"$type": 4}}, {"a": []});
     n!(operator_type_44, {"a": {"$type": 4}}, {"a": [1]});
-    y!(operator_type_45, {"a": {"$type": 1}}, {"a": [1]});
+    y!(operator_type_45, {"a": {"$type": 1}}, {"a": [1.5]});
     n!(operator_type_46, {"a": {"$type": 2}}, {"a": [1]});
-    y!(operator_type_47, {"a": {"$type": 1}}, {"a": ["1", 1]});
+    y!(operator_type_47, {"a": {"$type": 1}}, {"a": ["1", 1.5]});
     y!(operator_type_48, {"a": {"$type": 2}}, {"a": ["1", 1]});
     n!(operator_type_49, {"a": {"$type": 3}}, {"a": ["1", 1]});
     n!(operator_type_50, {"a": {"$type": 4}}, {"a": ["1", 1]});
@@ -1015,6 +1170,43 @@ mod tests {
     f!(operator_type_58, {"a": {"$type": -2}});
     f!(operator_type_59, {"a": {"$type": 0}});
     f!(operator_type_60, {"a": {"$type": 20}});
+    y!(operator_type_61, {"a": {"$type": 16}}, {"a": 1});
+    y!(operator_type_62, {"a": {"$type": "int"}}, {"a": 1});
+    n!(operator_type_63, {"a": {"$type": "int"}}, {"a": 1_i64});
+    y!(operator_type_64, {"a": {"$type": 18}}, {"a": 1_i64});
+    y!(operator_type_65, {"a": {"$type": "long"}}, {"a": 1_i64});
+    n!(operator_type_66, {"a": {"$type": "long"}}, {"a": 1.0});
+    y!(operator_type_67, {"a": {"$type": 19}}, {"a": decimal("1.5")});
+    y!(operator_type_68, {"a": {"$type": "decimal"}}, {"a": decimal("1.5")});
+    n!(operator_type_69, {"a": {"$type": "decimal"}}, {"a": 1.5});
+    y!(operator_type_70, {"a": {"$type": "number"}}, {"a": 1});
+    y!(operator_type_71, {"a": {"$type": "number"}}, {"a": 1_i64});
+    y!(operator_type_72, {"a": {"$type": "number"}}, {"a": 1.5});
+    y!(operator_type_73, {"a": {"$type": "number"}}, {"a": decimal("1.5")});
+    n!(operator_type_74, {"a": {"$type": "number"}}, {"a": "1"});
+    y!(operator_type_75, {"a": {"$type": "double"}}, {"a": f64::NAN});
+    y!(operator_type_76, {"a": {"$type": "double"}}, {"a": f64::INFINITY});
+    y!(operator_type_77, {"a": {"$type": ["string", "int"]}}, {"a": 1});
+    y!(operator_type_78, {"a": {"$type": [2, "number"]}}, {"a": 1.5});
+    n!(operator_type_79, {"a": {"$type": ["string", "bool"]}}, {"a": 1});
+    y!(operator_type_80, {"a": {"$type": 2.0}}, {"a": "1"});
+    y!(operator_type_81, {"a": {"$type": 2_i64}}, {"a": "1"});
+    y!(operator_type_82, {"a": {"$type": "timestamp"}}, {"a": Timestamp { time: 1, increment: 2 }});
+    y!(operator_type_83, {"a": {"$type": 17}}, {"a": Timestamp { time: 1, increment: 2 }});
+    n!(operator_type_84, {"a": {"$type": "date"}}, {"a": Timestamp { time: 1, increment: 2 }});
+    y!(operator_type_85, {"a": {"$type": "minKey"}}, {"a": Bson::MinKey});
+    y!(operator_type_86, {"a": {"$type": -1}}, {"a": Bson::MinKey});
+    y!(operator_type_87, {"a": {"$type": "maxKey"}}, {"a": Bson::MaxKey});
+    y!(operator_type_88, {"a": {"$type": 127}}, {"a": Bson::MaxKey});
+    n!(operator_type_89, {"a": {"$type": "minKey"}}, {"a": Bson::MaxKey});
+    y!(operator_type_90, {"a": {"$type": "javascript"}}, {"a": Bson::JavaScriptCode("x".to_owned())});
+    n!(operator_type_91, {"a": {"$type": "string"}}, {"a": Bson::JavaScriptCode("x".to_owned())});
+    y!(operator_type_92, {"a": {"$type": "symbol"}}, {"a": Bson::Symbol("x".to_owned())});
+    y!(operator_type_93, {"a": {"$type": ["minKey", "maxKey"]}}, {"a": [1, Bson::MaxKey]});
+    f!(operator_type_94, {"a": {"$type": []}});
+    f!(operator_type_95, {"a": {"$type": [2, "foo"]}});
+    f!(operator_type_96, {"a": {"$type": 2.5}});
+    f!(operator_type_97, {"a": {"$type": [[2]]}});
 
     // $and + $in.
     n!(operators_and_in_1, {"$and": [{"a": {"$in": []}}]}, {});
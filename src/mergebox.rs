@@ -1,183 +1,861 @@
 use crate::ddp::DDPMessage;
+use crate::ejson::into_ddp;
+use crate::flatten::{is_flattened, size_of_json};
+use crate::outbox::Outbox;
+use crate::settings::{Flatten, Inconsistencies, SlowClients};
 use anyhow::{anyhow, Context, Error};
 use serde_json::{Map, Value};
 use std::collections::btree_map::Entry;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::iter::once;
+use std::mem::{replace, take};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 
 type Document = Map<String, Value>;
+type Fields = BTreeMap<Arc<str>, Arc<Value>>;
+/// `_id` of the next document in an ordered collection (`None` is the end).
+type Before = Option<String>;
+/// Order of documents published with `addedBefore` and `movedBefore`.
+type Orders = BTreeMap<String, Order>;
+/// Fields of all documents, by collection and `_id`.
+type View = BTreeMap<String, HashMap<Id, Fields>>;
 
+/// Fields of a document (in DDP format), shared by the `CursorFetcher` cache
+/// and all mergeboxes. Cloning it and its values is cheap, so every session
+/// keeps only references and counters instead of its own copy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SharedDocument(Arc<[(Arc<str>, Arc<Value>)]>);
+
+impl SharedDocument {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Arc<str>, &Arc<Value>)> {
+        self.0.iter().map(|(field, value)| (field, value))
+    }
+
+    pub fn to_document(&self) -> Document {
+        self.iter()
+            .map(|(field, value)| (field.to_string(), Value::clone(value)))
+            .collect()
+    }
+}
+
+impl From<Document> for SharedDocument {
+    fn from(document: Document) -> Self {
+        document
+            .into_iter()
+            .map(|(field, value)| (Arc::from(field), Arc::new(value)))
+            .collect()
+    }
+}
+
+impl FromIterator<(Arc<str>, Arc<Value>)> for SharedDocument {
+    fn from_iter<I: IntoIterator<Item = (Arc<str>, Arc<Value>)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+/// Mergeboxes of all sessions using the cursor, with the subscriptions using it.
 #[derive(Default)]
-pub struct Mergeboxes(BTreeMap<usize, (usize, Arc<Mutex<Mergebox>>)>);
+pub struct Mergeboxes(BTreeMap<usize, (Vec<Owner>, Arc<Mutex<Mergebox>>)>);
 
 impl Mergeboxes {
     pub async fn insert(
         &mut self,
         collection: String,
-        id: Value,
-        document: Document,
+        mut id: Value,
+        document: &SharedDocument,
     ) -> Result<(), Error> {
-        for (_, mergebox) in self.0.values_mut() {
-            mergebox
-                .lock()
-                .await
-                .insert(collection.clone(), id.clone(), document.clone())
-                .await
-                .context("Mergeboxes::insert")?;
+        into_ddp(&mut id);
+        for (owners, mergebox) in self.0.values_mut() {
+            let mut mergebox = mergebox.lock().await;
+            for owner in owners.iter() {
+                mergebox
+                    .insert(collection.clone(), id.clone(), owner, document)
+                    .await
+                    .context("Mergeboxes::insert")?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn insert_mergebox(&mut self, session_id: usize, mergebox: &Arc<Mutex<Mergebox>>) -> bool {
+    pub fn insert_mergebox(
+        &mut self,
+        session_id: usize,
+        owner: Owner,
+        mergebox: &Arc<Mutex<Mergebox>>,
+    ) -> bool {
         let is_first = self.0.is_empty();
         self.0
             .entry(session_id)
-            .and_modify(|(counter, _)| *counter += 1)
-            .or_insert_with(|| (0, mergebox.clone()));
+            .or_insert_with(|| (Vec::default(), mergebox.clone()))
+            .0
+            .push(owner);
         is_first
     }
 
     pub async fn remove(
         &mut self,
         collection: String,
-        id: Value,
-        document: &Document,
+        mut id: Value,
+        document: &SharedDocument,
     ) -> Result<(), Error> {
-        for (_, mergebox) in self.0.values_mut() {
-            mergebox
-                .lock()
-                .await
-                .remove(collection.clone(), id.clone(), document)
-                .await
-                .context("Mergeboxes::remove")?;
+        into_ddp(&mut id);
+        for (owners, mergebox) in self.0.values_mut() {
+            let mut mergebox = mergebox.lock().await;
+            for owner in owners.iter() {
+                mergebox
+                    .remove(collection.clone(), id.clone(), owner, document)
+                    .await
+                    .context("Mergeboxes::remove")?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn remove_mergebox(&mut self, session_id: usize) -> bool {
+    /// How many times the owner uses the cursor in the session (if at all).
+    pub fn count(&self, session_id: usize, owner: &Owner) -> usize {
+        self.0.get(&session_id).map_or(0, |(owners, _)| {
+            owners.iter().filter(|x| *x == owner).count()
+        })
+    }
+
+    pub fn remove_mergebox(&mut self, session_id: usize, owner: &Owner) -> bool {
         if let Entry::Occupied(mut entry) = self.0.entry(session_id) {
-            if entry.get().0 == 0 {
+            let owners = &mut entry.get_mut().0;
+            if let Some(index) = owners.iter().position(|x| x == owner) {
+                owners.remove(index);
+            }
+            if entry.get().0.is_empty() {
                 entry.remove();
                 return self.0.is_empty();
             }
-
-            entry.get_mut().0 -= 1;
         }
 
         false
     }
 }
 
+/// Who published a document (or a field). Server publications take precedence
+/// over the subscriptions managed by DDP Router, as they may publish additional
+/// data on top of the cursors. Otherwise, the first one wins (like in Meteor).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Owner {
+    Server,
+    Subscription(Arc<str>),
+}
+
+impl Owner {
+    fn precedes(&self, other: &Self) -> bool {
+        matches!((self, other), (Self::Server, Self::Subscription(_)))
+    }
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Server => f.write_str("server"),
+            Self::Subscription(id) => write!(f, "subscription {id}"),
+        }
+    }
+}
+
+/// Kind of an inconsistency repaired by a tolerant `Mergebox`.
+#[derive(Clone, Copy, Debug)]
+pub enum Inconsistency {
+    /// Server sent `added` for an already added document (treated as `changed`).
+    AddedTwice,
+    /// Server sent `changed` for an unknown document (treated as `added`).
+    ChangedUnknown,
+    /// A resync found a document that differs from the expected one (fixed
+    /// in both modes).
+    Drifted,
+    /// A cursor removed an unknown document (ignored).
+    MissingDocument,
+    /// A cursor removed an unknown field (ignored).
+    MissingField,
+    /// Server sent `movedBefore` for an unknown document (ignored).
+    MovedUnknown,
+    /// Server sent `addedBefore` or `movedBefore` before an unknown document
+    /// (treated as the end).
+    OrderUnknown,
+    /// Server sent `removed` for an unknown document (ignored).
+    RemovedUnknown,
+    /// A stopped subscription still owned documents (removed).
+    Unreleased,
+}
+
+/// Repaired inconsistencies of all sessions, indexed by `Inconsistency`.
+static REPAIRED: [AtomicUsize; 9] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+impl Inconsistency {
+    pub const ALL: [Self; 9] = [
+        Self::AddedTwice,
+        Self::ChangedUnknown,
+        Self::Drifted,
+        Self::MissingDocument,
+        Self::MissingField,
+        Self::MovedUnknown,
+        Self::OrderUnknown,
+        Self::RemovedUnknown,
+        Self::Unreleased,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::AddedTwice => "added_twice",
+            Self::ChangedUnknown => "changed_unknown",
+            Self::Drifted => "drifted",
+            Self::MissingDocument => "missing_document",
+            Self::MissingField => "missing_field",
+            Self::MovedUnknown => "moved_unknown",
+            Self::OrderUnknown => "order_unknown",
+            Self::RemovedUnknown => "removed_unknown",
+            Self::Unreleased => "unreleased",
+        }
+    }
+
+    pub fn repaired(self) -> usize {
+        REPAIRED[self as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Fails in the strict mode. Otherwise, logs and counts the inconsistency, so
+/// the caller can repair it.
+fn repair(
+    inconsistencies: Inconsistencies,
+    inconsistency: Inconsistency,
+    error: Error,
+) -> Result<(), Error> {
+    match inconsistencies {
+        Inconsistencies::Strict => Err(error),
+        Inconsistencies::Tolerant => {
+            REPAIRED[inconsistency as usize].fetch_add(1, Ordering::Relaxed);
+            println!("\x1b[0;33mrouter\x1b[0m \x1b[0;31mRepairing {error:#}\x1b[0m");
+            Ok(())
+        }
+    }
+}
+
+/// `_id` of a document. Unlike `Value`, it is hashable, making all lookups O(1).
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Id(Value);
+
+impl Hash for Id {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state);
+    }
+}
+
+/// Consistent with `Value::eq`, i.e., numbers of different representations are
+/// different and the order of keys in objects does not matter.
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    match value {
+        Value::Array(values) => {
+            state.write_u8(0);
+            state.write_usize(values.len());
+            for value in values {
+                hash_value(value, state);
+            }
+        }
+        Value::Bool(value) => {
+            state.write_u8(1);
+            value.hash(state);
+        }
+        Value::Null => state.write_u8(2),
+        Value::Number(number) => {
+            state.write_u8(3);
+            if let Some(number) = number.as_u64() {
+                state.write_u8(0);
+                number.hash(state);
+            } else if let Some(number) = number.as_i64() {
+                state.write_u8(1);
+                number.hash(state);
+            } else if let Some(number) = number.as_f64() {
+                // Both zeros are equal.
+                state.write_u8(2);
+                (if number == 0.0 { 0.0 } else { number })
+                    .to_bits()
+                    .hash(state);
+            }
+        }
+        Value::Object(object) => {
+            state.write_u8(4);
+            state.write_usize(object.len());
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_unstable_by_key(|(key, _)| *key);
+            for (key, value) in entries {
+                key.hash(state);
+                hash_value(value, state);
+            }
+        }
+        Value::String(value) => {
+            state.write_u8(5);
+            value.hash(state);
+        }
+    }
+}
+
 pub struct Mergebox {
-    collections: BTreeMap<String, Vec<MergeboxDocument>>,
-    server_view: BTreeMap<String, Vec<(Value, Document)>>,
-    messages_sink: Sender<DDPMessage>,
+    /// What the client will see once it receives all messages sent before it
+    /// fell behind (see `SlowClients`). No messages are sent in the meantime.
+    client_view: Option<View>,
+    /// Order of `client_view`.
+    client_order: Orders,
+    collections: BTreeMap<String, HashMap<Id, MergeboxDocument>>,
+    flatten: Arc<Flatten>,
+    /// Non-data messages (e.g., `ready`) held back while the client is stale,
+    /// as they may depend on the data.
+    held: Vec<DDPMessage>,
+    inconsistencies: Inconsistencies,
+    /// Documents removed since the last `flush` with their last sent fields
+    /// (and the order of removal). If one is added back before that, a single
+    /// `changed` is sent instead of `removed` and `added`.
+    removed: BTreeMap<String, HashMap<Id, (usize, Fields)>>,
+    removed_counter: usize,
+    /// Notified when the first removal since the last `flush` is scheduled.
+    removals: Arc<Notify>,
+    server_order: Orders,
+    server_view: BTreeMap<String, HashMap<Id, SharedDocument>>,
+    messages_sink: Outbox,
 }
 
 impl Mergebox {
+    /// Sends all differences between what the client has and the current state
+    /// after a slow client caught up. Returns whether it did.
+    pub fn catch_up(&mut self) -> Result<bool, Error> {
+        if self.messages_sink.pending() > 0 {
+            return Ok(false);
+        }
+
+        let Some(client_view) = self.client_view.take() else {
+            return Ok(false);
+        };
+
+        let client_order = take(&mut self.client_order);
+        let view = self.view();
+        for message in compare(&client_view, &view, &client_order, &self.server_order) {
+            self.send(message)?;
+        }
+
+        for message in take(&mut self.held) {
+            self.send(message)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Stops sending messages if the client fell behind. Called before every
+    /// change, as then the client view is known (i.e., all messages are sent).
+    fn check_overflow(&mut self) {
+        if self.client_view.is_some() {
+            return;
+        }
+
+        match self.messages_sink.is_overflowing() {
+            None => return,
+            // The session is closed anyway, so there's no need for a snapshot.
+            Some(SlowClients::Disconnect) => {}
+            Some(SlowClients::Resync) => {
+                self.client_view = Some(self.view());
+                self.client_order.clone_from(&self.server_order);
+            }
+        }
+
+        self.messages_sink.notify();
+    }
+
+    /// Sends `removed` for all documents that were not added back since their
+    /// removal.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.check_overflow();
+        let mut removed: Vec<_> = take(&mut self.removed)
+            .into_iter()
+            .flat_map(|(collection, documents)| {
+                documents
+                    .into_iter()
+                    .map(move |(id, (order, _))| (order, collection.clone(), id))
+            })
+            .collect();
+        removed.sort_unstable_by_key(|(order, _, _)| *order);
+
+        for (_, collection, id) in removed {
+            self.send(DDPMessage::Removed {
+                collection,
+                id: id.0,
+            })?;
+        }
+
+        Ok(())
+    }
+
     pub async fn insert(
         &mut self,
         collection: String,
         id: Value,
-        document: Document,
+        owner: &Owner,
+        document: &SharedDocument,
+    ) -> Result<(), Error> {
+        self.insert_ordered(collection, id, owner, document, None)
+            .await
+    }
+
+    /// Like `insert`, but for ordered collections it also sends the position
+    /// (`addedBefore` or `movedBefore`).
+    async fn insert_ordered(
+        &mut self,
+        collection: String,
+        id: Value,
+        owner: &Owner,
+        document: &SharedDocument,
+        before: Option<Before>,
     ) -> Result<(), Error> {
+        self.check_overflow();
         let mergebox_collection = self.collections.entry(collection.clone()).or_default();
-        let maybe_mergebox_index = mergebox_collection.iter().position(|x| x.id == id);
-        if let Some(mergebox_index) = maybe_mergebox_index {
-            let fields = mergebox_collection[mergebox_index].change(document);
-            if !fields.is_empty() {
-                self.messages_sink
-                    .send(DDPMessage::Changed {
-                        collection,
-                        id,
-                        fields: Some(fields),
-                        cleared: None,
-                    })
-                    .await?;
-            }
+        let id = Id(id);
+        if let Some(mergebox_document) = mergebox_collection.get_mut(&id) {
+            let fields = mergebox_document.change(owner, document);
+            self.send_changed(&collection, &id, fields, Vec::default())?;
+        } else if let Some((_, fields_before)) = self
+            .removed
+            .get_mut(&collection)
+            .and_then(|documents| documents.remove(&id))
+        {
+            let mergebox_document = MergeboxDocument::new(owner, document);
+            mergebox_collection.insert(id.clone(), mergebox_document);
+            let (fields, cleared) = diff(&fields_before, document);
+            self.send_changed(&collection, &id, fields, cleared)?;
         } else {
-            mergebox_collection.push(MergeboxDocument::new(id.clone(), document.clone()));
-            self.messages_sink
-                .send(DDPMessage::Added {
+            let mergebox_document = MergeboxDocument::new(owner, document);
+            mergebox_collection.insert(id.clone(), mergebox_document);
+            let fields = if document.is_empty() {
+                None
+            } else {
+                Some(document.to_document())
+            };
+            let message = match before {
+                Some(before) => DDPMessage::AddedBefore {
                     collection,
-                    id,
-                    fields: if document.is_empty() {
-                        None
-                    } else {
-                        Some(document)
-                    },
+                    id: id.0,
+                    fields,
+                    before,
+                },
+                None => DDPMessage::Added {
+                    collection,
+                    id: id.0,
+                    fields,
                     cleared: None,
-                })
-                .await?;
+                },
+            };
+            self.send(message)?;
+            return Ok(());
+        }
+
+        // The client already has it, so only its position may change.
+        if let Some(before) = before {
+            self.send(DDPMessage::MovedBefore {
+                collection,
+                id: id.0,
+                before,
+            })?;
         }
 
         Ok(())
     }
 
-    pub fn new(messages_sink: Sender<DDPMessage>) -> Self {
+    /// Notified when removals are pending, so `flush` can be scheduled only
+    /// then (see `schedule_removal`).
+    pub fn removals(&self) -> Arc<Notify> {
+        self.removals.clone()
+    }
+
+    /// Whether the client fell behind and did not catch up yet.
+    pub fn is_stale(&self) -> bool {
+        self.client_view.is_some()
+    }
+
+    pub fn new(
+        messages_sink: Outbox,
+        inconsistencies: Inconsistencies,
+        flatten: Arc<Flatten>,
+    ) -> Self {
         Self {
+            client_view: None,
+            client_order: BTreeMap::default(),
             collections: BTreeMap::default(),
+            flatten,
+            held: Vec::new(),
+            inconsistencies,
+            removed: BTreeMap::default(),
+            removed_counter: 0,
+            removals: Arc::default(),
+            server_order: BTreeMap::default(),
             server_view: BTreeMap::default(),
             messages_sink,
         }
     }
 
+    /// Checks that the owner (e.g., a stopped subscription) does not own any
+    /// document anymore. If it does, they are removed (if tolerant).
+    pub async fn release(&mut self, owner: &Owner) -> Result<(), Error> {
+        self.check_overflow();
+        let mut owned = vec![];
+        for (collection, documents) in &self.collections {
+            for (id, mergebox_document) in documents {
+                if mergebox_document.is_owned_by(owner) {
+                    owned.push((collection.clone(), id.clone()));
+                }
+            }
+        }
+
+        if owned.is_empty() {
+            return Ok(());
+        }
+
+        let error = anyhow!("{owner} still owns {} documents", owned.len());
+        repair(self.inconsistencies, Inconsistency::Unreleased, error)?;
+        for (collection, id) in owned {
+            self.release_document(&collection, &id, owner)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes all fields of the owner from the document (if any).
+    fn release_document(&mut self, collection: &str, id: &Id, owner: &Owner) -> Result<(), Error> {
+        let Some(documents) = self.collections.get_mut(collection) else {
+            return Ok(());
+        };
+        let Some(mergebox_document) = documents.get_mut(id) else {
+            return Ok(());
+        };
+
+        let fields_before = mergebox_document.values();
+        let (fields, cleared) = mergebox_document.release(owner);
+        if mergebox_document.is_empty() {
+            documents.remove(id);
+            self.schedule_removal(collection.to_owned(), id.clone(), fields_before);
+        } else {
+            self.send_changed(collection, id, fields, cleared)?;
+        }
+
+        Ok(())
+    }
+
     pub async fn remove(
         &mut self,
         collection: String,
         id: Value,
-        document: &Document,
+        owner: &Owner,
+        document: &SharedDocument,
     ) -> Result<(), Error> {
-        let mergebox_collection = self
+        self.check_overflow();
+        let Some(mergebox_collection) = self.collections.get_mut(&collection) else {
+            let error = anyhow!("Collection {collection} not found");
+            return repair(self.inconsistencies, Inconsistency::MissingDocument, error);
+        };
+        let id = Id(id);
+        let Some(mergebox_document) = mergebox_collection.get_mut(&id) else {
+            let error = anyhow!("Document {} not found in {collection}", id.0);
+            return repair(self.inconsistencies, Inconsistency::MissingDocument, error);
+        };
+        let fields_before = mergebox_document
+            .is_last(owner)
+            .then(|| mergebox_document.values());
+        let Some((fields, cleared, missing)) = mergebox_document.remove(owner, document) else {
+            let error = anyhow!(
+                "Document {} in {collection} is not owned by {owner} (only by {})",
+                id.0,
+                mergebox_document.provenance()
+            );
+            return repair(self.inconsistencies, Inconsistency::MissingDocument, error);
+        };
+
+        if let Some(field) = missing.first() {
+            let error = anyhow!("Field {field} not found").context(format!(
+                "Remove {} from {collection} by {owner} (owned by {})",
+                id.0,
+                mergebox_document.provenance()
+            ));
+            repair(self.inconsistencies, Inconsistency::MissingField, error)?;
+        }
+
+        if let Some(fields_before) = fields_before {
+            mergebox_collection.remove(&id);
+            self.schedule_removal(collection, id, fields_before);
+        } else {
+            self.send_changed(&collection, &id, fields, cleared)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the documents the owner published in the collection against the
+    /// given ones (e.g., of its cursor, inserted `inserts` times) and fixes the
+    /// ones that drifted. Returns the number of them.
+    pub async fn resync(
+        &mut self,
+        owner: &Owner,
+        collection: &str,
+        inserts: usize,
+        documents: impl IntoIterator<Item = (Value, SharedDocument)>,
+    ) -> Result<usize, Error> {
+        let mut current: HashMap<_, _> = self
             .collections
-            .get_mut(&collection)
-            .ok_or_else(|| anyhow!("Collection {collection} not found"))?;
-        let mergebox_index = mergebox_collection
-            .iter()
-            .position(|x| x.id == id)
-            .ok_or_else(|| anyhow!("Document {id} not found in {collection}"))?;
-        let cleared = mergebox_collection[mergebox_index]
-            .remove(document)
-            .with_context(|| format!("Remove {id} from {collection}"))?;
-
-        if mergebox_collection[mergebox_index].count == 0 {
-            mergebox_collection.swap_remove(mergebox_index);
-            self.messages_sink
-                .send(DDPMessage::Removed { collection, id })
-                .await?;
-        } else if !cleared.is_empty() {
-            self.messages_sink
-                .send(DDPMessage::Changed {
-                    collection,
-                    id,
-                    fields: None,
-                    cleared: Some(cleared),
-                })
+            .get(collection)
+            .into_iter()
+            .flatten()
+            .filter_map(|(id, mergebox_document)| {
+                let owned = mergebox_document.owned_by(owner)?;
+                Some((id.clone(), owned))
+            })
+            .collect();
+
+        let mut drifted = vec![];
+        for (id, document) in documents {
+            let id = Id(id);
+            let is_synced = current.remove(&id).is_some_and(|(count, fields)| {
+                count == inserts
+                    && fields.len() == document.iter().count()
+                    && document
+                        .iter()
+                        .all(|(field, value)| fields.get(field) == Some(value))
+            });
+            if !is_synced {
+                drifted.push((id, Some(document)));
+            }
+        }
+
+        drifted.extend(current.into_keys().map(|id| (id, None)));
+        for (id, document) in &drifted {
+            REPAIRED[Inconsistency::Drifted as usize].fetch_add(1, Ordering::Relaxed);
+            println!(
+                "\x1b[0;33mrouter\x1b[0m \x1b[0;31mResyncing {} in {collection} of {owner}\x1b[0m",
+                id.0
+            );
+
+            self.check_overflow();
+            self.release_document(collection, id, owner)?;
+            if let Some(document) = document {
+                for _ in 0..inserts {
+                    self.insert(collection.to_owned(), id.0.clone(), owner, document)
+                        .await
+                        .context("Mergebox::resync")?;
+                }
+            }
+        }
+
+        Ok(drifted.len())
+    }
+
+    /// Like `resync`, but for documents published by the server.
+    pub async fn resync_server(&mut self) -> Result<usize, Error> {
+        let collections: Vec<_> = self.collections.keys().cloned().collect();
+        let mut drifted = 0;
+        for collection in collections {
+            let documents: Vec<_> = self
+                .server_view
+                .get(&collection)
+                .into_iter()
+                .flatten()
+                .map(|(id, document)| (id.0.clone(), self.flattened(&collection, document)))
+                .collect();
+            drifted += self
+                .resync(&Owner::Server, &collection, 1, documents)
                 .await?;
         }
 
+        Ok(drifted)
+    }
+
+    /// Server documents are flattened just like the ones of cursors, so the
+    /// client sees the same fields no matter who published the document (e.g.,
+    /// a publication served by the server in strict mode). `server_view` keeps
+    /// them as they are, as `changed` replaces whole top-level fields.
+    fn flattened(&self, collection: &str, document: &SharedDocument) -> SharedDocument {
+        let flattening = self.flatten.get(collection);
+        if flattening.is_empty() {
+            return document.clone();
+        }
+
+        let mut fields = document.to_document();
+        flattening.apply(&mut fields);
+        SharedDocument::from(fields)
+    }
+
+    /// Sends a non-data message (e.g., `ready`) after all data messages sent
+    /// so far, i.e., holds it until the client catches up, if it fell behind.
+    pub fn send_other(&mut self, message: DDPMessage) -> Result<(), Error> {
+        self.check_overflow();
+        if self.client_view.is_some() {
+            self.held.push(message);
+            return Ok(());
+        }
+
+        self.messages_sink.send(message)
+    }
+
+    /// Sends the message, unless the client fell behind.
+    fn send(&self, message: DDPMessage) -> Result<(), Error> {
+        if self.client_view.is_none() {
+            self.messages_sink.send(message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fields of all documents the client sees (or will see, once it receives
+    /// all sent messages), including the ones waiting for `flush`.
+    fn view(&self) -> View {
+        let mut view: View = self
+            .collections
+            .iter()
+            .map(|(collection, documents)| {
+                let documents = documents
+                    .iter()
+                    .map(|(id, mergebox_document)| (id.clone(), mergebox_document.values()))
+                    .collect();
+                (collection.clone(), documents)
+            })
+            .collect();
+
+        for (collection, documents) in &self.removed {
+            let view_collection = view.entry(collection.clone()).or_default();
+            for (id, (_, fields)) in documents {
+                view_collection.insert(id.clone(), fields.clone());
+            }
+        }
+
+        view
+    }
+
+    /// Sends `removed` in `flush`, unless the document is added back before.
+    fn schedule_removal(&mut self, collection: String, id: Id, fields_before: Fields) {
+        if self.removed.values().all(HashMap::is_empty) {
+            self.removals.notify_one();
+        }
+
+        self.removed_counter += 1;
+        self.removed
+            .entry(collection)
+            .or_default()
+            .insert(id, (self.removed_counter, fields_before));
+    }
+
+    fn send_changed(
+        &self,
+        collection: &str,
+        id: &Id,
+        fields: Document,
+        cleared: Vec<String>,
+    ) -> Result<(), Error> {
+        if fields.is_empty() && cleared.is_empty() {
+            return Ok(());
+        }
+
+        let flattening = self.flatten.get(collection);
+        if !flattening.is_empty() && self.client_view.is_none() {
+            if let Some(document) = self.collections.get(collection).and_then(|x| x.get(id)) {
+                flattening.record(&fields, &cleared, |field| document.flattened_size(field));
+            }
+        }
+
+        self.send(DDPMessage::Changed {
+            collection: collection.to_owned(),
+            id: id.0.clone(),
+            fields: (!fields.is_empty()).then_some(fields),
+            cleared: (!cleared.is_empty()).then_some(cleared),
+        })?;
         Ok(())
     }
 
+    /// Meteor sends fields set to `undefined` as `cleared`, also in `added`.
+    /// Such fields are simply not there.
     pub async fn server_added(
         &mut self,
         collection: String,
         id: Value,
         fields: Option<Document>,
+        cleared: Option<Vec<String>>,
+    ) -> Result<(), Error> {
+        let mut fields = fields.unwrap_or_default();
+        for field in cleared.into_iter().flatten() {
+            fields.remove(&field);
+        }
+
+        self.server_added_ordered(collection, id, fields, None)
+            .await
+    }
+
+    pub async fn server_added_before(
+        &mut self,
+        collection: String,
+        id: Value,
+        fields: Option<Document>,
+        before: Before,
+    ) -> Result<(), Error> {
+        self.server_order_move(&collection, &Id(id.clone()), &before)?;
+        self.server_added_ordered(collection, id, fields.unwrap_or_default(), Some(before))
+            .await
+    }
+
+    async fn server_added_ordered(
+        &mut self,
+        collection: String,
+        id: Value,
+        fields: Document,
+        before: Option<Before>,
     ) -> Result<(), Error> {
         // Update `server_view`.
-        let document = fields.unwrap_or_default();
-        self.server_view
+        let document = SharedDocument::from(fields);
+        let document_before = self
+            .server_view
             .entry(collection.clone())
             .or_default()
-            .push((id.clone(), document.clone()));
+            .insert(Id(id.clone()), document.clone());
 
         // Update `collections`.
-        self.insert(collection, id, document).await
+        let document = self.flattened(&collection, &document);
+        self.insert_ordered(
+            collection.clone(),
+            id.clone(),
+            &Owner::Server,
+            &document,
+            before,
+        )
+        .await
+        .context("Mergebox::server_added")?;
+
+        // Replace the previous version, if any.
+        if let Some(document_before) = document_before {
+            let error = anyhow!("Document {id} already added to {collection}");
+            repair(self.inconsistencies, Inconsistency::AddedTwice, error)?;
+            let document_before = self.flattened(&collection, &document_before);
+            self.remove(collection, id, &Owner::Server, &document_before)
+                .await
+                .context("Mergebox::server_added")?;
+        }
+
+        Ok(())
     }
 
     pub async fn server_changed(
@@ -188,124 +866,1394 @@ impl Mergebox {
         cleared: Option<Vec<String>>,
     ) -> Result<(), Error> {
         // Update `server_view`.
-        let documents = self
+        let Some(document) = self
             .server_view
             .get_mut(&collection)
-            .ok_or_else(|| anyhow!("Collection not found {collection}"))?;
-        let index = documents
+            .and_then(|documents| documents.get_mut(&Id(id.clone())))
+        else {
+            let error = anyhow!("Document not found {id} in {collection}");
+            repair(self.inconsistencies, Inconsistency::ChangedUnknown, error)?;
+            return self.server_added(collection, id, fields, None).await;
+        };
+
+        // Unchanged fields keep sharing their values.
+        let cleared = cleared.unwrap_or_default();
+        let mut fields = fields.unwrap_or_default();
+        let mut document_applied: Vec<_> = document
             .iter()
-            .position(|x| x.0 == id)
-            .ok_or_else(|| anyhow!("Document not found {id} in {collection}"))?;
-        let document = documents.swap_remove(index).1;
-        let mut document_applied = document.clone();
-        for field in cleared.into_iter().flatten() {
-            document_applied.remove(&field);
-        }
-        for (key, value) in fields.into_iter().flatten() {
-            document_applied.insert(key, value);
-        }
-        documents.push((id.clone(), document_applied.clone()));
+            .filter(|(field, _)| !cleared.iter().any(|cleared| **cleared == ***field))
+            .map(|(field, value)| match fields.remove(&**field) {
+                Some(value) => (field.clone(), Arc::new(value)),
+                None => (field.clone(), value.clone()),
+            })
+            .collect();
+        document_applied.extend(
+            fields
+                .into_iter()
+                .map(|(field, value)| (Arc::from(field), Arc::new(value))),
+        );
+        let document_applied = SharedDocument::from_iter(document_applied);
+        let document = replace(document, document_applied.clone());
 
         // Update `collections`.
-        self.insert(collection.clone(), id.clone(), document_applied)
-            .await
-            .context("Mergebox::server_changed")?;
-        self.remove(collection, id, &document)
+        let document_applied = self.flattened(&collection, &document_applied);
+        let document = self.flattened(&collection, &document);
+        self.insert(
+            collection.clone(),
+            id.clone(),
+            &Owner::Server,
+            &document_applied,
+        )
+        .await
+        .context("Mergebox::server_changed")?;
+        self.remove(collection, id, &Owner::Server, &document)
             .await
             .context("Mergebox::server_changed")
     }
 
+    pub async fn server_moved_before(
+        &mut self,
+        collection: String,
+        id: Value,
+        before: Before,
+    ) -> Result<(), Error> {
+        let is_known = self
+            .server_view
+            .get(&collection)
+            .is_some_and(|documents| documents.contains_key(&Id(id.clone())));
+        if !is_known {
+            let error = anyhow!("Document not found {id} in {collection}");
+            return repair(self.inconsistencies, Inconsistency::MovedUnknown, error);
+        }
+
+        self.server_order_move(&collection, &Id(id.clone()), &before)?;
+        self.send(DDPMessage::MovedBefore {
+            collection,
+            id,
+            before,
+        })?;
+        Ok(())
+    }
+
+    /// Moves (or inserts) the document in the order of its collection.
+    fn server_order_move(
+        &mut self,
+        collection: &str,
+        id: &Id,
+        before: &Before,
+    ) -> Result<(), Error> {
+        let order = self.server_order.entry(collection.to_owned()).or_default();
+        order.remove(id);
+        let before = match before {
+            None => None,
+            Some(before) => match Id(Value::String(before.clone())) {
+                before if order.contains(&before) => Some(before),
+                _ => {
+                    let error = anyhow!("Document not found {before} in order of {collection}");
+                    repair(self.inconsistencies, Inconsistency::OrderUnknown, error)?;
+                    None
+                }
+            },
+        };
+
+        order.insert(id.clone(), before.as_ref());
+        Ok(())
+    }
+
     pub async fn server_removed(&mut self, collection: String, id: Value) -> Result<(), Error> {
+        // Update `server_order`.
+        if let Some(order) = self.server_order.get_mut(&collection) {
+            order.remove(&Id(id.clone()));
+        }
+
         // Update `server_view`.
-        let documents = self
+        let Some(document) = self
             .server_view
             .get_mut(&collection)
-            .ok_or_else(|| anyhow!("Collection not found {collection}"))?;
-        let index = documents
-            .iter()
-            .position(|x| x.0 == id)
-            .ok_or_else(|| anyhow!("Document not found {id} in {collection}"))?;
-        let document = documents.swap_remove(index).1;
+            .and_then(|documents| documents.remove(&Id(id.clone())))
+        else {
+            let error = anyhow!("Document not found {id} in {collection}");
+            return repair(self.inconsistencies, Inconsistency::RemovedUnknown, error);
+        };
 
         // Update `collections`.
-        self.remove(collection, id, &document)
+        let document = self.flattened(&collection, &document);
+        self.remove(collection, id, &Owner::Server, &document)
             .await
             .context("Mergebox::server_removed")
     }
 }
 
 pub struct MergeboxDocument {
-    id: Value,
-    count: usize,
-    fields: BTreeMap<String, MergeboxField>,
+    /// Number of inserts of every owner.
+    owners: Vec<(Owner, usize)>,
+    fields: BTreeMap<Arc<str>, MergeboxField>,
 }
 
 impl MergeboxDocument {
-    pub fn change(&mut self, document: Document) -> Document {
-        self.count += 1;
+    pub fn change(&mut self, owner: &Owner, document: &SharedDocument) -> Document {
+        match self.owners.iter_mut().find(|(x, _)| x == owner) {
+            Some((_, count)) => *count += 1,
+            None => self.owners.push((owner.clone(), 1)),
+        }
 
-        document
-            .into_iter()
-            .filter(|(field, value)| {
-                if let Some(mergebox_field) = self.fields.get_mut(field) {
-                    mergebox_field.count += 1;
-                    if mergebox_field.value != *value {
-                        mergebox_field.value = value.clone();
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    self.fields
-                        .insert(field.clone(), MergeboxField::new(value.clone()));
+        let mut changed = Document::new();
+        for (field, value) in document.iter() {
+            let is_changed = match self.fields.get_mut(&**field) {
+                Some(mergebox_field) => mergebox_field.insert(owner, value),
+                None => {
+                    let mergebox_field = MergeboxField::new(owner, value);
+                    self.fields.insert(field.clone(), mergebox_field);
                     true
                 }
-            })
-            .collect()
-    }
+            };
 
-    pub fn new(id: Value, document: Document) -> Self {
-        let fields = document
-            .into_iter()
-            .map(|(field, value)| (field, MergeboxField::new(value)))
+            if is_changed {
+                changed.insert(field.to_string(), Value::clone(value));
+            }
+        }
+
+        changed
+    }
+
+    /// Size of all keys (without the field itself) and values of a flattened
+    /// field (see `Flattening::record`).
+    fn flattened_size(&self, field: &str) -> usize {
+        self.fields
+            .range::<str, _>((Bound::Included(field), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(field))
+            .filter(|(key, _)| is_flattened(field, key))
+            .map(|(key, mergebox_field)| {
+                key.len() - field.len() + size_of_json(&mergebox_field.head.value)
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+
+    /// Whether removing it once by this owner removes the document entirely.
+    pub fn is_last(&self, owner: &Owner) -> bool {
+        matches!(self.owners.as_slice(), [(x, 1)] if x == owner)
+    }
+
+    pub fn is_owned_by(&self, owner: &Owner) -> bool {
+        self.owners.iter().any(|(x, _)| x == owner)
+    }
+
+    /// Number of inserts of the owner and its values of all fields (if any).
+    pub fn owned_by(&self, owner: &Owner) -> Option<(usize, Fields)> {
+        let (_, count) = self.owners.iter().find(|(x, _)| x == owner)?;
+        let fields = self
+            .fields
+            .iter()
+            .filter_map(|(field, mergebox_field)| {
+                let value = mergebox_field.value_of(owner)?;
+                Some((field.clone(), value.clone()))
+            })
+            .collect();
+        Some((*count, fields))
+    }
+
+    pub fn new(owner: &Owner, document: &SharedDocument) -> Self {
+        let fields = document
+            .iter()
+            .map(|(field, value)| (field.clone(), MergeboxField::new(owner, value)))
             .collect();
 
         Self {
-            id,
-            count: 1,
+            owners: vec![(owner.clone(), 1)],
             fields,
         }
     }
 
-    pub fn remove(&mut self, document: &Document) -> Result<Vec<String>, Error> {
-        self.count -= 1;
+    /// All owners of the document, e.g., for debugging.
+    pub fn provenance(&self) -> String {
+        let owners: Vec<_> = self.owners.iter().map(|(x, _)| x.to_string()).collect();
+        owners.join(", ")
+    }
+
+    /// Removes all fields of the owner. Returns changed and cleared fields.
+    pub fn release(&mut self, owner: &Owner) -> (Document, Vec<String>) {
+        self.owners.retain(|(x, _)| x != owner);
+
+        let mut changed = Document::new();
+        let mut cleared = Vec::default();
+        self.fields.retain(
+            |field, mergebox_field| match mergebox_field.release(owner) {
+                FieldRemoval::Changed => {
+                    let value = Value::clone(&mergebox_field.head.value);
+                    changed.insert(field.to_string(), value);
+                    true
+                }
+                FieldRemoval::Cleared => {
+                    cleared.push(field.to_string());
+                    false
+                }
+                FieldRemoval::Missing | FieldRemoval::Unchanged => true,
+            },
+        );
+
+        (changed, cleared)
+    }
+
+    /// Returns changed and cleared fields, and the ones the owner did not have
+    /// (or `None` if it did not own the document at all).
+    pub fn remove(
+        &mut self,
+        owner: &Owner,
+        document: &SharedDocument,
+    ) -> Option<(Document, Vec<String>, Vec<String>)> {
+        let index = self.owners.iter().position(|(x, _)| x == owner)?;
+        self.owners[index].1 -= 1;
+        if self.owners[index].1 == 0 {
+            self.owners.remove(index);
+        }
 
+        let mut changed = Document::new();
         let mut cleared = Vec::default();
-        for field in document.keys() {
-            let count = &mut self
-                .fields
-                .get_mut(field)
-                .ok_or_else(|| anyhow!("Field {field} not found"))?
-                .count;
-            if *count == 1 {
-                self.fields.remove(field);
-                cleared.push(field.clone());
+        let mut missing = Vec::default();
+        for (field, _) in document.iter() {
+            let Some(mergebox_field) = self.fields.get_mut(&**field) else {
+                missing.push(field.to_string());
+                continue;
+            };
+
+            match mergebox_field.remove(owner) {
+                FieldRemoval::Changed => {
+                    let value = Value::clone(&mergebox_field.head.value);
+                    changed.insert(field.to_string(), value);
+                }
+                FieldRemoval::Cleared => {
+                    self.fields.remove(&**field);
+                    cleared.push(field.to_string());
+                }
+                FieldRemoval::Missing => missing.push(field.to_string()),
+                FieldRemoval::Unchanged => {}
+            }
+        }
+
+        Some((changed, cleared, missing))
+    }
+
+    /// Visible values of all fields.
+    pub fn values(&self) -> Fields {
+        self.fields
+            .iter()
+            .map(|(field, mergebox_field)| (field.clone(), mergebox_field.head.value.clone()))
+            .collect()
+    }
+}
+
+/// Messages that turn one view (and order) into the other.
+fn compare(
+    before: &View,
+    after: &View,
+    order_before: &Orders,
+    order_after: &Orders,
+) -> Vec<DDPMessage> {
+    let mut messages = vec![];
+    for (collection, documents) in after {
+        let before = before.get(collection);
+        let order = order_after.get(collection);
+        for (id, fields) in documents {
+            let document = SharedDocument::from_iter(fields.clone());
+            let message = match before.and_then(|documents| documents.get(id)) {
+                // Ordered ones are added below, in order.
+                None if order.is_some_and(|order| order.contains(id)) => continue,
+                None => DDPMessage::Added {
+                    collection: collection.clone(),
+                    id: id.0.clone(),
+                    fields: (!document.is_empty()).then(|| document.to_document()),
+                    cleared: None,
+                },
+                Some(fields) => {
+                    let (fields, cleared) = diff(fields, &document);
+                    if fields.is_empty() && cleared.is_empty() {
+                        continue;
+                    }
+
+                    DDPMessage::Changed {
+                        collection: collection.clone(),
+                        id: id.0.clone(),
+                        fields: (!fields.is_empty()).then_some(fields),
+                        cleared: (!cleared.is_empty()).then_some(cleared),
+                    }
+                }
+            };
+            messages.push(message);
+        }
+    }
+
+    // Ordered documents are positioned from the last one, so the next one is
+    // already in place (and present) when it is referred to.
+    for (collection, order) in order_after {
+        let before = before.get(collection);
+        let after = after.get(collection);
+        let mut client_order = order_before.get(collection).cloned().unwrap_or_default();
+        for id in client_order.ids() {
+            if !order.contains(&id) {
+                client_order.remove(&id);
+            }
+        }
+
+        let ids = order.ids();
+        for (index, id) in ids.iter().enumerate().rev() {
+            let next = ids.get(index + 1);
+            let before_id = next.and_then(|next| next.0.as_str()).map(str::to_owned);
+            if !before.is_some_and(|documents| documents.contains_key(id)) {
+                let fields = after
+                    .and_then(|documents| documents.get(id))
+                    .filter(|fields| !fields.is_empty())
+                    .map(|fields| SharedDocument::from_iter(fields.clone()).to_document());
+                messages.push(DDPMessage::AddedBefore {
+                    collection: collection.clone(),
+                    id: id.0.clone(),
+                    fields,
+                    before: before_id,
+                });
+            } else if client_order.contains(id) && client_order.next(id) == next {
+                continue;
             } else {
-                *count -= 1;
+                client_order.remove(id);
+                messages.push(DDPMessage::MovedBefore {
+                    collection: collection.clone(),
+                    id: id.0.clone(),
+                    before: before_id,
+                });
             }
+
+            client_order.insert(id.clone(), next);
         }
+    }
 
-        Ok(cleared)
+    for (collection, documents) in before {
+        let after = after.get(collection);
+        for id in documents.keys() {
+            if !after.is_some_and(|documents| documents.contains_key(id)) {
+                messages.push(DDPMessage::Removed {
+                    collection: collection.clone(),
+                    id: id.0.clone(),
+                });
+            }
+        }
     }
+
+    messages
+}
+
+/// Changed (or new) fields and cleared ones.
+fn diff(before: &Fields, after: &SharedDocument) -> (Document, Vec<String>) {
+    let fields = after
+        .iter()
+        .filter(|(field, value)| before.get(*field) != Some(*value))
+        .map(|(field, value)| (field.to_string(), Value::clone(value)))
+        .collect();
+    let cleared = before
+        .keys()
+        .filter(|field| !after.iter().any(|(x, _)| x == *field))
+        .map(|field| field.to_string())
+        .collect();
+    (fields, cleared)
 }
 
+/// Documents of a collection as a doubly linked list, as `addedBefore` and
+/// `movedBefore` refer to the next document. That makes both of them O(1).
+#[derive(Clone, Default)]
+struct Order {
+    first: Option<Id>,
+    last: Option<Id>,
+    /// Previous and next document of every document.
+    links: HashMap<Id, (Option<Id>, Option<Id>)>,
+}
+
+impl Order {
+    fn contains(&self, id: &Id) -> bool {
+        self.links.contains_key(id)
+    }
+
+    /// All documents, from the first one.
+    fn ids(&self) -> Vec<Id> {
+        let mut ids = Vec::with_capacity(self.links.len());
+        let mut next = self.first.as_ref();
+        while let Some(id) = next {
+            ids.push(id.clone());
+            next = self.links.get(id).and_then(|(_, next)| next.as_ref());
+        }
+        ids
+    }
+
+    /// Inserts the document before the given one (it has to be there) or at
+    /// the end. The document itself cannot be there already.
+    fn insert(&mut self, id: Id, before: Option<&Id>) {
+        let previous = match before.and_then(|before| self.links.get_mut(before)) {
+            Some((previous, _)) => previous.replace(id.clone()),
+            None => self.last.replace(id.clone()),
+        };
+
+        match previous
+            .as_ref()
+            .and_then(|previous| self.links.get_mut(previous))
+        {
+            Some((_, next)) => *next = Some(id.clone()),
+            None => self.first = Some(id.clone()),
+        }
+
+        self.links.insert(id, (previous, before.cloned()));
+    }
+
+    fn next(&self, id: &Id) -> Option<&Id> {
+        self.links.get(id).and_then(|(_, next)| next.as_ref())
+    }
+
+    fn remove(&mut self, id: &Id) {
+        let Some((previous, next)) = self.links.remove(id) else {
+            return;
+        };
+
+        match previous
+            .as_ref()
+            .and_then(|previous| self.links.get_mut(previous))
+        {
+            Some((_, link)) => link.clone_from(&next),
+            None => self.first.clone_from(&next),
+        }
+
+        match next.as_ref().and_then(|next| self.links.get_mut(next)) {
+            Some((link, _)) => *link = previous,
+            None => self.last = previous,
+        }
+    }
+}
+
+/// Values of a field published by different owners.
 pub struct MergeboxField {
-    count: usize,
-    value: Value,
+    /// Visible value, i.e., of the owner with the highest precedence.
+    head: FieldValue,
+    /// Values of other owners, in the order of precedence.
+    rest: Vec<FieldValue>,
 }
 
 impl MergeboxField {
-    pub fn new(value: Value) -> Self {
-        Self { count: 1, value }
+    /// Returns whether the visible value changed.
+    fn insert(&mut self, owner: &Owner, value: &Arc<Value>) -> bool {
+        if self.head.owner == *owner {
+            self.head.count += 1;
+            return self.head.set(value);
+        }
+
+        if let Some(field_value) = self.rest.iter_mut().find(|x| x.owner == *owner) {
+            field_value.count += 1;
+            field_value.set(value);
+            return false;
+        }
+
+        let field_value = FieldValue::new(owner, value);
+        if owner.precedes(&self.head.owner) {
+            let head = replace(&mut self.head, field_value);
+            let is_changed = head.value != self.head.value;
+            self.rest.insert(0, head);
+            is_changed
+        } else {
+            self.rest.push(field_value);
+            false
+        }
+    }
+
+    fn value_of(&self, owner: &Owner) -> Option<&Arc<Value>> {
+        let mut values = once(&self.head).chain(&self.rest);
+        let value = values.find(|value| value.owner == *owner)?;
+        Some(&value.value)
+    }
+
+    fn new(owner: &Owner, value: &Arc<Value>) -> Self {
+        Self {
+            head: FieldValue::new(owner, value),
+            rest: Vec::default(),
+        }
+    }
+
+    /// Removes one insert of the owner.
+    fn remove(&mut self, owner: &Owner) -> FieldRemoval {
+        self.remove_with(owner, |count| {
+            *count -= 1;
+            *count == 0
+        })
+    }
+
+    /// Removes all inserts of the owner.
+    fn release(&mut self, owner: &Owner) -> FieldRemoval {
+        self.remove_with(owner, |_| true)
+    }
+
+    fn remove_with(
+        &mut self,
+        owner: &Owner,
+        is_gone: impl FnOnce(&mut usize) -> bool,
+    ) -> FieldRemoval {
+        if self.head.owner == *owner {
+            if !is_gone(&mut self.head.count) {
+                return FieldRemoval::Unchanged;
+            }
+
+            if self.rest.is_empty() {
+                return FieldRemoval::Cleared;
+            }
+
+            let head = replace(&mut self.head, self.rest.remove(0));
+            return if head.value == self.head.value {
+                FieldRemoval::Unchanged
+            } else {
+                FieldRemoval::Changed
+            };
+        }
+
+        let Some(index) = self.rest.iter().position(|x| x.owner == *owner) else {
+            return FieldRemoval::Missing;
+        };
+
+        if is_gone(&mut self.rest[index].count) {
+            self.rest.remove(index);
+        }
+
+        FieldRemoval::Unchanged
+    }
+}
+
+/// What happened to the visible value of a field when removing it.
+enum FieldRemoval {
+    Changed,
+    Cleared,
+    Missing,
+    Unchanged,
+}
+
+struct FieldValue {
+    count: usize,
+    owner: Owner,
+    value: Arc<Value>,
+}
+
+impl FieldValue {
+    fn new(owner: &Owner, value: &Arc<Value>) -> Self {
+        Self {
+            count: 1,
+            owner: owner.clone(),
+            value: value.clone(),
+        }
+    }
+
+    /// Returns whether the value changed.
+    fn set(&mut self, value: &Arc<Value>) -> bool {
+        if Arc::ptr_eq(&self.value, value) {
+            return false;
+        }
+
+        let is_changed = self.value != *value;
+
+        // Keep the latest value, so the old one can be released.
+        self.value = value.clone();
+        is_changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hash_value, Id, Inconsistency, Mergebox, Mergeboxes, Order, Owner, SharedDocument,
+    };
+    use crate::ddp::DDPMessage;
+    use crate::flatten::Flattening;
+    use crate::outbox::{Outbox, OutboxReader};
+    use crate::settings::{Flatten, Inconsistencies, SlowClients};
+    use anyhow::Error;
+    use futures_util::FutureExt;
+    use serde_json::{json, Map, Value};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::sync::Mutex;
+    use tokio::test;
+
+    fn hash(value: &Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hash_value(value, &mut hasher);
+        hasher.finish()
+    }
+
+    fn document(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    fn mergebox(
+        limit: Option<(usize, SlowClients)>,
+        inconsistencies: Inconsistencies,
+    ) -> (OutboxReader, Mergebox) {
+        let (sender, receiver) = Outbox::new(limit);
+        let mergebox = Mergebox::new(sender, inconsistencies, Arc::default());
+        (receiver, mergebox)
+    }
+
+    fn s() -> Owner {
+        Owner::Subscription(Arc::from("s"))
+    }
+
+    fn x() -> String {
+        "x".to_owned()
+    }
+
+    /// Checks that exactly these messages were sent (and nothing else).
+    macro_rules! assert_messages {
+        ($receiver:expr, $messages:expr) => {
+            for message in $messages {
+                assert_eq!($receiver.try_recv(), Ok(message));
+            }
+
+            assert!($receiver.try_recv().is_err());
+        };
+    }
+
+    macro_rules! eq {
+        ($name:ident, $lhs:tt, $rhs:tt) => {
+            #[test]
+            async fn $name() {
+                let lhs = json!($lhs);
+                let rhs = json!($rhs);
+                assert_eq!(Id(lhs.clone()), Id(rhs.clone()));
+                assert_eq!(hash(&lhs), hash(&rhs));
+            }
+        };
+    }
+
+    macro_rules! ne {
+        ($name:ident, $lhs:tt, $rhs:tt) => {
+            #[test]
+            async fn $name() {
+                assert_ne!(Id(json!($lhs)), Id(json!($rhs)));
+            }
+        };
+    }
+
+    eq!(id_1, "a", "a");
+    eq!(id_2, 1, 1);
+    eq!(id_3, {"$type": "oid", "$value": "5f2b"}, {"$value": "5f2b", "$type": "oid"});
+    eq!(id_4, 0.0, (-0.0));
+    eq!(id_5, [{"a": 1, "b": 2}], [{"b": 2, "a": 1}]);
+    ne!(id_6, 1, 1.0);
+    ne!(id_7, "1", 1);
+    ne!(id_8, [1, 2], [2, 1]);
+
+    #[test]
+    async fn messages() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) = mergebox(None, Inconsistencies::Strict);
+        let id = json!({"$type": "oid", "$value": "5f2b"});
+        let id_reordered = json!({"$value": "5f2b", "$type": "oid"});
+
+        let a = SharedDocument::from(document(json!({"a": 1})));
+        let ab = SharedDocument::from(document(json!({"a": 1, "b": 2})));
+        mergebox.insert(x(), id.clone(), &s(), &a).await?;
+        mergebox
+            .insert(x(), id_reordered.clone(), &s(), &ab)
+            .await?;
+        mergebox.remove(x(), id_reordered, &s(), &ab).await?;
+        mergebox.remove(x(), id.clone(), &s(), &a).await?;
+        mergebox.flush().await?;
+
+        let messages = [
+            DDPMessage::Added {
+                collection: x(),
+                id: id.clone(),
+                fields: Some(a.to_document()),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: id.clone(),
+                fields: Some(document(json!({"b": 2}))),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: id.clone(),
+                fields: None,
+                cleared: Some(vec!["b".to_owned()]),
+            },
+            DDPMessage::Removed {
+                collection: x(),
+                id,
+            },
+        ];
+
+        assert_messages!(receiver, messages);
+        Ok(())
+    }
+
+    #[test]
+    async fn server_flattened() -> Result<(), Error> {
+        let (sender, mut receiver) = Outbox::new(None);
+        let flatten = Flatten {
+            collections: [("x".to_owned(), vec!["a".to_owned()])].into(),
+        };
+        let mut mergebox = Mergebox::new(sender, Inconsistencies::Strict, Arc::new(flatten));
+
+        mergebox
+            .server_added(x(), json!(1), Some(document(json!({"a": [1, 2]}))), None)
+            .await?;
+        let saved = Flattening::saved();
+        mergebox
+            .server_changed(x(), json!(1), Some(document(json!({"a": [3]}))), None)
+            .await?;
+        mergebox.flush().await?;
+
+        // Other tests may save bytes in the meantime too.
+        assert!(Flattening::saved() >= saved + 4);
+
+        let messages = [
+            DDPMessage::Added {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"a.0": 1, "a.1": 2}))),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"a.0": 3}))),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: json!(1),
+                fields: None,
+                cleared: Some(vec!["a.1".to_owned()]),
+            },
+        ];
+
+        assert_messages!(receiver, messages);
+        Ok(())
+    }
+
+    #[test]
+    async fn removed_added() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) = mergebox(None, Inconsistencies::Strict);
+        let ab = SharedDocument::from(document(json!({"a": 1, "b": 2})));
+        let ac = SharedDocument::from(document(json!({"a": 1, "c": 3})));
+
+        // E.g., the document moved from one cursor to another.
+        mergebox.insert(x(), json!(1), &s(), &ab).await?;
+        mergebox.insert(x(), json!(2), &s(), &ab).await?;
+        mergebox.remove(x(), json!(1), &s(), &ab).await?;
+        mergebox.remove(x(), json!(2), &s(), &ab).await?;
+        mergebox.insert(x(), json!(1), &s(), &ac).await?;
+        mergebox.flush().await?;
+        mergebox.flush().await?;
+
+        let messages = [
+            DDPMessage::Added {
+                collection: x(),
+                id: json!(1),
+                fields: Some(ab.to_document()),
+                cleared: None,
+            },
+            DDPMessage::Added {
+                collection: x(),
+                id: json!(2),
+                fields: Some(ab.to_document()),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"c": 3}))),
+                cleared: Some(vec!["b".to_owned()]),
+            },
+            DDPMessage::Removed {
+                collection: x(),
+                id: json!(2),
+            },
+        ];
+
+        assert_messages!(receiver, messages);
+        Ok(())
+    }
+
+    #[test]
+    async fn removals() -> Result<(), Error> {
+        let (_receiver, mut mergebox) = mergebox(None, Inconsistencies::Strict);
+        let removals = mergebox.removals();
+        let a = SharedDocument::from(document(json!({"a": 1})));
+
+        // Only the first removal since the last flush notifies.
+        mergebox.insert(x(), json!(1), &s(), &a).await?;
+        mergebox.insert(x(), json!(2), &s(), &a).await?;
+        assert!(removals.notified().now_or_never().is_none());
+        mergebox.remove(x(), json!(1), &s(), &a).await?;
+        mergebox.remove(x(), json!(2), &s(), &a).await?;
+        assert!(removals.notified().now_or_never().is_some());
+        assert!(removals.notified().now_or_never().is_none());
+
+        // Removals added back are not pending anymore.
+        mergebox.insert(x(), json!(1), &s(), &a).await?;
+        mergebox.insert(x(), json!(2), &s(), &a).await?;
+        mergebox.remove(x(), json!(1), &s(), &a).await?;
+        assert!(removals.notified().now_or_never().is_some());
+        mergebox.flush().await?;
+        mergebox.remove(x(), json!(2), &s(), &a).await?;
+        assert!(removals.notified().now_or_never().is_some());
+        Ok(())
+    }
+
+    #[test]
+    async fn ordered() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) = mergebox(None, Inconsistencies::Strict);
+        let b = || Some("b".to_owned());
+
+        mergebox
+            .server_added_before(x(), json!("a"), Some(document(json!({"a": 1}))), None)
+            .await?;
+        mergebox
+            .server_added_before(x(), json!("b"), None, None)
+            .await?;
+        mergebox.server_moved_before(x(), json!("a"), None).await?;
+        mergebox
+            .server_added(
+                x(),
+                json!("d"),
+                Some(document(json!({"d": 1, "e": 2}))),
+                Some(vec!["e".to_owned()]),
+            )
+            .await?;
+        mergebox
+            .server_added_before(x(), json!("c"), None, b())
+            .await?;
+        assert!(mergebox
+            .server_moved_before(x(), json!("z"), b())
+            .await
+            .is_err());
+        assert_eq!(
+            mergebox.server_order[&x()].ids(),
+            [Id(json!("c")), Id(json!("b")), Id(json!("a"))]
+        );
+
+        mergebox.server_removed(x(), json!("c")).await?;
+        mergebox.flush().await?;
+        assert_eq!(
+            mergebox.server_order[&x()].ids(),
+            [Id(json!("b")), Id(json!("a"))]
+        );
+
+        let messages = [
+            DDPMessage::AddedBefore {
+                collection: x(),
+                id: json!("a"),
+                fields: Some(document(json!({"a": 1}))),
+                before: None,
+            },
+            DDPMessage::AddedBefore {
+                collection: x(),
+                id: json!("b"),
+                fields: None,
+                before: None,
+            },
+            DDPMessage::MovedBefore {
+                collection: x(),
+                id: json!("a"),
+                before: None,
+            },
+            DDPMessage::Added {
+                collection: x(),
+                id: json!("d"),
+                fields: Some(document(json!({"d": 1}))),
+                cleared: None,
+            },
+            DDPMessage::AddedBefore {
+                collection: x(),
+                id: json!("c"),
+                fields: None,
+                before: b(),
+            },
+            DDPMessage::Removed {
+                collection: x(),
+                id: json!("c"),
+            },
+        ];
+
+        assert_messages!(receiver, messages);
+        Ok(())
+    }
+
+    #[test]
+    async fn strict() {
+        let (_receiver, mut mergebox) = mergebox(None, Inconsistencies::Strict);
+        let a = SharedDocument::from(document(json!({"a": 1})));
+        assert!(mergebox
+            .server_changed(x(), json!(1), None, None)
+            .await
+            .is_err());
+        assert!(mergebox.server_removed(x(), json!(1)).await.is_err());
+        assert!(mergebox.remove(x(), json!(1), &s(), &a).await.is_err());
+        mergebox.insert(x(), json!(1), &s(), &a).await.unwrap();
+        let t = Owner::Subscription(Arc::from("t"));
+        assert!(mergebox.remove(x(), json!(1), &t, &a).await.is_err());
+        assert!(mergebox.release(&s()).await.is_err());
+    }
+
+    #[test]
+    async fn tolerant() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) = mergebox(None, Inconsistencies::Tolerant);
+        let a = SharedDocument::from(document(json!({"a": 1})));
+        let ab = SharedDocument::from(document(json!({"a": 1, "b": 2})));
+        let repaired_before = Inconsistency::ChangedUnknown.repaired();
+
+        mergebox
+            .server_changed(x(), json!(1), Some(document(json!({"a": 1}))), None)
+            .await?;
+        mergebox
+            .server_added(x(), json!(1), Some(document(json!({"a": 2}))), None)
+            .await?;
+        mergebox.server_removed(x(), json!(2)).await?;
+        mergebox.remove(x(), json!(2), &s(), &a).await?;
+        mergebox.insert(x(), json!(3), &s(), &a).await?;
+        mergebox.remove(x(), json!(3), &s(), &ab).await?;
+        mergebox.flush().await?;
+
+        let messages = [
+            DDPMessage::Added {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"a": 1}))),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"a": 2}))),
+                cleared: None,
+            },
+            DDPMessage::Added {
+                collection: x(),
+                id: json!(3),
+                fields: Some(document(json!({"a": 1}))),
+                cleared: None,
+            },
+            DDPMessage::Removed {
+                collection: x(),
+                id: json!(3),
+            },
+        ];
+
+        assert_messages!(receiver, messages);
+        assert!(Inconsistency::ChangedUnknown.repaired() > repaired_before);
+        Ok(())
+    }
+
+    #[test]
+    async fn owners() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) = mergebox(None, Inconsistencies::Tolerant);
+        let t = Owner::Subscription(Arc::from("t"));
+        let ab = SharedDocument::from(document(json!({"a": 1, "b": 2})));
+        let b = SharedDocument::from(document(json!({"b": 4})));
+        let repaired_before = Inconsistency::Unreleased.repaired();
+
+        // Server takes precedence, then the first subscription wins.
+        mergebox.insert(x(), json!(1), &s(), &ab).await?;
+        mergebox
+            .server_added(x(), json!(1), Some(document(json!({"a": 3}))), None)
+            .await?;
+        mergebox.insert(x(), json!(1), &t, &b).await?;
+        mergebox.server_removed(x(), json!(1)).await?;
+        mergebox.remove(x(), json!(1), &s(), &ab).await?;
+        assert!(mergebox.remove(x(), json!(1), &s(), &ab).await.is_ok());
+        mergebox.release(&t).await?;
+        mergebox.flush().await?;
+
+        let messages = [
+            DDPMessage::Added {
+                collection: x(),
+                id: json!(1),
+                fields: Some(ab.to_document()),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"a": 3}))),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"a": 1}))),
+                cleared: None,
+            },
+            DDPMessage::Changed {
+                collection: x(),
+                id: json!(1),
+                fields: Some(document(json!({"b": 4}))),
+                cleared: Some(vec!["a".to_owned()]),
+            },
+            DDPMessage::Removed {
+                collection: x(),
+                id: json!(1),
+            },
+        ];
+
+        assert_messages!(receiver, messages);
+        assert!(Inconsistency::Unreleased.repaired() > repaired_before);
+        Ok(())
+    }
+
+    #[test]
+    async fn resync() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) = mergebox(None, Inconsistencies::Strict);
+        let a1 = SharedDocument::from(document(json!({"a": 1})));
+        let a1c3 = SharedDocument::from(document(json!({"a": 1, "c": 3})));
+        let a2 = SharedDocument::from(document(json!({"a": 2})));
+        let a4 = SharedDocument::from(document(json!({"a": 4})));
+        mergebox.insert(x(), json!(1), &s(), &a1).await?;
+        mergebox.insert(x(), json!(2), &s(), &a2).await?;
+        mergebox
+            .server_added(x(), json!(3), Some(document(json!({"b": 1}))), None)
+            .await?;
+        while receiver.try_recv().is_ok() {}
+
+        // E.g., a lost event changed 1, removed 2, and added 4.
+        let documents = vec![(json!(1), a1c3.clone()), (json!(4), a4.clone())];
+        assert_eq!(mergebox.resync(&s(), "x", 1, documents.clone()).await?, 3);
+        assert_eq!(mergebox.resync_server().await?, 0);
+        mergebox.flush().await?;
+
+        let mut messages = vec![];
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message);
+        }
+
+        assert_eq!(messages.len(), 3);
+        assert!(messages.contains(&DDPMessage::Changed {
+            collection: x(),
+            id: json!(1),
+            fields: Some(document(json!({"c": 3}))),
+            cleared: None,
+        }));
+        assert!(messages.contains(&DDPMessage::Added {
+            collection: x(),
+            id: json!(4),
+            fields: Some(a4.to_document()),
+            cleared: None,
+        }));
+        assert!(messages.contains(&DDPMessage::Removed {
+            collection: x(),
+            id: json!(2),
+        }));
+
+        // Nothing to fix anymore, and the state is usable as before.
+        assert_eq!(mergebox.resync(&s(), "x", 1, documents).await?, 0);
+        mergebox.remove(x(), json!(1), &s(), &a1c3).await?;
+
+        // Server documents are checked against `server_view`.
+        mergebox.server_view.get_mut(&x()).unwrap().clear();
+        assert_eq!(mergebox.resync_server().await?, 1);
+        mergebox.flush().await?;
+        let mut messages = vec![];
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message);
+        }
+
+        assert!(messages.contains(&DDPMessage::Removed {
+            collection: x(),
+            id: json!(3),
+        }));
+        Ok(())
+    }
+
+    #[test]
+    async fn slow() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) =
+            mergebox(Some((2, SlowClients::Resync)), Inconsistencies::Strict);
+        let a = |a| SharedDocument::from(document(json!({"a": a})));
+
+        // The third message is over the limit, so the rest are not sent.
+        for id in 1..=4 {
+            mergebox.insert(x(), json!(id), &s(), &a(id)).await?;
+        }
+        assert!(mergebox.is_stale());
+        mergebox.insert(x(), json!(1), &s(), &a(5)).await?;
+        mergebox.remove(x(), json!(1), &s(), &a(1)).await?;
+        mergebox.remove(x(), json!(2), &s(), &a(2)).await?;
+        mergebox.flush().await?;
+
+        // Not caught up yet.
+        assert!(!mergebox.catch_up()?);
+        let messages = (1..=3).map(|id| DDPMessage::Added {
+            collection: x(),
+            id: json!(id),
+            fields: Some(a(id).to_document()),
+            cleared: None,
+        });
+        assert_messages!(receiver, messages);
+
+        // Only the differences are sent.
+        assert!(mergebox.catch_up()?);
+        assert!(!mergebox.is_stale());
+        let mut messages = vec![];
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message);
+        }
+
+        assert_eq!(messages.len(), 3);
+        assert!(messages.contains(&DDPMessage::Changed {
+            collection: x(),
+            id: json!(1),
+            fields: Some(document(json!({"a": 5}))),
+            cleared: None,
+        }));
+        assert!(messages.contains(&DDPMessage::Added {
+            collection: x(),
+            id: json!(4),
+            fields: Some(a(4).to_document()),
+            cleared: None,
+        }));
+        assert!(messages.contains(&DDPMessage::Removed {
+            collection: x(),
+            id: json!(2),
+        }));
+        Ok(())
+    }
+
+    #[test]
+    async fn slow_fetch() -> Result<(), Error> {
+        let (mut receiver, mergebox) =
+            mergebox(Some((10, SlowClients::Resync)), Inconsistencies::Strict);
+        let mergebox = Arc::new(Mutex::new(mergebox));
+        let mut mergeboxes = Mergeboxes::default();
+        mergeboxes.insert_mergebox(1, s(), &mergebox);
+        let a = SharedDocument::from(document(json!({"a": 1})));
+        let ready = || DDPMessage::Ready {
+            subs: vec!["s".to_owned()],
+        };
+
+        // E.g., the initial fetch of a large cursor goes over the limit.
+        for id in 0..100 {
+            mergeboxes.insert(x(), json!(id), &a).await?;
+        }
+        mergebox.lock().await.send_other(ready())?;
+        assert!(mergebox.lock().await.is_stale());
+
+        // The client gets all documents once it catches up, and then `ready`.
+        let mut messages = vec![];
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message);
+        }
+        assert!(mergebox.lock().await.catch_up()?);
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message);
+        }
+
+        assert_eq!(messages.pop(), Some(ready()));
+        let mut ids: Vec<_> = messages
+            .into_iter()
+            .map(|message| match message {
+                DDPMessage::Added { id, .. } => id,
+                message => panic!("Unexpected {message:?}"),
+            })
+            .collect();
+        ids.sort_by_key(|id| id.as_u64());
+        assert_eq!(ids, (0..100).map(|id| json!(id)).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    async fn slow_held() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) =
+            mergebox(Some((1, SlowClients::Resync)), Inconsistencies::Strict);
+        let a = SharedDocument::from(document(json!({"a": 1})));
+        let ready = || DDPMessage::Ready {
+            subs: vec!["s".to_owned()],
+        };
+
+        // The third message is over the limit, so `ready` is held back.
+        for id in 1..=3 {
+            mergebox.insert(x(), json!(id), &s(), &a).await?;
+        }
+        mergebox.send_other(ready())?;
+        assert!(mergebox.is_stale());
+
+        // It's sent after the data.
+        while receiver.try_recv().is_ok() {}
+        assert!(mergebox.catch_up()?);
+        let message = DDPMessage::Added {
+            collection: x(),
+            id: json!(3),
+            fields: Some(a.to_document()),
+            cleared: None,
+        };
+        assert_messages!(receiver, [message, ready()]);
+        Ok(())
+    }
+
+    #[test]
+    async fn slow_disconnect() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) =
+            mergebox(Some((1, SlowClients::Disconnect)), Inconsistencies::Strict);
+        let a = SharedDocument::from(document(json!({"a": 1})));
+
+        // Nothing is held back, as the session is closed anyway.
+        for id in 1..=3 {
+            mergebox.insert(x(), json!(id), &s(), &a).await?;
+        }
+        mergebox.send_other(DDPMessage::Ready { subs: vec![] })?;
+        assert!(!mergebox.is_stale());
+
+        let mut messages = 0;
+        while receiver.try_recv().is_ok() {
+            messages += 1;
+        }
+        assert_eq!(messages, 4);
+        Ok(())
+    }
+
+    #[test]
+    async fn slow_ordered() -> Result<(), Error> {
+        let (mut receiver, mut mergebox) =
+            mergebox(Some((1, SlowClients::Resync)), Inconsistencies::Strict);
+        let before = |id: &str| Some(id.to_owned());
+
+        // The third message is over the limit, so the rest are not sent.
+        mergebox
+            .server_added_before(x(), json!("a"), None, None)
+            .await?;
+        mergebox
+            .server_added_before(x(), json!("b"), None, None)
+            .await?;
+        mergebox
+            .server_added_before(x(), json!("c"), None, before("a"))
+            .await?;
+        mergebox
+            .server_moved_before(x(), json!("b"), before("c"))
+            .await?;
+        assert!(mergebox.is_stale());
+        while receiver.try_recv().is_ok() {}
+
+        // Client has `a, b` and gets `b, c, a`.
+        assert!(mergebox.catch_up()?);
+        let messages = [
+            DDPMessage::MovedBefore {
+                collection: x(),
+                id: json!("a"),
+                before: None,
+            },
+            DDPMessage::AddedBefore {
+                collection: x(),
+                id: json!("c"),
+                fields: None,
+                before: before("a"),
+            },
+        ];
+
+        assert_messages!(receiver, messages);
+
+        // Nothing drifted.
+        assert_eq!(mergebox.resync_server().await?, 0);
+        Ok(())
+    }
+
+    #[test]
+    async fn order() {
+        let id = |id: &str| Id(json!(id));
+        let mut order = Order::default();
+        order.insert(id("a"), None);
+        order.insert(id("b"), None);
+        order.insert(id("c"), Some(&id("a")));
+        assert_eq!(order.ids(), [id("c"), id("a"), id("b")]);
+        assert_eq!(order.next(&id("a")), Some(&id("b")));
+        assert_eq!(order.next(&id("b")), None);
+
+        order.remove(&id("c"));
+        order.remove(&id("b"));
+        order.insert(id("b"), Some(&id("a")));
+        assert_eq!(order.ids(), [id("b"), id("a")]);
+
+        order.remove(&id("a"));
+        order.remove(&id("b"));
+        order.remove(&id("b"));
+        assert!(order.ids().is_empty());
+        assert!(order.first.is_none() && order.last.is_none());
+    }
+
+    #[test]
+    async fn shared() -> Result<(), Error> {
+        let (sender, _receiver) = Outbox::new(None);
+        let mut mergeboxes: Vec<_> = (0..3)
+            .map(|_| Mergebox::new(sender.clone(), Inconsistencies::Strict, Arc::default()))
+            .collect();
+        let a1 = SharedDocument::from(document(json!({"a": 1, "b": 2})));
+        let a2 = SharedDocument::from(document(json!({"a": 1, "b": 3})));
+        for mergebox in &mut mergeboxes {
+            mergebox.insert(x(), json!(1), &s(), &a1).await?;
+            mergebox.insert(x(), json!(1), &s(), &a2).await?;
+            mergebox.remove(x(), json!(1), &s(), &a1).await?;
+        }
+
+        // Only the latest values are referenced by all mergeboxes.
+        assert_eq!(Arc::strong_count(&a1.0[0].1), 1);
+        assert_eq!(Arc::strong_count(&a1.0[1].1), 1);
+        assert_eq!(Arc::strong_count(&a2.0[0].1), 4);
+        assert_eq!(Arc::strong_count(&a2.0[1].1), 4);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with `cargo test --release -- --ignored --nocapture`"]
+    async fn benchmark_50k() -> Result<(), Error> {
+        let (sender, mut receiver) = Outbox::new(None);
+        let drain = tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+        let mut mergebox = Mergebox::new(sender, Inconsistencies::Strict, Arc::default());
+        let documents: Vec<_> = (0..50_000)
+            .map(|id| {
+                (
+                    json!({"$type": "oid", "$value": format!("{id:024x}")}),
+                    SharedDocument::from(document(json!({"a": id}))),
+                )
+            })
+            .collect();
+
+        // Documents used to be kept in a `Vec` and looked up by `_id` linearly.
+        // It's quadratic, so only the first 10k are used (50k took minutes).
+        let start = Instant::now();
+        let mut linear: Vec<(&Value, &SharedDocument)> = vec![];
+        for (id, document) in &documents[..10_000] {
+            if !linear.iter().any(|(x, _)| *x == id) {
+                linear.push((id, document));
+            }
+        }
+        for (id, _) in &documents[..10_000] {
+            if let Some(index) = linear.iter().position(|(x, _)| *x == id) {
+                linear.swap_remove(index);
+            }
+        }
+        let linear = start.elapsed();
+
+        let start = Instant::now();
+        let s = s();
+        for (id, document) in &documents {
+            mergebox.insert(x(), id.clone(), &s, document).await?;
+        }
+        for (id, document) in &documents {
+            mergebox.remove(x(), id.clone(), &s, document).await?;
+        }
+        mergebox.flush().await?;
+
+        println!(
+            "50k inserts and removes took {:?} ({linear:?} for 10k with linear lookups)",
+            start.elapsed()
+        );
+        drop(mergebox);
+        drain.await?;
+        Ok(())
     }
 }
@@ -0,0 +1,298 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// A BSON number encoded as EJSON (see `into_ejson`). Keeping the type lets us
+/// support `$type` properly and compare `long`s and `decimal`s exactly.
+#[derive(Clone, Copy, Debug)]
+pub enum Numeric<'a> {
+    Decimal(&'a str),
+    Double(f64),
+    Int(i64),
+    Long(i64),
+}
+
+impl<'a> Numeric<'a> {
+    pub fn as_i64_truncated(&self) -> Option<i64> {
+        match *self {
+            Self::Int(number) | Self::Long(number) => Some(number),
+            Self::Double(number) if number.is_finite() => Some(number.trunc() as i64),
+            Self::Double(_) => None,
+            Self::Decimal(_) => match self.exact() {
+                Exact::Finite(decimal) => decimal.trunc(),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn cmp(&self, other: &Self) -> Ordering {
+        match (*self, *other) {
+            (Self::Int(lhs) | Self::Long(lhs), Self::Int(rhs) | Self::Long(rhs)) => lhs.cmp(&rhs),
+            (Self::Double(lhs), Self::Double(rhs)) => cmp_f64(lhs, rhs),
+            (Self::Int(lhs) | Self::Long(lhs), Self::Double(rhs)) => cmp_i64_f64(lhs, rhs),
+            (Self::Double(lhs), Self::Int(rhs) | Self::Long(rhs)) => {
+                cmp_i64_f64(rhs, lhs).reverse()
+            }
+            _ => self.exact().cmp(&other.exact()),
+        }
+    }
+
+    fn exact(&self) -> Exact {
+        match *self {
+            Self::Decimal(decimal) => Exact::parse(decimal).unwrap_or(Exact::NaN),
+            Self::Double(number) if number.is_nan() => Exact::NaN,
+            Self::Double(number) if number.is_infinite() => {
+                if number.is_sign_positive() {
+                    Exact::PositiveInfinity
+                } else {
+                    Exact::NegativeInfinity
+                }
+            }
+            // Every finite `f64` has an exact decimal representation of at
+            // most 767 significant digits.
+            Self::Double(number) => Exact::parse(&format!("{number:.767e}")).unwrap(),
+            Self::Int(number) | Self::Long(number) => Exact::parse(&number.to_string()).unwrap(),
+        }
+    }
+
+    pub fn from_value(value: &'a Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => Some(match number.as_i64() {
+                Some(integer) if !number.is_f64() => Self::Int(integer),
+                _ => Self::Double(number.as_f64()?),
+            }),
+            Value::Object(object) if object.len() == 1 => match object.get("$InfNaN")?.as_i64()? {
+                1 => Some(Self::Double(f64::INFINITY)),
+                -1 => Some(Self::Double(f64::NEG_INFINITY)),
+                _ => Some(Self::Double(f64::NAN)),
+            },
+            Value::Object(object) if object.len() == 2 => {
+                match (object.get("$type")?.as_str()?, object.get("$value")?) {
+                    ("Decimal", Value::String(value)) => Some(Self::Decimal(value)),
+                    ("long", Value::Number(value)) => Some(Self::Long(value.as_i64()?)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// <https://www.mongodb.com/docs/manual/reference/operator/query/type/#available-types>
+    pub fn value_type(&self) -> i8 {
+        match self {
+            Self::Decimal(_) => 19,
+            Self::Double(_) => 1,
+            Self::Int(_) => 16,
+            Self::Long(_) => 18,
+        }
+    }
+}
+
+/// MongoDB treats `NaN` as equal to itself and lower than any other number.
+fn cmp_f64(lhs: f64, rhs: f64) -> Ordering {
+    match (lhs.is_nan(), rhs.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => lhs.partial_cmp(&rhs).unwrap(),
+    }
+}
+
+fn cmp_i64_f64(lhs: i64, rhs: f64) -> Ordering {
+    // 2^63 is exactly representable, unlike `i64::MAX`.
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if rhs.is_nan() {
+        Ordering::Greater
+    } else if rhs >= LIMIT {
+        Ordering::Less
+    } else if rhs < -LIMIT {
+        Ordering::Greater
+    } else {
+        lhs.cmp(&(rhs.trunc() as i64))
+            .then_with(|| 0.0.partial_cmp(&rhs.fract()).unwrap())
+    }
+}
+
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Exact {
+    NaN,
+    NegativeInfinity,
+    Finite(Decimal),
+    PositiveInfinity,
+}
+
+impl Exact {
+    fn parse(string: &str) -> Option<Self> {
+        let (is_negative, string) = match string.strip_prefix('-') {
+            Some(string) => (true, string),
+            None => (false, string.strip_prefix('+').unwrap_or(string)),
+        };
+
+        match string {
+            "NaN" => return Some(Self::NaN),
+            "Infinity" | "inf" if is_negative => return Some(Self::NegativeInfinity),
+            "Infinity" | "inf" => return Some(Self::PositiveInfinity),
+            _ => {}
+        }
+
+        let (mantissa, exponent) = match string.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+            None => (string, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if !integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|x| x.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let digits = format!("{integer}{fraction}");
+        let leading_zeros = digits.len() - digits.trim_start_matches('0').len();
+        let digits = digits.trim_matches('0').as_bytes().to_vec();
+        Some(Self::Finite(if digits.is_empty() {
+            Decimal::default()
+        } else {
+            Decimal {
+                exponent: exponent + integer.len() as i64 - leading_zeros as i64,
+                is_negative,
+                digits,
+            }
+        }))
+    }
+}
+
+/// An arbitrary precision decimal: `0.{digits} * 10^{exponent}`. The digits
+/// have no leading nor trailing zeros, i.e., zero has no digits at all.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Decimal {
+    digits: Vec<u8>,
+    exponent: i64,
+    is_negative: bool,
+}
+
+impl Decimal {
+    fn signum(&self) -> i8 {
+        match (self.digits.is_empty(), self.is_negative) {
+            (true, _) => 0,
+            (false, true) => -1,
+            (false, false) => 1,
+        }
+    }
+
+    fn trunc(&self) -> Option<i64> {
+        let Ok(length) = usize::try_from(self.exponent) else {
+            return Some(0);
+        };
+
+        // It would overflow anyway.
+        if length > 19 {
+            return None;
+        }
+
+        let mut integer = String::from(if self.is_negative { "-0" } else { "0" });
+        for index in 0..length {
+            integer.push(char::from(*self.digits.get(index).unwrap_or(&b'0')));
+        }
+
+        integer.parse().ok()
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ordering = self.signum().cmp(&other.signum());
+        if ordering.is_ne() || self.digits.is_empty() {
+            return ordering;
+        }
+
+        let ordering = self
+            .exponent
+            .cmp(&other.exponent)
+            .then_with(|| self.digits.cmp(&other.digits));
+        if self.is_negative {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Numeric;
+    use crate::ejson::into_ejson;
+    use bson::{Bson, Decimal128};
+    use std::cmp::Ordering;
+
+    macro_rules! test {
+        ($name:ident, $lhs:expr, $rhs:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let lhs = into_ejson(Bson::from($lhs));
+                let rhs = into_ejson(Bson::from($rhs));
+                let lhs = Numeric::from_value(&lhs).unwrap();
+                let rhs = Numeric::from_value(&rhs).unwrap();
+                assert_eq!(lhs.cmp(&rhs), $expected);
+                assert_eq!(rhs.cmp(&lhs), $expected.reverse());
+            }
+        };
+    }
+
+    macro_rules! eq {
+        ($name:ident, $lhs:expr, $rhs:expr) => {
+            test!($name, $lhs, $rhs, Ordering::Equal);
+        };
+    }
+    macro_rules! lt {
+        ($name:ident, $lhs:expr, $rhs:expr) => {
+            test!($name, $lhs, $rhs, Ordering::Less);
+        };
+    }
+
+    fn decimal(string: &str) -> Decimal128 {
+        string.parse().unwrap()
+    }
+
+    eq!(int_double, 1, 1.0);
+    eq!(int_long, 1, 1_i64);
+    lt!(int_double_fraction, 1, 1.5);
+    lt!(
+        long_exact,
+        9_007_199_254_740_992_i64,
+        9_007_199_254_740_993_i64
+    );
+    lt!(
+        long_double_exact,
+        9_007_199_254_740_992.0,
+        9_007_199_254_740_993_i64
+    );
+    lt!(long_double_limit, i64::MAX, 9_223_372_036_854_775_808.0);
+    eq!(decimal_int, decimal("1.000"), 1);
+    eq!(decimal_exponent, decimal("1.5E+3"), 1500);
+    lt!(decimal_double_exact, decimal("0.1"), 0.1);
+    lt!(decimal_negative, decimal("-2.5"), decimal("-2.4"));
+    lt!(decimal_zero, decimal("-0"), decimal("0.0001"));
+    eq!(decimal_zeros, decimal("-0"), 0.0);
+    lt!(decimal_long, decimal("9223372036854775806.5"), i64::MAX);
+    lt!(nan_infinity, f64::NAN, f64::NEG_INFINITY);
+    eq!(nan_decimal, f64::NAN, decimal("NaN"));
+    lt!(infinity_decimal, decimal("1E+6000"), f64::INFINITY);
+
+    #[test]
+    fn truncated() {
+        let value = into_ejson(Bson::from(decimal("-12.75")));
+        let value = Numeric::from_value(&value).unwrap();
+        assert_eq!(value.as_i64_truncated(), Some(-12));
+
+        let value = into_ejson(Bson::from(12.75));
+        let value = Numeric::from_value(&value).unwrap();
+        assert_eq!(value.as_i64_truncated(), Some(12));
+    }
+}
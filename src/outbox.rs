@@ -0,0 +1,137 @@
+use crate::ddp::DDPMessage;
+use crate::settings::SlowClients;
+use anyhow::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+/// Messages waiting to be written to the client. Unlike a bounded channel, it
+/// never blocks the writer (e.g., a cursor shared with other sessions), so the
+/// `Mergebox` checks the (optional) limit instead and handles slow clients.
+#[derive(Clone)]
+pub struct Outbox {
+    limit: Option<(usize, SlowClients)>,
+    shared: Arc<Shared>,
+    sender: UnboundedSender<DDPMessage>,
+}
+
+pub struct OutboxReader {
+    receiver: UnboundedReceiver<DDPMessage>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    /// Notified when the client is too slow or when it caught up.
+    events: Notify,
+    pending: AtomicUsize,
+}
+
+impl Outbox {
+    /// Whether the client fell behind and how it should be handled (if so).
+    pub fn is_overflowing(&self) -> Option<SlowClients> {
+        let (limit, slow_clients) = self.limit?;
+        (self.pending() > limit).then_some(slow_clients)
+    }
+
+    pub fn new(limit: Option<(usize, SlowClients)>) -> (Self, OutboxReader) {
+        let (sender, receiver) = unbounded_channel();
+        let shared = Arc::new(Shared {
+            events: Notify::new(),
+            pending: AtomicUsize::new(0),
+        });
+
+        let outbox = Self {
+            limit,
+            shared: shared.clone(),
+            sender,
+        };
+        (outbox, OutboxReader { receiver, shared })
+    }
+
+    pub fn notify(&self) {
+        self.shared.events.notify_one();
+    }
+
+    pub async fn notified(&self) {
+        self.shared.events.notified().await;
+    }
+
+    pub fn pending(&self) -> usize {
+        self.shared.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn send(&self, message: DDPMessage) -> Result<(), Error> {
+        self.shared.pending.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(message)?;
+        Ok(())
+    }
+}
+
+impl OutboxReader {
+    pub async fn recv(&mut self) -> Option<DDPMessage> {
+        let message = self.receiver.recv().await;
+        self.received(message.is_some());
+        message
+    }
+
+    pub fn try_recv(&mut self) -> Result<DDPMessage, TryRecvError> {
+        let message = self.receiver.try_recv();
+        self.received(message.is_ok());
+        message
+    }
+
+    fn received(&self, is_received: bool) {
+        if is_received && self.shared.pending.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.shared.events.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outbox;
+    use crate::ddp::DDPMessage;
+    use crate::settings::SlowClients;
+    use anyhow::Error;
+    use tokio::test;
+
+    fn ready() -> DDPMessage {
+        DDPMessage::Ready { subs: vec![] }
+    }
+
+    #[test]
+    async fn unlimited() -> Result<(), Error> {
+        let (outbox, mut reader) = Outbox::new(None);
+        for _ in 0..10_000 {
+            outbox.send(ready())?;
+        }
+
+        assert_eq!(outbox.pending(), 10_000);
+        assert!(outbox.is_overflowing().is_none());
+        while reader.try_recv().is_ok() {}
+        assert_eq!(outbox.pending(), 0);
+        Ok(())
+    }
+
+    #[test]
+    async fn limited() -> Result<(), Error> {
+        let (outbox, mut reader) = Outbox::new(Some((2, SlowClients::Resync)));
+        for _ in 0..3 {
+            assert!(outbox.is_overflowing().is_none());
+            outbox.send(ready())?;
+        }
+
+        assert!(matches!(outbox.is_overflowing(), Some(SlowClients::Resync)));
+
+        // Caught up.
+        for _ in 0..3 {
+            assert_eq!(reader.recv().await, Some(ready()));
+        }
+
+        outbox.notified().await;
+        assert!(outbox.is_overflowing().is_none());
+        Ok(())
+    }
+}
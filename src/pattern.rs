@@ -0,0 +1,150 @@
+use anyhow::{anyhow, Error};
+use regex::RegexBuilder;
+
+/// A compiled `$regex`. MongoDB uses PCRE, so we try to stay as close to it as
+/// possible: patterns are first compiled with [`regex`] (linear time, no
+/// lookarounds nor backreferences) and, if that fails and the `pcre` feature
+/// is enabled, with a backtracking engine instead.
+#[derive(Debug)]
+pub enum Pattern {
+    Linear(regex::Regex),
+    #[cfg(feature = "pcre")]
+    Backtracking(Box<fancy_regex::Regex>),
+}
+
+impl Pattern {
+    pub fn compile(pattern: &str, options: &str) -> Result<Self, Error> {
+        let mut flags = String::new();
+        for flag in options.chars() {
+            match flag {
+                'i' | 'm' | 's' | 'x' => flags.push(flag),
+                // MongoDB accepts it, but it's redundant (UTF-8 is always on)
+                // and it does not make the classes Unicode-aware.
+                'u' => {}
+                flag => return Err(anyhow!("Unknown $regex flag {flag}")),
+            }
+        }
+
+        let pattern = translate(pattern);
+        let linear_error = match RegexBuilder::new(&pattern)
+            .case_insensitive(flags.contains('i'))
+            .multi_line(flags.contains('m'))
+            .dot_matches_new_line(flags.contains('s'))
+            .ignore_whitespace(flags.contains('x'))
+            .build()
+        {
+            Ok(regex) => return Ok(Self::Linear(regex)),
+            Err(error) => error,
+        };
+
+        #[cfg(feature = "pcre")]
+        {
+            // `fancy_regex` has no builder options for flags, but it does
+            // understand the inline ones.
+            let pattern = if flags.is_empty() {
+                pattern
+            } else {
+                format!("(?{flags}){pattern}")
+            };
+
+            if let Ok(regex) = fancy_regex::Regex::new(&pattern) {
+                return Ok(Self::Backtracking(Box::new(regex)));
+            }
+        }
+
+        Err(linear_error.into())
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Self::Linear(regex) => regex.is_match(text),
+            #[cfg(feature = "pcre")]
+            Self::Backtracking(regex) => regex.is_match(text).unwrap_or_else(|error| {
+                // Most likely the backtrack limit was exceeded. MongoDB would
+                // fail the query, but we can only treat it as a mismatch.
+                println!("\x1b[0;31m[[ERROR]] $regex {regex} failed: {error}\x1b[0m");
+                false
+            }),
+        }
+    }
+}
+
+/// PCRE (as configured by MongoDB) uses ASCII-only Perl classes, while
+/// [`regex`] makes them Unicode-aware, e.g., `\d` matches `"٣"`. Rewrite them
+/// into their ASCII equivalents understood by both engines.
+fn translate(pattern: &str) -> String {
+    let mut translated = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    let mut is_in_class = false;
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                let Some(escaped) = chars.next() else {
+                    translated.push(char);
+                    break;
+                };
+
+                let class = match escaped {
+                    'd' => Some(":digit:"),
+                    'D' => Some(":^digit:"),
+                    's' => Some(":space:"),
+                    'S' => Some(":^space:"),
+                    'w' => Some(":word:"),
+                    'W' => Some(":^word:"),
+                    _ => None,
+                };
+
+                match (class, escaped) {
+                    (Some(class), _) if is_in_class => {
+                        translated.push('[');
+                        translated.push_str(class);
+                        translated.push(']');
+                    }
+                    (Some(class), _) => {
+                        translated.push_str("[[");
+                        translated.push_str(class);
+                        translated.push_str("]]");
+                    }
+                    (None, 'b' | 'B') if !is_in_class => {
+                        translated.push_str("(?-u:\\");
+                        translated.push(escaped);
+                        translated.push(')');
+                    }
+                    (None, _) => {
+                        translated.push(char);
+                        translated.push(escaped);
+                    }
+                }
+            }
+            '[' if !is_in_class => {
+                is_in_class = true;
+                translated.push(char);
+
+                // A leading `]` (possibly after `^`) is a literal.
+                if chars.peek() == Some(&'^') {
+                    translated.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    translated.push(chars.next().unwrap());
+                }
+            }
+            '[' if chars.peek() == Some(&':') => {
+                // POSIX class inside of a class, e.g., `[[:alpha:]]`.
+                translated.push(char);
+                for char in chars.by_ref() {
+                    translated.push(char);
+                    if char == ']' {
+                        break;
+                    }
+                }
+            }
+            ']' if is_in_class => {
+                is_in_class = false;
+                translated.push(char);
+            }
+            _ => translated.push(char),
+        }
+    }
+
+    translated
+}
@@ -1,5 +1,8 @@
+use crate::collation::Collation;
+use crate::expression::Expression;
+use crate::matcher::DocumentMatcher;
 use anyhow::{anyhow, Error};
-use bson::{Bson, Document};
+use bson::{doc, Bson, Document};
 use serde_json::{Map, Value};
 use std::collections::BTreeMap;
 
@@ -7,41 +10,89 @@ use std::collections::BTreeMap;
 pub struct Projector(Tree, bool);
 
 impl Projector {
-    pub fn apply(&self, document: &mut Map<String, Value>) {
+    /// Fails if a computed field fails, like MongoDB would fail the query.
+    pub fn apply(&self, document: &mut Map<String, Value>) -> Result<(), Error> {
+        // Computed fields see the document before projection.
+        let computed = self.0.evaluate(document)?;
         self.0.apply_document(document, self.1);
+        document.extend(computed);
+        Ok(())
     }
 
-    pub fn compile(projection: Option<&Document>) -> Result<Self, Error> {
+    pub fn compile(
+        projection: Option<&Document>,
+        selector: &Document,
+        collation: Option<&Collation>,
+    ) -> Result<Self, Error> {
         let mut tree = Tree::default();
         let mut include_all = None;
         let mut include_id = None;
+        let mut has_positional = false;
 
         for (path, operator) in projection.into_iter().flatten() {
-            let include = match operator {
-                Bson::Boolean(boolean) => *boolean,
-                Bson::Int32(1) => true,
-                Bson::Int32(0) => false,
-                operator => {
+            let (path, include, subtree) = match (path.strip_suffix(".$"), operator) {
+                (Some(_), _) if has_positional => {
+                    return Err(anyhow!("Projection allows only one positional operator"))
+                }
+                (Some(path), Bson::Boolean(true) | Bson::Int32(1)) => {
+                    has_positional = true;
+                    let condition = Condition::compile_positional(path, selector, collation)?;
+                    (path, Some(true), Tree::Positional(condition))
+                }
+                (_, Bson::Boolean(boolean)) => (path.as_str(), Some(*boolean), Tree::Leaf),
+                (_, Bson::Int32(1)) => (path.as_str(), Some(true), Tree::Leaf),
+                (_, Bson::Int32(0)) => (path.as_str(), Some(false), Tree::Leaf),
+                (_, Bson::Document(document)) if document.len() == 1 => {
+                    let (name, operand) = document.iter().next().unwrap();
+                    match (name.as_str(), operand) {
+                        ("$elemMatch", Bson::Document(condition)) if !path.contains('.') => {
+                            let condition =
+                                Condition::compile_elem_match(path, condition, collation)?;
+                            (path.as_str(), Some(true), Tree::ElemMatch(condition))
+                        }
+                        ("$slice", operand) => (path.as_str(), None, Tree::compile_slice(operand)?),
+                        _ => (
+                            path.as_str(),
+                            Some(true),
+                            Tree::compile_computed(path, operator, collation)?,
+                        ),
+                    }
+                }
+                (_, Bson::Array(_) | Bson::Null | Bson::String(_)) => (
+                    path.as_str(),
+                    Some(true),
+                    Tree::compile_computed(path, operator, collation)?,
+                ),
+                (_, operator) => {
                     return Err(anyhow!("Projection {operator} for {path} is not supported"))
                 }
             };
 
+            if path.contains('$') {
+                return Err(anyhow!("Projection {path} is not supported"));
+            }
+
             // _id is special.
-            if path == "_id" {
-                include_id = Some(include);
+            if path == "_id" && matches!(subtree, Tree::Leaf) {
+                include_id = include;
                 continue;
             }
 
-            match include_all {
-                Some(include_all) => {
-                    if include_all != include {
-                        return Err(anyhow!("Projection cannot be both exclusive and inclusive"));
+            // `$slice` works with both inclusive and exclusive projections.
+            if let Some(include) = include {
+                match include_all {
+                    Some(include_all) => {
+                        if include_all != include {
+                            return Err(anyhow!(
+                                "Projection cannot be both exclusive and inclusive"
+                            ));
+                        }
                     }
+                    None => include_all = Some(include),
                 }
-                None => include_all = Some(include),
             }
 
-            tree.add(path.as_str());
+            tree.add(path, subtree)?;
         }
 
         match (include_all, include_id) {
@@ -51,41 +102,174 @@ impl Projector {
         }
 
         if include_all == include_id {
-            tree.add("_id");
+            tree.add("_id", Tree::Leaf)?;
         }
 
         Ok(Self(tree, include_all.unwrap_or(false)))
     }
 }
 
+/// Selects the first matching array element, e.g., for `$elemMatch`.
+#[derive(Debug)]
+enum Condition {
+    /// Query matched against the element itself (has to be a document).
+    Document(DocumentMatcher),
+    /// Query matched against `{path: element}`.
+    Value(DocumentMatcher, String),
+}
+
+impl Condition {
+    fn compile_elem_match(
+        path: &str,
+        condition: &Document,
+        collation: Option<&Collation>,
+    ) -> Result<Self, Error> {
+        if condition
+            .keys()
+            .next()
+            .is_some_and(|key| key.starts_with('$'))
+        {
+            let selector = doc! { path: condition.clone() };
+            let matcher = DocumentMatcher::compile(&selector, collation)?;
+            Ok(Self::Value(matcher, path.to_owned()))
+        } else {
+            let matcher = DocumentMatcher::compile(condition, collation)?;
+            Ok(Self::Document(matcher))
+        }
+    }
+
+    /// Positional projection relies on the query conditions on the array
+    /// (i.e., `path` itself and its subpaths) and picks the first element that
+    /// matches all of them.
+    fn compile_positional(
+        path: &str,
+        selector: &Document,
+        collation: Option<&Collation>,
+    ) -> Result<Self, Error> {
+        fn collect(path: &str, selector: &Document, conditions: &mut Vec<Bson>) {
+            for (key, value) in selector {
+                if key == "$and" {
+                    for selector in value.as_array().into_iter().flatten() {
+                        if let Some(selector) = selector.as_document() {
+                            collect(path, selector, conditions);
+                        }
+                    }
+                } else if key == path || key.strip_prefix(path).is_some_and(|x| x.starts_with('.'))
+                {
+                    conditions.push(Bson::Document(doc! { key: value.clone() }));
+                }
+            }
+        }
+
+        let mut conditions = vec![];
+        collect(path, selector, &mut conditions);
+        if conditions.is_empty() {
+            return Err(anyhow!(
+                "Positional projection of {path} requires a query on it"
+            ));
+        }
+
+        let selector = doc! { "$and": conditions };
+        let matcher = DocumentMatcher::compile(&selector, collation)?;
+        Ok(Self::Value(matcher, path.to_owned()))
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            Self::Document(matcher) => value
+                .as_object()
+                .is_some_and(|document| matcher.matches(document)),
+            Self::Value(matcher, path) => {
+                let mut value = value.clone();
+                for key in path.rsplit('.') {
+                    value = Value::Object(Map::from_iter([(key.to_owned(), value)]));
+                }
+
+                let Value::Object(document) = value else {
+                    unreachable!()
+                };
+                matcher.matches(&document)
+            }
+        }
+    }
+
+    /// Returns `false` if the field should be removed.
+    fn retain_first(&self, value: &mut Value) -> bool {
+        let Value::Array(values) = value else {
+            return false;
+        };
+
+        match values.iter().position(|value| self.matches(value)) {
+            Some(index) => {
+                *values = vec![values.swap_remove(index)];
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 enum Tree {
     #[default]
     Leaf,
     Node(BTreeMap<String, Tree>),
+    /// Aggregation expression, only in top-level fields.
+    Computed(Expression),
+    ElemMatch(Condition),
+    Positional(Condition),
+    /// `$slice` as `skip` and `limit`.
+    Slice(i64, Option<i64>),
 }
 
 impl Tree {
-    fn add(&mut self, key: &str) {
+    /// Fails if `key` collides with an operator (e.g., `$slice`) on the same
+    /// path, as one of them would be silently lost otherwise.
+    fn add(&mut self, key: &str, subtree: Self) -> Result<(), Error> {
         let map = match self {
+            Self::Node(map) => map,
             Self::Leaf => {
                 *self = Self::Node(BTreeMap::new());
-                return self.add(key);
+                return self.add(key, subtree);
             }
-            Self::Node(map) => map,
+            _ => return Err(anyhow!("Projection has a path collision at {key}")),
         };
 
         if let Some((key, path)) = key.split_once('.') {
-            map.entry(key.to_owned()).or_default().add(path);
-        } else {
+            map.entry(key.to_owned()).or_default().add(path, subtree)?;
+        } else if let Self::Leaf = subtree {
             map.entry(key.to_owned()).or_default();
+        } else if map.insert(key.to_owned(), subtree).is_some() {
+            return Err(anyhow!("Projection has a path collision at {key}"));
         }
+
+        Ok(())
     }
 
     fn apply_document(&self, document: &mut Map<String, Value>, include: bool) {
         if let Self::Node(map) = self {
             document.retain(|key, value| match map.get(key) {
                 Some(Self::Leaf) => include,
+                // Replaced in `Projector::apply`.
+                Some(Self::Computed(_)) => false,
+                Some(Self::ElemMatch(condition)) => condition.retain_first(value),
+                // Non-array values are left as they are.
+                Some(Self::Positional(condition)) => {
+                    !value.is_array() || condition.retain_first(value)
+                }
+                Some(Self::Slice(skip, limit)) => {
+                    if let Value::Array(values) = value {
+                        let length = values.len() as i64;
+                        let start = if *skip < 0 {
+                            (length + skip).max(0)
+                        } else {
+                            (*skip).min(length)
+                        };
+                        let end = limit.map_or(length, |limit| (start + limit).min(length));
+                        *values = values.drain(start as usize..end as usize).collect();
+                    }
+                    true
+                }
                 Some(tree) => {
                     tree.apply_value(value, include);
                     true
@@ -95,6 +279,24 @@ impl Tree {
         }
     }
 
+    fn evaluate(&self, document: &Map<String, Value>) -> Result<Vec<(String, Value)>, Error> {
+        let Self::Node(map) = self else {
+            return Ok(vec![]);
+        };
+
+        let mut computed = vec![];
+        for (key, tree) in map {
+            if let Self::Computed(expression) = tree {
+                let value = expression
+                    .evaluate(document)
+                    .map_err(|error| anyhow!("Projection of {key} failed: {error}"))?;
+                computed.extend(value.map(|value| (key.clone(), value)));
+            }
+        }
+
+        Ok(computed)
+    }
+
     fn apply_value(&self, value: &mut Value, include: bool) {
         match value {
             Value::Array(values) => values
@@ -104,6 +306,48 @@ impl Tree {
             _ => {}
         }
     }
+
+    fn compile_computed(
+        path: &str,
+        expression: &Bson,
+        collation: Option<&Collation>,
+    ) -> Result<Self, Error> {
+        if path == "_id" || path.contains('.') {
+            return Err(anyhow!(
+                "Projection {expression} for {path} is not supported"
+            ));
+        }
+
+        Ok(Self::Computed(Expression::compile(expression, collation)?))
+    }
+
+    fn compile_slice(operand: &Bson) -> Result<Self, Error> {
+        let parse = |value: &Bson| -> Result<i64, Error> {
+            Ok(match value {
+                Bson::Double(n) if n.fract() == 0.0 => *n as i64,
+                Bson::Int32(n) => *n as i64,
+                Bson::Int64(n) => *n,
+                value => return Err(anyhow!("$slice expected a number, got {value:?}")),
+            })
+        };
+
+        match operand {
+            Bson::Array(operands) if operands.len() == 2 => {
+                let skip = parse(&operands[0])?;
+                let limit = parse(&operands[1])?;
+                if limit <= 0 {
+                    return Err(anyhow!("$slice limit must be positive, got {limit}"));
+                }
+
+                Ok(Self::Slice(skip, Some(limit)))
+            }
+            Bson::Array(_) => Err(anyhow!("$slice expected 2 arguments, got {operand:?}")),
+            operand => match parse(operand)? {
+                limit if limit < 0 => Ok(Self::Slice(limit, None)),
+                limit => Ok(Self::Slice(0, Some(limit))),
+            },
+        }
+    }
 }
 
 #[cfg(test)]
@@ -113,9 +357,10 @@ mod tests {
     use serde_json::{json, Value};
 
     macro_rules! test {
-        ($name:ident, { $($projection:tt)* }, { $($input:tt)* }, { $($output:tt)* }) => {
+        ($name:ident, { $($selector:tt)* }, { $($projection:tt)* }, { $($input:tt)* }, { $($output:tt)* }) => {
             #[test]
             fn $name() {
+                let selector = doc! { $($selector)* };
                 let projection = doc! { $($projection)* };
                 let input = json! {{ $($input)* }};
                 let output = json! {{ $($output)* }};
@@ -124,15 +369,50 @@ mod tests {
                 let Value::Object(mut input) = input else { unreachable!() };
                 let Value::Object(output) = output else { unreachable!() };
 
-                let projector = match Projector::compile(projection) {
+                let projector = match Projector::compile(projection, &selector, None) {
                     Ok(projector) => projector,
                     Err(error) => panic!("{projection:?} is not supported: {error:?}"),
                 };
 
-                projector.apply(&mut input);
+                if let Err(error) = projector.apply(&mut input) {
+                    panic!("{projection:?} failed: {error:?}");
+                }
+
                 assert_eq!(input, output);
             }
         };
+        ($name:ident, { $($projection:tt)* }, { $($input:tt)* }, { $($output:tt)* }) => {
+            test!($name, {}, { $($projection)* }, { $($input)* }, { $($output)* });
+        };
+    }
+
+    macro_rules! error {
+        ($name:ident, { $($projection:tt)* }, { $($input:tt)* }) => {
+            #[test]
+            fn $name() {
+                let projection = doc! { $($projection)* };
+                let input = json! {{ $($input)* }};
+                let Value::Object(mut input) = input else { unreachable!() };
+
+                let projector = Projector::compile(Some(&projection), &doc! {}, None).unwrap();
+                if projector.apply(&mut input).is_ok() {
+                    panic!("{projection:?} should fail on {input:?}");
+                }
+            }
+        };
+    }
+
+    macro_rules! fail {
+        ($name:ident, { $($selector:tt)* }, { $($projection:tt)* }) => {
+            #[test]
+            fn $name() {
+                let selector = doc! { $($selector)* };
+                let projection = doc! { $($projection)* };
+                if Projector::compile(Some(&projection), &selector, None).is_ok() {
+                    panic!("{projection:?} should not be supported");
+                }
+            }
+        };
     }
 
     test!(empty, {}, {"a": 7, "b": 8}, {"a": 7, "b": 8});
@@ -162,4 +442,56 @@ mod tests {
     test!(nested_09, {"a.b.c": 1}, {"a": {"b": [{"c": 7}]}}, {"a": {"b": [{"c": 7}]}});
     test!(nested_10, {"a.b.c": 1}, {"a": [{"b": {"c": 7}}]}, {"a": [{"b": {"c": 7}}]});
     test!(nested_11, {"a.b.c": 0}, {"a": {"b": {"c": 7}, "c": 8}, "d": 9}, {"a": {"b": {}, "c": 8}, "d": 9});
+
+    test!(slice_01, {"a": {"$slice": 2}}, {"a": [1, 2, 3], "b": 4}, {"a": [1, 2], "b": 4});
+    test!(slice_02, {"a": {"$slice": -2}}, {"a": [1, 2, 3]}, {"a": [2, 3]});
+    test!(slice_03, {"a": {"$slice": 5}}, {"a": [1, 2, 3]}, {"a": [1, 2, 3]});
+    test!(slice_04, {"a": {"$slice": -5}}, {"a": [1, 2, 3]}, {"a": [1, 2, 3]});
+    test!(slice_05, {"a": {"$slice": 0}}, {"a": [1, 2, 3]}, {"a": []});
+    test!(slice_06, {"a": {"$slice": [1, 1]}}, {"a": [1, 2, 3]}, {"a": [2]});
+    test!(slice_07, {"a": {"$slice": [-2, 1]}}, {"a": [1, 2, 3]}, {"a": [2]});
+    test!(slice_08, {"a": {"$slice": [-5, 2]}}, {"a": [1, 2, 3]}, {"a": [1, 2]});
+    test!(slice_09, {"a": {"$slice": [5, 2]}}, {"a": [1, 2, 3]}, {"a": []});
+    test!(slice_10, {"a": {"$slice": 1}}, {"a": 7}, {"a": 7});
+    test!(slice_11, {"a": {"$slice": 1}, "b": 1}, {"a": [1, 2], "b": 3, "c": 4}, {"a": [1], "b": 3});
+    test!(slice_12, {"a": {"$slice": 1}, "b": 0}, {"a": [1, 2], "b": 3, "c": 4}, {"a": [1], "c": 4});
+    test!(slice_13, {"a.b": {"$slice": 1}}, {"a": [{"b": [1, 2]}, {"b": [3, 4]}]}, {"a": [{"b": [1]}, {"b": [3]}]});
+    fail!(slice_14, {}, {"a": {"$slice": [1, 0]}});
+    fail!(slice_15, {}, {"a": {"$slice": [1]}});
+    fail!(slice_16, {}, {"a": {"$slice": "1"}});
+    fail!(slice_17, {}, {"a": {"$slice": 2}, "a.b": 1});
+    fail!(slice_18, {}, {"a.b": 1, "a": {"$slice": 2}});
+
+    test!(elem_match_1, {"a": {"$elemMatch": {"b": 2}}}, {"a": [{"b": 1}, {"b": 2, "c": 1}, {"b": 2, "c": 2}], "d": 1}, {"a": [{"b": 2, "c": 1}]});
+    test!(elem_match_2, {"a": {"$elemMatch": {"b": 3}}}, {"a": [{"b": 1}, {"b": 2}]}, {});
+    test!(elem_match_3, {"a": {"$elemMatch": {"$gt": 1}}}, {"a": [1, 2, 3]}, {"a": [2]});
+    test!(elem_match_4, {"a": {"$elemMatch": {"b": 1}}}, {"a": {"b": 1}}, {});
+    test!(elem_match_5, {"a": {"$elemMatch": {"b": 1}}, "c": 1}, {"a": [{"b": 1}], "c": 2, "d": 3}, {"a": [{"b": 1}], "c": 2});
+    fail!(elem_match_6, {}, {"a": {"$elemMatch": {"b": 1}}, "c": 0});
+    fail!(elem_match_7, {}, {"a.b": {"$elemMatch": {"c": 1}}});
+    fail!(elem_match_8, {}, {"a": {"$elemMatch": {"b": 1}}, "a.c": 1});
+
+    test!(positional_1, {"a": {"$gt": 1}}, {"a.$": 1}, {"a": [1, 2, 3], "b": 4}, {"a": [2]});
+    test!(positional_2, {"a.b": 2}, {"a.$": 1}, {"a": [{"b": 1}, {"b": 2, "c": 3}]}, {"a": [{"b": 2, "c": 3}]});
+    test!(positional_3, {"$and": [{"a": {"$gte": 2}}, {"a": {"$lt": 3}}]}, {"a.$": 1, "b": 1}, {"a": [1, 2, 3], "b": 4, "c": 5}, {"a": [2], "b": 4});
+    test!(positional_4, {"a": 7}, {"a.$": true}, {"a": 7}, {"a": 7});
+    test!(positional_5, {"a.b": {"$in": [2, 3]}}, {"a.b.$": 1}, {"a": {"b": [1, 2, 3]}}, {"a": {"b": [2]}});
+    fail!(positional_6, {"b": 1}, {"a.$": 1});
+    fail!(positional_7, {"a": 1}, {"a.$": 0});
+    fail!(positional_8, {"a": 1, "b": 1}, {"a.$": 1, "b.$": 1});
+    fail!(positional_9, {"a.b": 1}, {"a.$.b": 1});
+    fail!(positional_10, {"a": 1}, {"a.$": 1, "a.b": 1});
+
+    test!(computed_1, {"c": {"$concat": ["$a", " ", "$b"]}}, {"a": "x", "b": "y", "d": 1}, {"c": "x y"});
+    test!(computed_2, {"c": {"$size": "$a"}, "a": 1}, {"a": [1, 2], "b": 3}, {"a": [1, 2], "c": 2});
+    test!(computed_3, {"a": {"$toUpper": "$a"}}, {"a": "x", "b": 3}, {"a": "X"});
+    test!(computed_4, {"c": "$a.b", "d": "x"}, {"a": {"b": 7}}, {"c": 7, "d": "x"});
+    test!(computed_5, {"c": "$b"}, {"a": 1}, {});
+    error!(computed_6, {"c": {"$size": "$a"}}, {"a": 1});
+    test!(computed_7, {"c": {"$literal": 0}, "_id": 0}, {"_id": 1, "a": 1}, {"c": 0});
+    fail!(computed_8, {}, {"c": {"$concat": ["$a"]}, "a": 0});
+    fail!(computed_9, {}, {"c.d": {"$concat": ["$a"]}});
+    fail!(computed_10, {}, {"c": {"$function": {}}});
+    fail!(computed_11, {}, {"_id": {"$toLower": "$a"}});
+    fail!(computed_12, {}, {"c": {"$toLower": "$a"}, "c.d": 1});
 }
@@ -0,0 +1,117 @@
+use crate::settings::Mongo;
+use crate::watcher::Watcher;
+use anyhow::{anyhow, Context, Error};
+use mongodb::{Client, Database};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Database (and its `Watcher`) of every collection. Collections matching one
+/// of the routes use its database; all others use the default one.
+pub struct Databases {
+    default: (Database, Arc<Mutex<Watcher>>),
+    routes: Vec<(Vec<String>, Database, Arc<Mutex<Watcher>>)>,
+}
+
+impl Databases {
+    pub async fn connect(mongo: Mongo) -> Result<Self, Error> {
+        let mut routes = Vec::with_capacity(mongo.routes.len());
+        for route in mongo.routes {
+            let database = connect(&route.url).await?;
+            let watcher = Arc::new(Mutex::new(Watcher::new(database.clone())));
+            routes.push((route.collections, database, watcher));
+        }
+
+        let database = connect(&mongo.url).await?;
+        let watcher = Arc::new(Mutex::new(Watcher::new(database.clone())));
+        Ok(Self {
+            default: (database, watcher),
+            routes,
+        })
+    }
+
+    pub fn get(&self, collection: &str) -> (&Database, &Arc<Mutex<Watcher>>) {
+        self.routes
+            .iter()
+            .find(|(patterns, _, _)| {
+                patterns
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, collection))
+            })
+            .map_or(
+                (&self.default.0, &self.default.1),
+                |(_, database, watcher)| (database, watcher),
+            )
+    }
+}
+
+async fn connect(url: &str) -> Result<Database, Error> {
+    Client::with_uri_str(url)
+        .await
+        .with_context(|| format!("Failed to connect to MongoDB at {url}"))?
+        .default_database()
+        .ok_or_else(|| anyhow!("Mongo URL {url} did not specify the database"))
+}
+
+/// Collection name or a pattern with `*` matching any (possibly empty) text.
+fn matches_pattern(pattern: &str, collection: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(prefix) = parts.next() else {
+        return false;
+    };
+
+    let Some(mut rest) = collection.strip_prefix(prefix) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+    let Some(suffix) = parts.pop() else {
+        // No `*` at all.
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches_pattern;
+
+    macro_rules! y {
+        ($name:ident, $pattern:expr, $collection:expr) => {
+            #[test]
+            fn $name() {
+                assert!(matches_pattern($pattern, $collection));
+            }
+        };
+    }
+
+    macro_rules! n {
+        ($name:ident, $pattern:expr, $collection:expr) => {
+            #[test]
+            fn $name() {
+                assert!(!matches_pattern($pattern, $collection));
+            }
+        };
+    }
+
+    y!(pattern_1, "events", "events");
+    n!(pattern_2, "events", "events2");
+    n!(pattern_3, "events", "my_events");
+    y!(pattern_4, "analytics_*", "analytics_");
+    y!(pattern_5, "analytics_*", "analytics_events");
+    n!(pattern_6, "analytics_*", "events");
+    y!(pattern_7, "*_log", "audit_log");
+    n!(pattern_8, "*_log", "audit_logs");
+    y!(pattern_9, "a*b*c", "abc");
+    y!(pattern_10, "a*b*c", "a_b_b_c");
+    n!(pattern_11, "a*b*c", "a_c_b");
+    n!(pattern_12, "ab*ba", "aba");
+    y!(pattern_13, "*", "anything");
+}
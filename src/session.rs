@@ -1,21 +1,42 @@
+use crate::batch::Batch;
 use crate::ddp::DDPMessage;
 use crate::inflights::{Inflight, Inflights};
 use crate::mergebox::Mergebox;
+use crate::outbox::{Outbox, OutboxReader};
+use crate::settings::{Flatten, Inconsistencies, Resync, SlowClients};
 use crate::subscriptions::Subscriptions;
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::Mutex;
 use tokio::task::JoinSet;
+use tokio::time::{interval_at, sleep, Duration, Instant};
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
+/// Maximum number of messages written to the client at once.
+const BATCH_LIMIT: usize = 1024;
+
+/// Configuration shared by all sessions.
+#[derive(Clone)]
+pub struct SessionOptions {
+    /// Limit of messages waiting for the client and what happens then.
+    pub client_buffer: Option<(usize, SlowClients)>,
+    pub flatten: Arc<Flatten>,
+    pub inconsistencies: Inconsistencies,
+    pub resync: Option<Resync>,
+    /// How long pending removals are held back.
+    pub tick: Duration,
+}
+
 struct Session {
     id: usize,
-    client_writer: Sender<DDPMessage>,
+    client_writer: Outbox,
     server_writer: Sender<DDPMessage>,
     inflights: Mutex<Inflights>,
     mergebox: Arc<Mutex<Mergebox>>,
@@ -68,10 +89,9 @@ async fn process_message_client(session: &Session, ddp_message: DDPMessage) -> R
                 .stop(session.id, &session.mergebox, id)
                 .await?
             {
-                session
-                    .client_writer
-                    .send(DDPMessage::Nosub { id, error: None })
-                    .await?;
+                let mut mergebox = session.mergebox.lock().await;
+                mergebox.flush().await?;
+                mergebox.send_other(DDPMessage::Nosub { id, error: None })?;
             } else {
                 session.server_writer.send(ddp_message).await?;
             }
@@ -96,7 +116,7 @@ async fn process_message_server(session: &Session, ddp_message: DDPMessage) -> R
         } => {
             let mut inflights = session.inflights.lock().await;
             let Some(inflight) = inflights.process_result(id) else {
-                session.client_writer.send(ddp_message).await?;
+                session.mergebox.lock().await.send_other(ddp_message)?;
                 return Ok(());
             };
 
@@ -114,9 +134,10 @@ async fn process_message_server(session: &Session, ddp_message: DDPMessage) -> R
                     // subscription.
                     let subs = vec![id.clone()];
                     session
-                        .client_writer
-                        .send(DDPMessage::Ready { subs })
-                        .await?;
+                        .mergebox
+                        .lock()
+                        .await
+                        .send_other(DDPMessage::Ready { subs })?;
                 }
                 Err(error) => {
                     // If the method failed, did not provide a response, used an
@@ -144,10 +165,9 @@ async fn process_message_server(session: &Session, ddp_message: DDPMessage) -> R
                 return Ok(());
             }
 
-            session
-                .client_writer
-                .send(DDPMessage::Updated { methods })
-                .await?;
+            let mut mergebox = session.mergebox.lock().await;
+            mergebox.flush().await?;
+            mergebox.send_other(DDPMessage::Updated { methods })?;
             Ok(())
         }
 
@@ -156,13 +176,27 @@ async fn process_message_server(session: &Session, ddp_message: DDPMessage) -> R
             id,
             collection,
             fields,
-            ..
+            cleared,
+        } => {
+            session
+                .mergebox
+                .lock()
+                .await
+                .server_added(collection, id, fields, cleared)
+                .await
+        }
+
+        DDPMessage::AddedBefore {
+            id,
+            collection,
+            fields,
+            before,
         } => {
             session
                 .mergebox
                 .lock()
                 .await
-                .server_added(collection, id, fields)
+                .server_added_before(collection, id, fields, before)
                 .await
         }
 
@@ -180,6 +214,19 @@ async fn process_message_server(session: &Session, ddp_message: DDPMessage) -> R
                 .await
         }
 
+        DDPMessage::MovedBefore {
+            id,
+            collection,
+            before,
+        } => {
+            session
+                .mergebox
+                .lock()
+                .await
+                .server_moved_before(collection, id, before)
+                .await
+        }
+
         DDPMessage::Removed { id, collection } => {
             session
                 .mergebox
@@ -189,21 +236,99 @@ async fn process_message_server(session: &Session, ddp_message: DDPMessage) -> R
                 .await
         }
 
-        // Pass-through other DDP messages.
+        // Pass-through other DDP messages. As they may depend on the data
+        // (e.g., `ready`), all pending removals are sent first.
         _ => {
-            session.client_writer.send(ddp_message).await?;
+            let mut mergebox = session.mergebox.lock().await;
+            mergebox.flush().await?;
+            mergebox.send_other(ddp_message)?;
             Ok(())
         }
     }
 }
 
+/// Sends pending removals a tick after the first one was scheduled (unless
+/// another message sent them earlier).
+async fn start_flusher(session: Arc<Session>, tick: Duration) -> Result<(), Error> {
+    let removals = session.mergebox.lock().await.removals();
+    loop {
+        removals.notified().await;
+        sleep(tick).await;
+        session.mergebox.lock().await.flush().await?;
+    }
+}
+
+async fn start_resyncer(session: Arc<Session>, resync: Resync) -> Result<(), Error> {
+    let period = Duration::from_millis(resync.interval_ms);
+    let mut interval = interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        if !is_sampled(resync.sample.unwrap_or(1.0)) {
+            continue;
+        }
+
+        let drifted =
+            Subscriptions::resync(&session.subscriptions, session.id, &session.mergebox).await?;
+        if drifted > 0 {
+            println!(
+                "\x1b[0;33mrouter\x1b[0m \x1b[0;31mResynced {drifted} documents of session {}\x1b[0m",
+                session.id
+            );
+        }
+    }
+}
+
+/// Whether to check a session, with the given probability. (`RandomState` is
+/// randomly seeded, so there is no need for a random number generator.)
+fn is_sampled(sample: f64) -> bool {
+    let random = RandomState::new().build_hasher().finish();
+    (random as f64) < sample * u64::MAX as f64
+}
+
+/// Disconnects or resyncs clients that fell behind (see `SlowClients`).
+async fn start_watchdog(session: Arc<Session>) -> Result<(), Error> {
+    loop {
+        session.client_writer.notified().await;
+        if let Some(SlowClients::Disconnect) = session.client_writer.is_overflowing() {
+            return Err(anyhow!(
+                "Client is too slow ({} messages pending)",
+                session.client_writer.pending()
+            ));
+        }
+
+        let mut mergebox = session.mergebox.lock().await;
+        if !mergebox.is_stale() {
+            continue;
+        }
+
+        if mergebox.catch_up()? {
+            println!("\x1b[0;33mrouter\x1b[0m Session {} caught up", session.id);
+        }
+    }
+}
+
 async fn start_consumer_client(
-    mut reader: Receiver<DDPMessage>,
+    mut reader: OutboxReader,
     mut sink: SplitSink<WebSocketStream<TcpStream>, Message>,
 ) -> Result<(), Error> {
+    let mut batch = Batch::default();
     while let Some(ddp_message) = reader.recv().await {
-        println!("\x1b[0;33mrouter\x1b[0m -> \x1b[0;34mclient\x1b[0m {ddp_message:?}");
-        sink.send(ddp_message.try_into()?).await?;
+        // Coalesce all messages that are already waiting and write them at
+        // once, flushing the socket only once.
+        batch.push(ddp_message);
+        while batch.len() < BATCH_LIMIT {
+            match reader.try_recv() {
+                Ok(ddp_message) => batch.push(ddp_message),
+                Err(_) => break,
+            }
+        }
+
+        for ddp_message in batch.drain() {
+            println!("\x1b[0;33mrouter\x1b[0m -> \x1b[0;34mclient\x1b[0m {ddp_message:?}");
+            sink.feed(ddp_message.try_into()?).await?;
+        }
+
+        sink.flush().await?;
     }
 
     Ok(())
@@ -276,6 +401,7 @@ pub async fn start_session(
     subscriptions: Arc<Mutex<Subscriptions>>,
     client: WebSocketStream<TcpStream>,
     server: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    options: SessionOptions,
 ) -> Result<(), Error> {
     let mut tasks = JoinSet::new();
 
@@ -283,7 +409,7 @@ pub async fn start_session(
     let (client_sink, client_stream) = client.split();
     let (server_sink, server_stream) = server.split();
 
-    let (client_writer, client_reader) = channel::<DDPMessage>(1024);
+    let (client_writer, client_reader) = Outbox::new(options.client_buffer);
     let (server_writer, server_reader) = channel::<DDPMessage>(1024);
 
     tasks.spawn(start_consumer_client(client_reader, client_sink));
@@ -295,13 +421,22 @@ pub async fn start_session(
         client_writer: client_writer.clone(),
         server_writer,
         inflights: Mutex::new(Inflights::default()),
-        mergebox: Arc::new(Mutex::new(Mergebox::new(client_writer.clone()))),
+        mergebox: Arc::new(Mutex::new(Mergebox::new(
+            client_writer.clone(),
+            options.inconsistencies,
+            options.flatten.clone(),
+        ))),
         subscriptions,
     });
 
     // Setup message producers.
     tasks.spawn(start_producer_client(client_stream, session.clone()));
     tasks.spawn(start_producer_server(server_stream, session.clone()));
+    tasks.spawn(start_flusher(session.clone(), options.tick));
+    tasks.spawn(start_watchdog(session.clone()));
+    if let Some(resync) = options.resync {
+        tasks.spawn(start_resyncer(session.clone(), resync));
+    }
 
     // Stop when any task's finished. Before the error is unwrapped (all of the
     // tasks will stop only when an error happens), stop all subscriptions made
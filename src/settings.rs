@@ -1,5 +1,34 @@
+use crate::flatten::Flattening;
+use bson::{doc, from_document};
 use config::{Config, ConfigError, Environment, File};
-use serde::Deserialize;
+use mongodb::options::{ReadConcern, ReadPreference};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Deserialize)]
+pub struct Admin {
+    pub url: String,
+}
+
+/// Fields of cursor documents published flattened (see `Flattening`), e.g.,
+/// large embedded arrays, per collection.
+#[derive(Clone, Default, Deserialize)]
+pub struct Flatten {
+    #[serde(default)]
+    pub collections: BTreeMap<String, Vec<String>>,
+}
+
+impl Flatten {
+    pub fn get(&self, collection: &str) -> Flattening {
+        Flattening::new(
+            self.collections
+                .get(collection)
+                .cloned()
+                .unwrap_or_default(),
+        )
+    }
+}
 
 #[derive(Deserialize)]
 pub struct Meteor {
@@ -8,19 +37,165 @@ pub struct Meteor {
 
 #[derive(Deserialize)]
 pub struct Mongo {
+    #[serde(default)]
+    pub routes: Vec<MongoRoute>,
     pub url: String,
 }
 
+/// Collections (names or patterns with `*`) stored in a different database.
+#[derive(Deserialize)]
+pub struct MongoRoute {
+    pub collections: Vec<String>,
+    pub url: String,
+}
+
+/// Cursor options that DDP Router does not recognize.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownOptions {
+    /// Ignore them (with a warning).
+    Lenient,
+    /// Serve such publications from the Meteor server instead.
+    #[default]
+    Strict,
+}
+
+/// What a `Mergebox` does on messages inconsistent with its state, e.g.,
+/// `changed` of an unknown document.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Inconsistencies {
+    /// Log and count them, and repair the state (e.g., treat such `changed` as
+    /// `added`).
+    Tolerant,
+    /// Close the session.
+    #[default]
+    Strict,
+}
+
+/// What happens to clients that fall behind `router.client_buffer` messages.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowClients {
+    /// Close the session.
+    #[default]
+    Disconnect,
+    /// Stop sending data messages until the client catches up, and then send
+    /// only the differences (like a resync).
+    Resync,
+}
+
+#[derive(Default, Deserialize)]
+pub struct Options {
+    #[serde(default)]
+    pub inconsistencies: Inconsistencies,
+    #[serde(default)]
+    pub slow_clients: SlowClients,
+    #[serde(default)]
+    pub unknown: UnknownOptions,
+}
+
+/// Read concern and read preference of queries (not change streams), e.g.,
+/// to offload initial fetches and polling to secondaries.
+#[derive(Clone, Default, Deserialize)]
+pub struct ReadOptions {
+    #[serde(default, deserialize_with = "deserialize_read_concern")]
+    pub concern: Option<ReadConcern>,
+    #[serde(default, deserialize_with = "deserialize_read_preference")]
+    pub preference: Option<ReadPreference>,
+}
+
+fn deserialize_read_concern<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ReadConcern>, D::Error> {
+    let level = Option::<String>::deserialize(deserializer)?;
+    Ok(level.map(ReadConcern::custom))
+}
+
+fn deserialize_read_preference<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ReadPreference>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|mode| from_document(doc! { "mode": mode }).map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Global `ReadOptions` with per-collection overrides.
+#[derive(Default, Deserialize)]
+pub struct Reads {
+    #[serde(default)]
+    pub collections: BTreeMap<String, ReadOptions>,
+    #[serde(flatten)]
+    pub default: ReadOptions,
+}
+
+impl Reads {
+    pub fn get(&self, collection: &str) -> ReadOptions {
+        let options = self.collections.get(collection);
+        ReadOptions {
+            concern: options
+                .and_then(|options| options.concern.clone())
+                .or_else(|| self.default.concern.clone()),
+            preference: options
+                .and_then(|options| options.preference.clone())
+                .or_else(|| self.default.preference.clone()),
+        }
+    }
+}
+
+/// Periodic consistency checks of all mergeboxes against their cursors (and
+/// the server messages), fixing the client view if needed.
+#[derive(Clone, Copy, Deserialize)]
+pub struct Resync {
+    pub interval_ms: u64,
+    /// Fraction of sessions checked in every interval (all by default).
+    pub sample: Option<f64>,
+}
+
 #[derive(Deserialize)]
 pub struct Router {
+    /// Maximum number of messages waiting for a client (unlimited by default).
+    pub client_buffer: Option<usize>,
+    /// How long removals are held back, so documents that are removed by one
+    /// cursor and added by another are sent as a single `changed`.
+    pub tick_ms: Option<u64>,
     pub url: String,
 }
 
 #[derive(Deserialize)]
 pub struct Settings {
+    pub admin: Option<Admin>,
+    #[serde(default)]
+    pub flatten: Flatten,
     pub meteor: Meteor,
     pub mongo: Mongo,
+    #[serde(default)]
+    pub options: Options,
+    #[serde(default)]
+    pub reads: Reads,
+    pub resync: Option<Resync>,
     pub router: Router,
+    #[serde(default)]
+    pub strict: Strict,
+}
+
+/// Publications that would have to be polled are served by the Meteor server
+/// instead. Applies to all of them (`all`), to the listed ones, or to the ones
+/// publishing from the listed collections.
+#[derive(Default, Deserialize)]
+pub struct Strict {
+    #[serde(default)]
+    pub all: bool,
+    #[serde(default)]
+    pub collections: BTreeSet<String>,
+    #[serde(default)]
+    pub publications: BTreeSet<String>,
+}
+
+impl Strict {
+    pub fn applies(&self, publication: &str, collection: &str) -> bool {
+        self.all || self.publications.contains(publication) || self.collections.contains(collection)
+    }
 }
 
 impl Settings {
@@ -32,3 +207,65 @@ impl Settings {
             .try_deserialize()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Reads, Strict};
+    use mongodb::options::{ReadConcern, ReadPreference, ReadPreferenceOptions};
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[test]
+    fn reads() {
+        let reads = json!({
+            "concern": "majority",
+            "preference": "secondaryPreferred",
+            "collections": {"x": {"preference": "primary"}},
+        });
+        let reads = Reads::deserialize(reads).unwrap();
+
+        let x = reads.get("x");
+        assert_eq!(x.concern, Some(ReadConcern::majority()));
+        assert_eq!(x.preference, Some(ReadPreference::Primary));
+
+        let y = reads.get("y");
+        assert_eq!(y.concern, Some(ReadConcern::majority()));
+        assert_eq!(
+            y.preference,
+            Some(ReadPreference::SecondaryPreferred {
+                options: ReadPreferenceOptions::default()
+            })
+        );
+    }
+
+    #[test]
+    fn reads_invalid() {
+        assert!(Reads::deserialize(json!({"preference": "x"})).is_err());
+    }
+
+    #[test]
+    fn strict_default() {
+        assert!(!Strict::default().applies("a", "b"));
+    }
+
+    #[test]
+    fn strict_all() {
+        let strict = Strict {
+            all: true,
+            ..Strict::default()
+        };
+        assert!(strict.applies("a", "b"));
+    }
+
+    #[test]
+    fn strict_lists() {
+        let strict = Strict {
+            all: false,
+            collections: ["x".to_owned()].into(),
+            publications: ["a".to_owned()].into(),
+        };
+        assert!(strict.applies("a", "y"));
+        assert!(strict.applies("b", "x"));
+        assert!(!strict.applies("b", "y"));
+    }
+}
@@ -1,41 +1,38 @@
+use crate::collation::Collation;
 use crate::lookup::{Branch, Lookup};
+use crate::numeric::Numeric;
 use anyhow::{anyhow, Error};
 use bson::{Bson, Document};
 use serde_json::{Map, Value};
 use std::cmp::Ordering;
 
+/// Values of all sorted fields of a document, precomputed with `Sorter::key` so
+/// that comparisons do not need to look into the document again.
+#[derive(Clone, Debug, Default)]
+pub struct SortKey(Vec<Option<Value>>);
+
 #[derive(Debug)]
 pub struct Sorter {
-    lookups: Vec<(Lookup, bool)>,
+    collation: Option<Collation>,
+    lookups: Vec<(Lookup, Vec<String>, bool)>,
 }
 
 impl Sorter {
-    pub fn cmp(&self, lhs: &Map<String, Value>, rhs: &Map<String, Value>) -> Ordering {
-        // It simplifies the `max_by`/`min_by` below.
-        #[inline(always)]
-        fn cmp_value(lhs: &&Value, rhs: &&Value) -> Ordering {
-            Sorter::cmp_value(lhs, rhs)
-        }
+    pub fn cmp(&self, lhs: &SortKey, rhs: &SortKey) -> Ordering {
+        self.cmp_key(
+            lhs.0.iter().map(Option::as_ref),
+            rhs.0.iter().map(Option::as_ref),
+        )
+    }
 
-        // TODO: `lookup` requires a `Value`.
-        let lhs = Value::Object(lhs.clone());
-        let rhs = Value::Object(rhs.clone());
-
-        for (lookup, reverse) in &self.lookups {
-            let lhs_values = Branch::expand(lookup.lookup(&lhs), true)
-                .into_iter()
-                .filter_map(|branch| branch.value);
-            let rhs_values = Branch::expand(lookup.lookup(&rhs), true)
-                .into_iter()
-                .filter_map(|branch| branch.value);
-
-            let (lhs_value, rhs_value) = if *reverse {
-                (lhs_values.max_by(cmp_value), rhs_values.max_by(cmp_value))
-            } else {
-                (lhs_values.min_by(cmp_value), rhs_values.min_by(cmp_value))
-            };
-
-            let ordering = Self::cmp_value_option(lhs_value, rhs_value);
+    fn cmp_key<'a>(
+        &self,
+        lhs: impl Iterator<Item = Option<&'a Value>>,
+        rhs: impl Iterator<Item = Option<&'a Value>>,
+    ) -> Ordering {
+        let collation = self.collation.as_ref();
+        for ((_, _, reverse), (lhs, rhs)) in self.lookups.iter().zip(lhs.zip(rhs)) {
+            let ordering = Self::cmp_value_option(lhs, rhs, collation);
             if ordering.is_ne() {
                 return if *reverse {
                     ordering.reverse()
@@ -48,22 +45,105 @@ impl Sorter {
         Ordering::Equal
     }
 
-    pub fn cmp_value(lhs: &Value, rhs: &Value) -> Ordering {
-        match Self::cmp_value_partial(lhs, rhs) {
+    /// The sort key of a document is the lowest combination of values of all
+    /// sorted fields. Values from the same array are combined positionally,
+    /// e.g., `{'a.x': 1, 'a.y': 1}` pairs `x` and `y` of the same element of
+    /// `a`. Unrelated arrays (i.e., parallel arrays) are rejected, just like
+    /// MongoDB does, as there is no way to combine them.
+    pub fn key(&self, document: &Map<String, Value>) -> Result<SortKey, Error> {
+        let branches: Vec<_> = self
+            .lookups
+            .iter()
+            .map(|(lookup, path, _)| {
+                let mut branches = Branch::expand(lookup.lookup_document(document), true);
+                if branches.is_empty() {
+                    branches.push(Branch {
+                        array_indices: vec![],
+                        dont_iterate: false,
+                        value: None,
+                    });
+                }
+                (path.as_slice(), branches)
+            })
+            .collect();
+
+        for (index, (lhs_path, lhs)) in branches.iter().enumerate() {
+            for (rhs_path, rhs) in &branches[index + 1..] {
+                if has_own_array(lhs_path, lhs, rhs_path) && has_own_array(rhs_path, rhs, lhs_path)
+                {
+                    return Err(anyhow!("Sort keys cannot be parallel arrays"));
+                }
+            }
+        }
+
+        let mut key = None;
+        self.key_search(&branches, &mut vec![], &mut key);
+        Ok(SortKey(
+            key.into_iter()
+                .flatten()
+                .map(Option::<&Value>::cloned)
+                .collect(),
+        ))
+    }
+
+    fn key_search<'a, 'b>(
+        &self,
+        branches: &'b [(&'b [String], Vec<Branch<'a>>)],
+        chosen: &mut Vec<(&'b [String], &'b Branch<'a>)>,
+        key: &mut Option<Vec<Option<&'a Value>>>,
+    ) {
+        let Some((path, candidates)) = branches.get(chosen.len()) else {
+            let candidate: Vec<_> = chosen.iter().map(|(_, branch)| branch.value).collect();
+            if !key.as_ref().is_some_and(|key: &Vec<_>| {
+                self.cmp_key(candidate.iter().copied(), key.iter().copied())
+                    .is_ge()
+            }) {
+                *key = Some(candidate);
+            }
+            return;
+        };
+
+        for candidate in candidates {
+            let is_compatible = chosen
+                .iter()
+                .all(|(chosen_path, chosen)| is_compatible(path, candidate, chosen_path, chosen));
+            if is_compatible {
+                chosen.push((path, candidate));
+                self.key_search(branches, chosen, key);
+                chosen.pop();
+            }
+        }
+    }
+
+    pub fn cmp_value(lhs: &Value, rhs: &Value, collation: Option<&Collation>) -> Ordering {
+        match Self::cmp_value_partial(lhs, rhs, collation) {
             Ok(ordering) | Err(ordering) => ordering,
         }
     }
 
-    pub fn cmp_value_option(lhs: Option<&Value>, rhs: Option<&Value>) -> Ordering {
+    pub fn cmp_value_option(
+        lhs: Option<&Value>,
+        rhs: Option<&Value>,
+        collation: Option<&Collation>,
+    ) -> Ordering {
         match (lhs, rhs) {
             (None, None) => Ordering::Equal,
             (None, _) => Ordering::Less,
             (_, None) => Ordering::Greater,
-            (Some(lhs), Some(rhs)) => Self::cmp_value(lhs, rhs),
+            (Some(lhs), Some(rhs)) => Self::cmp_value(lhs, rhs, collation),
         }
     }
 
-    pub fn cmp_value_partial(lhs: &Value, rhs: &Value) -> Result<Ordering, Ordering> {
+    pub fn cmp_value_partial(
+        lhs: &Value,
+        rhs: &Value,
+        collation: Option<&Collation>,
+    ) -> Result<Ordering, Ordering> {
+        // Numbers of different types are comparable.
+        if let (Some(lhs), Some(rhs)) = (Numeric::from_value(lhs), Numeric::from_value(rhs)) {
+            return Ok(lhs.cmp(&rhs));
+        }
+
         let lhs_type = Self::value_type(lhs);
         let rhs_type = Self::value_type(rhs);
         if lhs_type != rhs_type {
@@ -80,7 +160,7 @@ impl Sorter {
                 }
 
                 for (lhs, rhs) in lhs.iter().zip(rhs.iter()) {
-                    let ordering = Self::cmp_value_partial(lhs, rhs)?;
+                    let ordering = Self::cmp_value_partial(lhs, rhs, collation)?;
                     if ordering.is_ne() {
                         return Ok(ordering);
                     }
@@ -90,10 +170,6 @@ impl Sorter {
             }
             (Value::Bool(lhs), Value::Bool(rhs)) => lhs.cmp(rhs),
             (Value::Null, Value::Null) => Ordering::Equal,
-            (Value::Number(lhs), Value::Number(rhs)) => lhs
-                .as_f64()
-                .partial_cmp(&rhs.as_f64())
-                .unwrap_or(Ordering::Equal),
             (Value::Object(lhs), Value::Object(rhs)) => {
                 let ordering = lhs.len().cmp(&rhs.len());
                 if ordering.is_ne() {
@@ -107,7 +183,7 @@ impl Sorter {
                         return Ok(ordering);
                     }
 
-                    let ordering = Self::cmp_value_partial(lhs.1, rhs.1)?;
+                    let ordering = Self::cmp_value_partial(lhs.1, rhs.1, collation)?;
                     if ordering.is_ne() {
                         return Ok(ordering);
                     }
@@ -115,43 +191,59 @@ impl Sorter {
 
                 Ordering::Equal
             }
-            (Value::String(lhs), Value::String(rhs)) => lhs.cmp(rhs),
+            (Value::String(lhs), Value::String(rhs)) => match collation {
+                Some(collation) => collation.cmp(lhs, rhs),
+                None => lhs.cmp(rhs),
+            },
             _ => unreachable!(),
         })
     }
 
-    pub fn compile(sort: Option<&Document>) -> Result<Self, Error> {
-        let mut dotted_fields = vec![];
+    pub fn compile(sort: Option<&Document>, collation: Option<&Collation>) -> Result<Self, Error> {
         let lookups = sort
             .into_iter()
             .flatten()
             .map(|(key, order)| {
-                if let Some((field, _)) = key.split_once('.') {
-                    match dotted_fields.binary_search(&field) {
-                        Ok(_) => {
-                            return Err(anyhow!("Sort for parallel nested fields is not supported"))
-                        }
-                        Err(index) => dotted_fields.insert(index, field),
-                    }
-                }
-
                 let reverse = match order {
                     Bson::Int32(1) | Bson::Int64(1) => false,
                     Bson::Int32(-1) | Bson::Int64(-1) => true,
+                    Bson::Document(order) if order.contains_key("$meta") => {
+                        return Err(anyhow!(
+                            "Sort order {order} for {key} requires polling, as $meta values are not available in change events"
+                        ))
+                    }
                     order => return Err(anyhow!("Sort order {order} for {key} is not supported")),
                 };
 
+                // There is no way to know the storage order, so `$natural` is
+                // not maintained at all. It is fine for cursors without limit,
+                // as only their initial query is sorted (by the database).
+                if key == "$natural" {
+                    if sort.is_some_and(|sort| sort.len() > 1) {
+                        return Err(anyhow!("Sort by $natural cannot be combined with other fields"));
+                    }
+
+                    return Ok(None);
+                }
+
                 let lookup = Lookup::new(key.to_owned(), true);
-                Ok((lookup, reverse))
+                let path = key.split('.').map(str::to_owned).collect();
+                Ok(Some((lookup, path, reverse)))
             })
+            .filter_map(Result::transpose)
             .collect::<Result<_, _>>()?;
-        Ok(Self { lookups })
+        Ok(Self {
+            collation: collation.copied(),
+            lookups,
+        })
     }
 
-    // TODO: It coerces all numbers into `1`, just like Meteor. It would be better
-    // to support `double`, `int`, `long`, and `decimal` separately.
     /// <https://www.mongodb.com/docs/manual/reference/operator/query/type/#available-types>
     pub fn value_type(value: &Value) -> i8 {
+        if let Some(numeric) = Numeric::from_value(value) {
+            return numeric.value_type();
+        }
+
         match value {
             Value::Array(_) => 4,
             Value::Bool(_) => 8,
@@ -162,13 +254,19 @@ impl Sorter {
                 if keys.len() <= 2 {
                     keys.sort_unstable();
                     match keys.as_slice() {
-                        ["$InfNaN"] => return 1,
                         ["$binary"] => return 5,
                         ["$date"] => return 9,
                         ["$flags", "$regexp"] => return 11,
                         ["$type", "$value"] => match object.get("$type").and_then(Value::as_str) {
-                            Some("Decimal") => return 1,
+                            Some("undefined") => return 6,
                             Some("oid") => return 7,
+                            Some("dbPointer") => return 12,
+                            Some("javascript") => return 13,
+                            Some("symbol") => return 14,
+                            Some("javascriptWithScope") => return 15,
+                            Some("timestamp") => return 17,
+                            Some("minKey") => return -1,
+                            Some("maxKey") => return 127,
                             _ => {}
                         },
                         _ => {}
@@ -185,26 +283,61 @@ impl Sorter {
     fn value_type_order(value_type: i8) -> u8 {
         match value_type {
             -1 => 0,
-            10 => 1,
-            1 | 16 | 18 | 19 => 2,
-            2 | 14 => 3,
-            3 => 4,
-            4 => 5,
-            5 => 6,
-            7 => 7,
-            8 => 8,
-            9 => 9,
-            17 => 10,
-            11 => 11,
-            127 => 12,
+            6 => 1,
+            10 => 2,
+            1 | 16 | 18 | 19 => 3,
+            2 | 14 => 4,
+            3 => 5,
+            4 => 6,
+            5 => 7,
+            7 => 8,
+            8 => 9,
+            9 => 10,
+            17 => 11,
+            11 => 12,
+            12 => 13,
+            13 => 14,
+            15 => 15,
+            127 => 16,
             _ => unreachable!(),
         }
     }
 }
 
+/// Whether any of the branches went through an array that is not on the other
+/// path, e.g., `b` for `{'a.x': 1, 'b.y': 1}`.
+fn has_own_array(path: &[String], branches: &[Branch], other_path: &[String]) -> bool {
+    branches
+        .iter()
+        .flat_map(|branch| &branch.array_indices)
+        .any(|&(depth, _)| !other_path.starts_with(&path[..depth.min(path.len())]))
+}
+
+/// Branches are incompatible if they come from different elements of the same
+/// array. Arrays are the same if they are reached through the same path and
+/// the same elements of all the arrays on the way.
+fn is_compatible(lhs_path: &[String], lhs: &Branch, rhs_path: &[String], rhs: &Branch) -> bool {
+    for (&(lhs_depth, lhs_index), &(rhs_depth, rhs_index)) in
+        lhs.array_indices.iter().zip(&rhs.array_indices)
+    {
+        let lhs_prefix = &lhs_path[..lhs_depth.min(lhs_path.len())];
+        let rhs_prefix = &rhs_path[..rhs_depth.min(rhs_path.len())];
+        if lhs_prefix != rhs_prefix {
+            return true;
+        }
+
+        if lhs_index != rhs_index {
+            return false;
+        }
+    }
+
+    true
+}
+
 #[cfg(test)]
 mod tests {
     use super::Sorter;
+    use crate::collation::Collation;
     use bson::doc;
     use serde_json::{json, Value};
     use std::cmp::Ordering;
@@ -214,17 +347,34 @@ mod tests {
             #[test]
             fn $name() {
                 let sort = doc! { $($sort)* };
-                if Sorter::compile(Some(&sort)).is_ok() {
+                if Sorter::compile(Some(&sort), None).is_ok() {
                     panic!("{sort:?} should not be supported");
                 }
             }
         };
     }
 
+    macro_rules! parallel {
+        ($name:ident, { $($sort:tt)* }, { $($document:tt)* }) => {
+            #[test]
+            fn $name() {
+                let sort = doc! { $($sort)* };
+                let document = json! {{ $($document)* }};
+                let Value::Object(document) = document else { unreachable!() };
+                let sorter = Sorter::compile(Some(&sort), None).unwrap();
+                if sorter.key(&document).is_ok() {
+                    panic!("{sort:?} should not be supported for {document:?}");
+                }
+            }
+        };
+    }
+
     macro_rules! test {
-        ($name:ident, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }, $expected:expr) => {
+        ($name:ident, { $($collation:tt)* }, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }, $expected:expr) => {
             #[test]
             fn $name() {
+                let collation = doc! { $($collation)* };
+                let collation = Collation::compile(Some(&collation).filter(|x| !x.is_empty())).unwrap();
                 let sort = doc! { $($sort)* };
                 let lhs = json! {{ $($lhs)* }};
                 let rhs = json! {{ $($rhs)* }};
@@ -233,11 +383,14 @@ mod tests {
                 let Value::Object(lhs) = lhs else { unreachable!() };
                 let Value::Object(rhs) = rhs else { unreachable!() };
 
-                let sorter = match Sorter::compile(sort) {
+                let sorter = match Sorter::compile(sort, collation.as_ref()) {
                     Ok(sorter) => sorter,
                     Err(error) => panic!("{sort:?} is not supported: {error:?}"),
                 };
 
+                let lhs = sorter.key(&lhs).unwrap();
+                let rhs = sorter.key(&rhs).unwrap();
+
                 assert_eq!(sorter.cmp(&lhs, &lhs), Ordering::Equal);
                 assert_eq!(sorter.cmp(&rhs, &rhs), Ordering::Equal);
 
@@ -245,10 +398,19 @@ mod tests {
                 assert_eq!(sorter.cmp(&rhs, &lhs), $expected.reverse());
             }
         };
+        ($name:ident, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }, $expected:expr) => {
+            test!($name, {}, { $($sort)* }, { $($lhs)* }, { $($rhs)* }, $expected);
+        };
     }
 
-    macro_rules! eq {($name:ident, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }) => {test!($name, { $($sort)* }, { $($lhs)* }, { $($rhs)* }, Ordering::Equal);}}
-    macro_rules! lt {($name:ident, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }) => {test!($name, { $($sort)* }, { $($lhs)* }, { $($rhs)* }, Ordering::Less);}}
+    macro_rules! eq {
+        ($name:ident, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }) => {test!($name, { $($sort)* }, { $($lhs)* }, { $($rhs)* }, Ordering::Equal);};
+        ($name:ident, { $($collation:tt)* }, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }) => {test!($name, { $($collation)* }, { $($sort)* }, { $($lhs)* }, { $($rhs)* }, Ordering::Equal);};
+    }
+    macro_rules! lt {
+        ($name:ident, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }) => {test!($name, { $($sort)* }, { $($lhs)* }, { $($rhs)* }, Ordering::Less);};
+        ($name:ident, { $($collation:tt)* }, { $($sort:tt)* }, { $($lhs:tt)* }, { $($rhs:tt)* }) => {test!($name, { $($collation)* }, { $($sort)* }, { $($lhs)* }, { $($rhs)* }, Ordering::Less);};
+    }
 
     eq!(empty, {}, {"a": 1}, {"a": 2});
 
@@ -287,8 +449,51 @@ mod tests {
     lt!(arrays_13, {"a": 1}, {"a": [1, [10, 15], 20]}, {"a": [5, [19, 3], 18]});
     lt!(arrays_14, {"a": -1}, {"a": [5, [19, 3], 18]}, {"a": [1, [10, 15], 20]});
     lt!(arrays_15, {"a": -1}, {"a": [1, [10, 15], 20]}, {"a": [5, [3, 19], 18]});
+    lt!(numbers_1, {"a": 1}, {"a": 1}, {"a": 1.5});
+    eq!(numbers_2, {"a": 1}, {"a": 1}, {"a": 1.0});
+    lt!(numbers_3, {"a": 1}, {"a": 9_007_199_254_740_992_i64}, {"a": 9_007_199_254_740_993_i64});
+    lt!(numbers_4, {"a": 1}, {"a": {"$InfNaN": 0}}, {"a": {"$InfNaN": -1}});
+    lt!(numbers_5, {"a": 1}, {"a": {"$InfNaN": 1}}, {"a": "a"});
+    lt!(numbers_6, {"a": 1}, {"a": {"$type": "Decimal", "$value": "0.1"}}, {"a": 0.1});
+    eq!(numbers_7, {"a": 1}, {"a": {"$type": "long", "$value": 7}}, {"a": 7});
+    lt!(types_1, {"a": 1}, {"a": {"$type": "minKey", "$value": 1}}, {"a": null});
+    lt!(types_2, {"a": 1}, {"a": {"$type": "undefined", "$value": null}}, {"a": null});
+    lt!(types_3, {"a": 1}, {"a": {"$date": 0}}, {"a": {"$type": "timestamp", "$value": {"t": 0, "i": 0}}});
+    lt!(types_4, {"a": 1}, {"a": {"$type": "timestamp", "$value": {"t": 1, "i": 2}}}, {"a": {"$type": "timestamp", "$value": {"t": 2, "i": 1}}});
+    lt!(types_5, {"a": 1}, {"a": {"$regexp": "x", "$flags": ""}}, {"a": {"$type": "javascript", "$value": "x"}});
+    lt!(types_6, {"a": 1}, {"a": {"$type": "javascript", "$value": "x"}}, {"a": {"$type": "maxKey", "$value": 1}});
+    eq!(types_7, {"a": 1}, {"a": {"$type": "maxKey", "$value": 1}}, {"a": {"$type": "maxKey", "$value": 1}});
+
     lt!(arrays_16, {"a.0.s": 1}, {"a": [{"s": 1}]}, {"a": [{"s": 2}]});
 
-    fail!(unsupported_1, {"a.x": 1, "a.y": 1});
-    fail!(unsupported_2, {"a.b.x": 1, "a.b.y": 1});
+    lt!(collation_1, {"a": 1}, {"a": "B"}, {"a": "a"});
+    lt!(collation_2, {"locale": "en", "strength": 1}, {"a": 1}, {"a": "a"}, {"a": "B"});
+    eq!(collation_3, {"locale": "en", "strength": 1}, {"a": 1}, {"a": "é"}, {"a": "E"});
+    lt!(collation_4, {"locale": "en", "strength": 2}, {"a": 1}, {"a": "E"}, {"a": "é"});
+    lt!(collation_5, {"locale": "en", "strength": 1}, {"a": -1, "b": 1}, {"a": "b", "b": "x"}, {"a": "B", "b": "Y"});
+    lt!(collation_6, {"locale": "en", "strength": 1, "numericOrdering": true}, {"a": 1}, {"a": "item 9"}, {"a": "Item 10"});
+
+    lt!(parallel_1, {"a.x": 1, "a.y": 1}, {"a": [{"x": 1, "y": 2}]}, {"a": [{"x": 1, "y": 3}, {"x": 5, "y": 0}]});
+    lt!(parallel_2, {"a.x": 1, "a.y": -1}, {"a": [{"x": 1, "y": 5}, {"x": 1, "y": 7}]}, {"a": [{"x": 1, "y": 6}]});
+    lt!(parallel_3, {"a.b.x": 1, "a.b.y": 1}, {"a": {"b": [{"x": 1, "y": 2}]}}, {"a": {"b": [{"x": 1, "y": 3}, {"x": 2, "y": 0}]}});
+    lt!(parallel_4, {"a.b.x": 1, "a.c": 1}, {"a": [{"b": [{"x": 0}], "c": 3}]}, {"a": [{"b": [{"x": 1}], "c": 2}, {"b": [{"x": 0}], "c": 9}]});
+    lt!(parallel_5, {"a.x": 1, "a.y": 1}, {"a": [{"x": 1}, {"y": 0}]}, {"a": [{"x": 0, "y": 0}]});
+    parallel!(parallel_6, {"a.x": 1, "b.y": 1}, {"a": [{"x": 1}, {"x": 2}], "b": [{"y": 3}]});
+    eq!(parallel_7, {"a.x": 1, "a.y": 1}, {"a": [{"x": 1, "y": 2}, {"x": 3, "y": 0}]}, {"a": {"x": 1, "y": 2}});
+    parallel!(parallel_8, {"a": 1, "b": 1}, {"a": [1, 2], "b": [3, 4]});
+    parallel!(parallel_9, {"a.b.x": 1, "a.c.y": 1}, {"a": {"b": [{"x": 1}], "c": [{"y": 2}]}});
+    lt!(parallel_10, {"a.x": 1, "b.y": 1}, {"a": [{"x": 1}, {"x": 2}], "b": {"y": 3}}, {"a": [{"x": 1}], "b": {"y": 4}});
+    lt!(parallel_11, {"a": 1, "a.x": 1}, {"a": [{"x": 2}, {"x": 1}]}, {"a": [{"x": 3}]});
+
+    eq!(natural_1, {"$natural": 1}, {"_id": 1, "a": 2}, {"_id": 2, "a": 1});
+    eq!(natural_2, {"$natural": -1}, {"_id": 2, "a": 1}, {"_id": 1, "a": 2});
+    eq!(natural_3, {"$natural": 1}, {"_id": "a"}, {"_id": "b"});
+
+    fail!(meta_1, {"score": {"$meta": "textScore"}});
+    fail!(meta_2, {"a": 1, "score": {"$meta": "textScore"}});
+    fail!(natural_4, {"$natural": 1, "a": 1});
+    fail!(natural_5, {"$natural": 2});
+
+    fail!(unsupported_1, {"a": 2});
+    fail!(unsupported_2, {"a": "asc"});
 }
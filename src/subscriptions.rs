@@ -1,38 +1,142 @@
-use crate::cursor::{Cursor, CursorDescription};
+use crate::cursor::{explain_polling, Cursor, CursorDescription, Explanation, Part};
 use crate::inflights::Inflight;
-use crate::mergebox::Mergebox;
-use crate::watcher::Watcher;
+use crate::mergebox::{Mergebox, Owner};
+use crate::routing::Databases;
+use crate::settings::{Flatten, Reads, Strict, UnknownOptions};
 use anyhow::{anyhow, Context, Error};
-use mongodb::Database;
 use serde::Deserialize;
 use serde_json::{from_str, Value};
 use std::collections::{BTreeMap, BTreeSet};
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, MutexGuard, PoisonError, Weak};
 use tokio::sync::Mutex;
 
+/// Number of started cursors that could not use change streams, grouped by
+/// publication, collection, and the part of the cursor. Reasons may differ
+/// between cursors (e.g., mention values), so only the latest one is kept.
+/// Cursors that fall back later (e.g., on a document they cannot sort) are
+/// counted then.
+#[derive(Default)]
+pub struct Explanations(BTreeMap<(String, String, Part), (usize, Explanation)>);
+
+impl Explanations {
+    fn explained(&mut self, publication: &str, collection: &str, explanation: &Explanation) {
+        let key = (
+            publication.to_owned(),
+            collection.to_owned(),
+            explanation.part,
+        );
+        let entry = self
+            .0
+            .entry(key)
+            .or_insert_with(|| (0, explanation.clone()));
+        entry.0 += 1;
+        entry.1.clone_from(explanation);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &Explanation, usize)> {
+        self.0
+            .iter()
+            .map(|((publication, collection, _), (count, explanation))| {
+                (
+                    publication.as_str(),
+                    collection.as_str(),
+                    explanation,
+                    *count,
+                )
+            })
+    }
+}
+
 pub struct Subscriptions {
     cursors_by_collection: BTreeMap<String, Vec<Weak<Mutex<Cursor>>>>,
     #[allow(clippy::type_complexity)]
     cursors_by_session: BTreeMap<usize, BTreeMap<String, Vec<Arc<Mutex<Cursor>>>>>,
-    database: Database,
+    databases: Databases,
+    explanations: Arc<std::sync::Mutex<Explanations>>,
+    flatten: Flatten,
+    reads: Reads,
     #[allow(clippy::struct_field_names)]
     server_subscriptions: BTreeSet<String>,
-    watcher: Arc<Mutex<Watcher>>,
+    strict: Strict,
+    unknown_options: UnknownOptions,
 }
 
 impl Subscriptions {
+    pub fn explanations(&self) -> MutexGuard<'_, Explanations> {
+        self.explanations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn explained(&self, publication: &str, collection: &str, explanation: &Explanation) {
+        self.explanations()
+            .explained(publication, collection, explanation);
+    }
+
     pub fn is_server_subscription(&self, subscription: &str) -> bool {
         self.server_subscriptions.contains(subscription)
     }
 
-    pub fn new(database: Database, watcher: Watcher) -> Self {
+    pub fn new(
+        databases: Databases,
+        strict: Strict,
+        unknown_options: UnknownOptions,
+        reads: Reads,
+        flatten: Flatten,
+    ) -> Self {
         Self {
             cursors_by_collection: BTreeMap::default(),
             cursors_by_session: BTreeMap::default(),
-            database,
+            databases,
+            explanations: Arc::default(),
+            flatten,
+            reads,
             server_subscriptions: BTreeSet::default(),
-            watcher: Arc::new(Mutex::new(watcher)),
+            strict,
+            unknown_options,
+        }
+    }
+
+    /// Checks the mergebox of the session against the server and all of its
+    /// cursors and fixes the client view if needed. Returns the number of fixed
+    /// documents. Cursors are checked one by one (and without holding the lock
+    /// of all subscriptions), so others are not paused in the meantime.
+    pub async fn resync(
+        subscriptions: &Mutex<Self>,
+        session_id: usize,
+        mergebox: &Arc<Mutex<Mergebox>>,
+    ) -> Result<usize, Error> {
+        let cursors: Vec<_> = subscriptions
+            .lock()
+            .await
+            .cursors_by_session
+            .get(&session_id)
+            .into_iter()
+            .flatten()
+            .flat_map(|(subscription_id, cursors)| {
+                let owner = Owner::Subscription(Arc::from(subscription_id.as_str()));
+                cursors
+                    .iter()
+                    .map(move |cursor| (owner.clone(), cursor.clone()))
+            })
+            .collect();
+
+        let mut drifted = mergebox
+            .lock()
+            .await
+            .resync_server()
+            .await
+            .context("Subscriptions::resync")?;
+        for (owner, cursor) in cursors {
+            drifted += cursor
+                .lock()
+                .await
+                .resync(session_id, &owner, mergebox)
+                .await
+                .context("Subscriptions::resync")?;
         }
+
+        Ok(drifted)
     }
 
     pub async fn start(
@@ -74,10 +178,50 @@ impl Subscriptions {
             .map(CursorDescription::deserialize)
             .collect::<Result<Vec<_>, _>>()?;
 
+        // Check all cursors before starting any of them.
+        for description in &descriptions {
+            let collection = &description.collection;
+            let unknown_options = &description.unknown_options;
+            if !unknown_options.is_empty() {
+                match self.unknown_options {
+                    UnknownOptions::Lenient => println!(
+                        "\x1b[0;33mrouter\x1b[0m Ignoring unknown options {unknown_options:?} of {collection}"
+                    ),
+                    UnknownOptions::Strict => {
+                        return Err(anyhow!(
+                            "Unknown options {unknown_options:?} of {collection}"
+                        ))
+                    }
+                }
+            }
+
+            if !self.strict.applies(&inflight.name, collection) {
+                continue;
+            }
+
+            // Unlike not registered ones, the publication is not remembered, as
+            // other arguments may lead to cursors that do not need polling.
+            if let Some(explanation) = explain_polling(description) {
+                self.explained(&inflight.name, collection, &explanation);
+                return Err(anyhow!(
+                    "Publication {} would poll {collection} ({explanation})",
+                    inflight.name
+                ));
+            }
+        }
+
         // Start.
+        let owner = Owner::Subscription(Arc::from(subscription_id));
         let mut cursors = vec![];
         for description in descriptions {
-            cursors.push(self.start_cursor(session_id, mergebox, description).await?);
+            let collection = description.collection.clone();
+            let cursor = self
+                .start_cursor(session_id, &owner, mergebox, &inflight.name, description)
+                .await?;
+            if let Some(explanation) = cursor.lock().await.explanation().await {
+                self.explained(&inflight.name, &collection, &explanation);
+            }
+            cursors.push(cursor);
         }
 
         self.cursors_by_session
@@ -90,7 +234,9 @@ impl Subscriptions {
     async fn start_cursor(
         &mut self,
         session_id: usize,
+        owner: &Owner,
         mergebox: &Arc<Mutex<Mergebox>>,
+        publication: &str,
         description: CursorDescription,
     ) -> Result<Arc<Mutex<Cursor>>, Error> {
         // Search for existing cursor with the same description. While at it,
@@ -104,7 +250,7 @@ impl Subscriptions {
                 let is_deduplicated = {
                     let mut cursor = cursor.lock().await;
                     if *cursor.description() == description {
-                        cursor.start(session_id, mergebox).await?;
+                        cursor.start(session_id, owner, mergebox).await?;
                         true
                     } else {
                         false
@@ -120,8 +266,27 @@ impl Subscriptions {
         }
 
         // Create and start a new cursor.
-        let mut cursor = Cursor::new(self.database.clone(), description, self.watcher.clone());
-        cursor.start(session_id, mergebox).await?;
+        let reads = self.reads.get(&description.collection);
+        let (database, watcher) = self.databases.get(&description.collection);
+        let flattening = self.flatten.get(&description.collection);
+        let explanations = self.explanations.clone();
+        let publication = publication.to_owned();
+        let collection = description.collection.clone();
+        let fell_back = move |explanation: &Explanation| {
+            explanations
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .explained(&publication, &collection, explanation);
+        };
+        let mut cursor = Cursor::new(
+            database.clone(),
+            description,
+            watcher.clone(),
+            reads,
+            flattening,
+            Box::new(fell_back),
+        );
+        cursor.start(session_id, owner, mergebox).await?;
 
         // Store a weak reference for faster lookups.
         let cursor = Arc::new(Mutex::new(cursor));
@@ -140,14 +305,23 @@ impl Subscriptions {
             .get_mut(&session_id)
             .and_then(|cursors| cursors.remove_entry(subscription_id))
         {
+            let owner = Owner::Subscription(Arc::from(subscription_id.as_str()));
             for cursor in cursors {
                 cursor
                     .lock()
                     .await
-                    .stop(session_id, mergebox)
+                    .stop(session_id, &owner, mergebox)
                     .await
                     .context("Subscriptions::stop")?;
             }
+
+            // All documents of this subscription should be gone by now.
+            mergebox
+                .lock()
+                .await
+                .release(&owner)
+                .await
+                .context("Subscriptions::stop")?;
             Ok(Some(subscription_id))
         } else {
             Ok(None)
@@ -160,11 +334,21 @@ impl Subscriptions {
         mergebox: &Arc<Mutex<Mergebox>>,
     ) -> Result<(), Error> {
         if let Some(cursors) = self.cursors_by_session.remove(&session_id) {
-            for cursor in cursors.into_values().flatten() {
-                cursor
+            for (subscription_id, cursors) in cursors {
+                let owner = Owner::Subscription(Arc::from(subscription_id));
+                for cursor in cursors {
+                    cursor
+                        .lock()
+                        .await
+                        .stop(session_id, &owner, mergebox)
+                        .await
+                        .context("Subscriptions::stop_all")?;
+                }
+
+                mergebox
                     .lock()
                     .await
-                    .stop(session_id, mergebox)
+                    .release(&owner)
                     .await
                     .context("Subscriptions::stop_all")?;
             }